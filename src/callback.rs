@@ -0,0 +1,201 @@
+//! Filesystems built from closures
+//!
+//! `CallbackFilesystem` implements `Filesystem` by calling closures registered for single
+//! operations, which is handy for prototypes, tests and small read-only filesystems that
+//! don't warrant a type implementing the whole trait.
+
+use std::ffi::OsStr;
+use std::fmt;
+
+use libc::ENOSYS;
+
+use crate::{
+    Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, ReplyStatfs, Request,
+};
+
+type LookupFn = Box<dyn FnMut(&Request<'_>, u64, &OsStr, ReplyEntry) + Send>;
+type GetattrFn = Box<dyn FnMut(&Request<'_>, u64, Option<u64>, ReplyAttr) + Send>;
+type ReadlinkFn = Box<dyn FnMut(&Request<'_>, u64, ReplyData) + Send>;
+type OpenFn = Box<dyn FnMut(&Request<'_>, u64, i32, ReplyOpen) + Send>;
+type ReadFn = Box<dyn FnMut(&Request<'_>, u64, u64, i64, u32, ReplyData) + Send>;
+type ReaddirFn = Box<dyn FnMut(&Request<'_>, u64, u64, i64, ReplyDirectory) + Send>;
+type StatfsFn = Box<dyn FnMut(&Request<'_>, u64, ReplyStatfs) + Send>;
+
+/// Filesystem calling the closures registered for its operations
+///
+/// Operations without a closure behave like the default methods of `Filesystem`, i.e. most
+/// reply ENOSYS, while open succeeds without a file handle and statfs replies an empty
+/// filesystem. The closures receive the same arguments as the corresponding methods:
+///
+/// * `on_lookup(|req, parent, name, reply| ..)`
+/// * `on_getattr(|req, ino, fh, reply| ..)`
+/// * `on_readlink(|req, ino, reply| ..)`
+/// * `on_open(|req, ino, flags, reply| ..)`
+/// * `on_read(|req, ino, fh, offset, size, reply| ..)`, without the flags and the lock owner
+/// * `on_readdir(|req, ino, fh, offset, reply| ..)`
+/// * `on_statfs(|req, ino, reply| ..)`
+///
+/// Filesystems needing other operations implement `Filesystem` instead.
+#[derive(Default)]
+pub struct CallbackFilesystem {
+    lookup: Option<LookupFn>,
+    getattr: Option<GetattrFn>,
+    readlink: Option<ReadlinkFn>,
+    open: Option<OpenFn>,
+    read: Option<ReadFn>,
+    readdir: Option<ReaddirFn>,
+    statfs: Option<StatfsFn>,
+}
+
+impl CallbackFilesystem {
+    /// Create a filesystem without any operations
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call the given closure for `Filesystem::lookup()`
+    pub fn on_lookup<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, &OsStr, ReplyEntry) + Send + 'static,
+    {
+        self.lookup = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::getattr()`
+    pub fn on_getattr<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, Option<u64>, ReplyAttr) + Send + 'static,
+    {
+        self.getattr = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::readlink()`
+    pub fn on_readlink<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, ReplyData) + Send + 'static,
+    {
+        self.readlink = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::open()`
+    pub fn on_open<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, i32, ReplyOpen) + Send + 'static,
+    {
+        self.open = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::read()`
+    pub fn on_read<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, u64, i64, u32, ReplyData) + Send + 'static,
+    {
+        self.read = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::readdir()`
+    pub fn on_readdir<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, u64, i64, ReplyDirectory) + Send + 'static,
+    {
+        self.readdir = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given closure for `Filesystem::statfs()`
+    pub fn on_statfs<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Request<'_>, u64, ReplyStatfs) + Send + 'static,
+    {
+        self.statfs = Some(Box::new(f));
+        self
+    }
+}
+
+impl fmt::Debug for CallbackFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Closures can't be printed, only whether they are registered
+        f.debug_struct("CallbackFilesystem")
+            .field("lookup", &self.lookup.is_some())
+            .field("getattr", &self.getattr.is_some())
+            .field("readlink", &self.readlink.is_some())
+            .field("open", &self.open.is_some())
+            .field("read", &self.read.is_some())
+            .field("readdir", &self.readdir.is_some())
+            .field("statfs", &self.statfs.is_some())
+            .finish()
+    }
+}
+
+impl Filesystem for CallbackFilesystem {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match &mut self.lookup {
+            Some(f) => f(req, parent, name, reply),
+            None => reply.error(ENOSYS),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        match &mut self.getattr {
+            Some(f) => f(req, ino, fh, reply),
+            None => reply.error(ENOSYS),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match &mut self.readlink {
+            Some(f) => f(req, ino, reply),
+            None => reply.error(ENOSYS),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match &mut self.open {
+            Some(f) => f(req, ino, flags, reply),
+            None => reply.default_open(),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match &mut self.read {
+            Some(f) => f(req, ino, fh, offset, size, reply),
+            None => reply.error(ENOSYS),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        match &mut self.readdir {
+            Some(f) => f(req, ino, fh, offset, reply),
+            None => reply.error(ENOSYS),
+        }
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        match &mut self.statfs {
+            Some(f) => f(req, ino, reply),
+            None => reply.statfs(0, 0, 0, 0, 0, 512, 255, 0),
+        }
+    }
+}
@@ -1,8 +1,19 @@
-use std::{fs::File, io, os::unix::prelude::AsRawFd, sync::Arc};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::{Arc, Weak},
+    time::Instant,
+};
 
 use libc::{c_int, c_void, size_t};
 
+use crate::dir_handles::DirHandles;
+use crate::lookups::Lookups;
 use crate::reply::ReplySender;
+use crate::session::SessionObserver;
+use crate::stats::Counters;
 
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
@@ -16,6 +27,11 @@ impl Channel {
         Self(device)
     }
 
+    /// Returns the fd of the FUSE device
+    pub(crate) fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
     /// Receives data up to the capacity of the given buffer (can block).
     pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
         let rc = unsafe {
@@ -38,27 +54,217 @@ impl Channel {
     pub fn sender(&self) -> ChannelSender {
         // Since write/writev syscalls are threadsafe, we can simply create
         // a sender by using the same file and use it in other threads.
-        ChannelSender(self.0.clone())
+        ChannelSender {
+            file: self.0.clone(),
+            observer: None,
+            stats: None,
+            dir_handles: None,
+            lookups: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+
+    /// Returns a weak sender object for this channel. Unlike a regular sender, it
+    /// does not keep the channel open once the session has ended.
+    pub(crate) fn weak_sender(&self) -> WeakChannelSender {
+        WeakChannelSender(Arc::downgrade(&self.0))
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct ChannelSender(Arc<File>);
+pub struct ChannelSender {
+    file: Arc<File>,
+    /// Observer to report sent replies to, with the time the request was received
+    observer: Option<(Arc<dyn SessionObserver>, Instant)>,
+    /// Statistics of the session, with the opcode of the request the reply belongs to
+    stats: Option<(Arc<Counters>, u32)>,
+    /// Directory handles of the session, if the reply belongs to an opendir request
+    dir_handles: Option<Arc<DirHandles>>,
+    /// Lookup counts of the session if the reply contains entries, and whether it belongs to
+    /// a readdirplus request
+    lookups: Option<(Arc<Lookups>, bool)>,
+    /// Span of the request the reply belongs to
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl ChannelSender {
+    /// Report replies sent with this sender to the given observer. The duration of a
+    /// request is measured from the time this is called.
+    pub(crate) fn with_observer(mut self, observer: Arc<dyn SessionObserver>) -> Self {
+        self.observer = Some((observer, Instant::now()));
+        self
+    }
+
+    /// Count replies sent with this sender in the given statistics
+    pub(crate) fn with_stats(mut self, stats: Arc<Counters>, opcode: u32) -> Self {
+        self.stats = Some((stats, opcode));
+        self
+    }
+
+    /// Record the handle replied to an opendir request with this sender
+    pub(crate) fn with_dir_handles(mut self, dir_handles: Arc<DirHandles>) -> Self {
+        self.dir_handles = Some(dir_handles);
+        self
+    }
+
+    /// Count the entries replied with this sender in the given lookup counts
+    pub(crate) fn with_lookups(mut self, lookups: Arc<Lookups>, readdirplus: bool) -> Self {
+        self.lookups = Some((lookups, readdirplus));
+        self
+    }
+
+    /// Record the outcome of replies sent with this sender in the given span
+    #[cfg(feature = "tracing")]
+    pub(crate) fn with_span(mut self, span: tracing::Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Returns the span of the request this sender replies to
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Report a sent reply to the observer. The first buffer contains the fuse_out_header,
+    /// which has the request's unique id and the (negated) error code.
+    fn observe(&self, bufs: &[io::IoSlice<'_>]) {
+        if let Some((observer, start)) = &self.observer {
+            let header = match bufs.first() {
+                Some(header) if header.len() >= 16 => header,
+                _ => return,
+            };
+            let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+            let unique = u64::from_ne_bytes(header[8..16].try_into().unwrap());
+            let error = if error != 0 { Some(-error) } else { None };
+            observer.on_reply(unique, start.elapsed(), error);
+        }
+    }
+
+    /// Record the error code of a sent reply in the request's span (0 for success)
+    #[cfg(feature = "tracing")]
+    fn record(&self, bufs: &[io::IoSlice<'_>]) {
+        if let Some(header) = bufs.first().filter(|header| header.len() >= 8) {
+            let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+            self.span.record("errno", &-error);
+        }
+    }
+}
 
 impl ReplySender for ChannelSender {
     fn send(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<()> {
         let rc = unsafe {
             libc::writev(
-                self.0.as_raw_fd(),
+                self.file.as_raw_fd(),
                 bufs.as_ptr() as *const libc::iovec,
                 bufs.len() as c_int,
             )
         };
         if rc < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            debug_assert_eq!(bufs.iter().map(|b| b.len()).sum::<usize>(), rc as usize);
-            Ok(())
+            return Err(io::Error::last_os_error());
+        }
+        debug_assert_eq!(bufs.iter().map(|b| b.len()).sum::<usize>(), rc as usize);
+        // Only replies the kernel accepted count towards the session's bookkeeping
+        self.observe(bufs);
+        if let Some((stats, opcode)) = &self.stats {
+            stats.reply(*opcode, bufs);
+        }
+        if let Some(dir_handles) = &self.dir_handles {
+            dir_handles.opened(bufs);
+        }
+        if let Some((lookups, readdirplus)) = &self.lookups {
+            lookups.replied(*readdirplus, bufs);
+        }
+        #[cfg(feature = "tracing")]
+        self.record(bufs);
+        Ok(())
+    }
+}
+
+/// A sender that doesn't keep the channel alive
+#[derive(Clone, Debug)]
+pub(crate) struct WeakChannelSender(Weak<File>);
+
+impl WeakChannelSender {
+    /// Returns a sender for the channel, or None if the channel was already closed
+    #[cfg(feature = "abi-7-11")]
+    pub(crate) fn upgrade(&self) -> Option<ChannelSender> {
+        self.0.upgrade().map(|file| ChannelSender {
+            file,
+            observer: None,
+            stats: None,
+            dir_handles: None,
+            lookups: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        })
+    }
+
+    /// Returns true if the channel is still open
+    pub(crate) fn is_alive(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::IoSlice;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use libc::c_int;
+    use zerocopy::AsBytes;
+
+    use super::Channel;
+    use crate::ll::fuse_abi as abi;
+    use crate::reply::ReplySender;
+    use crate::session::SessionObserver;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, Option<c_int>)>>);
+
+    impl SessionObserver for Recorder {
+        fn on_reply(&self, unique: u64, _duration: Duration, error: Option<c_int>) {
+            self.0.lock().unwrap().push((unique, error));
         }
     }
+
+    #[test]
+    fn sender_reports_replies() {
+        let ch = Channel::new(Arc::new(tempfile::tempfile().unwrap()));
+        let recorder = Arc::new(Recorder::default());
+        let sender = ch.sender().with_observer(recorder.clone());
+        for (unique, error) in [(0xdeadbeef, 0), (0xcafe, -libc::ENOENT)].iter() {
+            let header = abi::fuse_out_header {
+                len: 16,
+                error: *error,
+                unique: *unique,
+            };
+            sender.send(&[IoSlice::new(header.as_bytes())]).unwrap();
+        }
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(0xdeadbeef, None), (0xcafe, Some(libc::ENOENT))]
+        );
+    }
+
+    #[test]
+    fn failed_replies_are_not_reported() {
+        // Writing to a file opened read-only fails with EBADF
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let ch = Channel::new(Arc::new(File::open(file.path()).unwrap()));
+        let recorder = Arc::new(Recorder::default());
+        let sender = ch.sender().with_observer(recorder.clone());
+        let header = abi::fuse_out_header {
+            len: 16,
+            error: 0,
+            unique: 0xdeadbeef,
+        };
+        let err = sender.send(&[IoSlice::new(header.as_bytes())]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        assert!(recorder.0.lock().unwrap().is_empty());
+    }
 }
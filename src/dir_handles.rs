@@ -0,0 +1,143 @@
+//! Directory handle tracking
+//!
+//! The file handle replied to opendir is passed to readdir, readdirplus and releasedir on
+//! the same directory. To catch bugs in stateful directory handling, a session can keep
+//! track of the handles replied to opendir and warn about handles which are used without
+//! being open (e.g. after releasedir) or which were never released when the session ends.
+//! This is enabled by default in debug builds only, see `Session::set_track_dir_handles()`.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use log::warn;
+
+/// Handles replied to opendir requests which haven't been released yet
+#[derive(Debug, Default)]
+pub(crate) struct DirHandles {
+    /// Number of times each handle was opened and not released yet. Filesystems which don't
+    /// keep state per open directory may reply the same handle (e.g. 0) to every opendir.
+    open: Mutex<BTreeMap<u64, usize>>,
+    /// Set once opendir was replied with ENOSYS. With FUSE_NO_OPENDIR_SUPPORT the kernel
+    /// then stops sending opendir and releasedir, so handles can't be tracked anymore.
+    disabled: AtomicBool,
+}
+
+impl DirHandles {
+    /// Record the reply to an opendir request. The first buffer contains the
+    /// fuse_out_header, followed by a fuse_open_out which starts with the file handle.
+    pub(crate) fn opened(&self, bufs: &[IoSlice<'_>]) {
+        let header = match bufs.first() {
+            Some(header) if header.len() >= 16 => header,
+            _ => return,
+        };
+        let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+        if error == -libc::ENOSYS {
+            self.disabled.store(true, Ordering::Relaxed);
+        }
+        if error != 0 {
+            return;
+        }
+        let fh = bufs.get(1).and_then(|data| data.get(0..8));
+        if let Some(fh) = fh {
+            let fh = u64::from_ne_bytes(fh.try_into().unwrap());
+            *self.open.lock().unwrap().entry(fh).or_insert(0) += 1;
+        }
+    }
+
+    /// Check that the handle passed to the given operation is open, and warn if it isn't.
+    /// Returns false if the handle isn't open.
+    pub(crate) fn check(&self, operation: &str, fh: u64) -> bool {
+        if self.disabled.load(Ordering::Relaxed) || self.open.lock().unwrap().contains_key(&fh) {
+            return true;
+        }
+        warn!(
+            "{} of directory handle {} which isn't open (released or never returned by opendir)",
+            operation, fh
+        );
+        false
+    }
+
+    /// Record the release of a handle, after checking that it is open
+    pub(crate) fn released(&self, fh: u64) {
+        if !self.check("RELEASEDIR", fh) {
+            return;
+        }
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&fh) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&fh);
+            }
+        }
+    }
+
+    /// Returns the handles which are still open
+    #[cfg(test)]
+    pub(crate) fn open(&self) -> Vec<u64> {
+        self.open.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Warn about all handles which are still open, e.g. when the session ends
+    pub(crate) fn warn_unreleased(&self) {
+        for (fh, count) in self.open.lock().unwrap().iter() {
+            warn!(
+                "Directory handle {} was opened {} time(s) but never released",
+                fh, count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::IoSlice;
+
+    use zerocopy::AsBytes;
+
+    use super::DirHandles;
+    use crate::ll::fuse_abi as abi;
+
+    fn reply(handles: &DirHandles, error: i32, fh: u64) {
+        let header = abi::fuse_out_header {
+            len: 32,
+            error,
+            unique: 1,
+        };
+        let arg = abi::fuse_open_out {
+            fh,
+            open_flags: 0,
+            padding: 0,
+        };
+        handles.opened(&[
+            IoSlice::new(header.as_bytes()),
+            IoSlice::new(arg.as_bytes()),
+        ]);
+    }
+
+    #[test]
+    fn track_handles() {
+        let handles = DirHandles::default();
+        reply(&handles, 0, 3);
+        reply(&handles, 0, 3);
+        reply(&handles, 0, 4);
+        reply(&handles, -libc::EACCES, 5);
+        assert_eq!(handles.open(), [3, 4]);
+        assert!(handles.check("READDIR", 3));
+        assert!(!handles.check("READDIR", 5));
+
+        handles.released(3);
+        handles.released(4);
+        assert_eq!(handles.open(), [3]);
+        assert!(!handles.check("READDIR", 4));
+        handles.released(3);
+        handles.released(3);
+        assert!(handles.open().is_empty());
+
+        // Without opendir, readdir is called with any handle
+        reply(&handles, -libc::ENOSYS, 0);
+        assert!(handles.check("READDIR", 0));
+    }
+}
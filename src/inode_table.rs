@@ -0,0 +1,217 @@
+//! Inode number allocation
+//!
+//! The kernel refers to files by inode number. Every entry replied to lookup, create,
+//! mkdir, mknod, symlink, link or readdirplus increments the lookup count of its inode, and
+//! the kernel sends forget with the number of lookups to drop once it no longer needs it. An
+//! inode number may only be reused after its lookup count dropped to zero, and then only with a
+//! new generation number, so NFS file handles of the old file don't refer to the new one.
+//! `InodeTable` implements this bookkeeping for filesystems which map their own objects
+//! (e.g. paths) to inode numbers.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::FUSE_ROOT_ID;
+
+#[derive(Debug)]
+struct Inode<K> {
+    key: K,
+    lookups: u64,
+    generation: u64,
+}
+
+/// Table mapping keys (e.g. paths) to inode numbers, counting lookups
+///
+/// Inode 1 (`FUSE_ROOT_ID`) is reserved for the root, which is never forgotten. Other
+/// inodes are removed once their lookup count drops to zero, and their numbers are reused
+/// with an incremented generation number.
+#[derive(Debug)]
+pub struct InodeTable<K> {
+    inodes: HashMap<u64, Inode<K>>,
+    by_key: HashMap<K, u64>,
+    /// Forgotten inode numbers, with the generation of their last use
+    free: Vec<(u64, u64)>,
+    next: u64,
+}
+
+impl<K: Clone + Eq + Hash> InodeTable<K> {
+    /// Create a table with the given key for the root
+    pub fn new(root: K) -> Self {
+        let mut inodes = HashMap::new();
+        let mut by_key = HashMap::new();
+        by_key.insert(root.clone(), FUSE_ROOT_ID);
+        inodes.insert(
+            FUSE_ROOT_ID,
+            Inode {
+                key: root,
+                lookups: 0,
+                generation: 0,
+            },
+        );
+        InodeTable {
+            inodes,
+            by_key,
+            free: vec![],
+            next: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    /// Returns the inode number and generation for the given key, allocating an inode if
+    /// there is none yet. This counts as one lookup, so call it once for every entry
+    /// replied to the kernel.
+    pub fn allocate(&mut self, key: K) -> (u64, u64) {
+        self.entry(key, 1)
+    }
+
+    /// Returns the inode number and generation for the given key like `allocate()`, but
+    /// without counting a lookup, e.g. for "." and ".." entries of readdirplus, which the
+    /// kernel doesn't count. An inode allocated this way is only removed after it was
+    /// counted and forgotten.
+    pub fn insert(&mut self, key: K) -> (u64, u64) {
+        self.entry(key, 0)
+    }
+
+    fn entry(&mut self, key: K, lookups: u64) -> (u64, u64) {
+        if let Some(&ino) = self.by_key.get(&key) {
+            let inode = self.inodes.get_mut(&ino).unwrap();
+            inode.lookups += lookups;
+            return (ino, inode.generation);
+        }
+        let (ino, generation) = match self.free.pop() {
+            Some((ino, generation)) => (ino, generation + 1),
+            None => {
+                let ino = self.next;
+                self.next += 1;
+                (ino, 0)
+            }
+        };
+        self.by_key.insert(key.clone(), ino);
+        self.inodes.insert(
+            ino,
+            Inode {
+                key,
+                lookups,
+                generation,
+            },
+        );
+        (ino, generation)
+    }
+
+    /// Returns the key of the given inode
+    pub fn lookup(&self, ino: u64) -> Option<&K> {
+        self.inodes.get(&ino).map(|inode| &inode.key)
+    }
+
+    /// Returns the inode number of the given key
+    pub fn ino(&self, key: &K) -> Option<u64> {
+        self.by_key.get(key).copied()
+    }
+
+    /// Returns the generation of the given inode
+    pub fn generation(&self, ino: u64) -> Option<u64> {
+        self.inodes.get(&ino).map(|inode| inode.generation)
+    }
+
+    /// Returns the lookup count of the given inode
+    pub fn lookups(&self, ino: u64) -> Option<u64> {
+        self.inodes.get(&ino).map(|inode| inode.lookups)
+    }
+
+    /// Drop nlookup lookups of the given inode, as requested by `Filesystem::forget()`.
+    /// Returns the key if the lookup count dropped to zero and the inode was removed. An
+    /// nlookup larger than the lookup count drops all lookups. The root is never removed.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> Option<K> {
+        let inode = self.inodes.get_mut(&ino)?;
+        inode.lookups = inode.lookups.saturating_sub(nlookup);
+        if inode.lookups > 0 || ino == FUSE_ROOT_ID {
+            return None;
+        }
+        let inode = self.inodes.remove(&ino).unwrap();
+        if self.by_key.get(&inode.key) == Some(&ino) {
+            self.by_key.remove(&inode.key);
+        }
+        self.free.push((ino, inode.generation));
+        Some(inode.key)
+    }
+
+    /// Change the key of an inode, e.g. after a rename, keeping its number and lookup
+    /// count. An inode with the new key is replaced, it remains allocated only by number so
+    /// that its pending forgets still work. Returns false if the inode doesn't exist.
+    pub fn rekey(&mut self, ino: u64, key: K) -> bool {
+        let inode = match self.inodes.get_mut(&ino) {
+            Some(inode) => inode,
+            None => return false,
+        };
+        if self.by_key.get(&inode.key) == Some(&ino) {
+            self.by_key.remove(&inode.key);
+        }
+        inode.key = key.clone();
+        self.by_key.insert(key, ino);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InodeTable;
+    use crate::FUSE_ROOT_ID;
+
+    #[test]
+    fn allocate_and_forget() {
+        let mut table = InodeTable::new("/".to_string());
+        assert_eq!(table.lookup(FUSE_ROOT_ID).unwrap(), "/");
+        assert_eq!(table.allocate("/".to_string()), (FUSE_ROOT_ID, 0));
+
+        let (a, generation) = table.allocate("/a".to_string());
+        assert_eq!((a, generation), (2, 0));
+        assert_eq!(table.allocate("/a".to_string()), (2, 0));
+        let (b, _) = table.allocate("/b".to_string());
+        assert_eq!(b, 3);
+        assert_eq!(table.lookups(a), Some(2));
+        assert_eq!(table.ino(&"/b".to_string()), Some(3));
+
+        assert_eq!(table.forget(a, 1), None);
+        assert_eq!(table.lookup(a).unwrap(), "/a");
+        assert_eq!(table.forget(a, 1), Some("/a".to_string()));
+        assert_eq!(table.lookup(a), None);
+        assert_eq!(table.ino(&"/a".to_string()), None);
+        assert_eq!(table.forget(a, 1), None);
+
+        // The number is reused with a new generation
+        assert_eq!(table.allocate("/c".to_string()), (a, 1));
+        assert_eq!(table.generation(a), Some(1));
+        assert_eq!(table.allocate("/d".to_string()), (4, 0));
+
+        // The root is never forgotten
+        assert_eq!(table.forget(FUSE_ROOT_ID, 10), None);
+        assert_eq!(table.lookup(FUSE_ROOT_ID).unwrap(), "/");
+    }
+
+    #[test]
+    fn rekey() {
+        let mut table = InodeTable::new("/".to_string());
+        let (a, _) = table.allocate("/a".to_string());
+        let (b, _) = table.allocate("/b".to_string());
+        // Rename /a over /b
+        assert!(table.rekey(a, "/b".to_string()));
+        assert_eq!(table.ino(&"/b".to_string()), Some(a));
+        assert_eq!(table.ino(&"/a".to_string()), None);
+        assert_eq!(table.lookup(b).unwrap(), "/b");
+        // Forgetting the replaced inode doesn't remove the key of the renamed one
+        assert_eq!(table.forget(b, 1), Some("/b".to_string()));
+        assert_eq!(table.ino(&"/b".to_string()), Some(a));
+        assert!(!table.rekey(b, "/c".to_string()));
+    }
+
+    #[test]
+    fn insert() {
+        let mut table = InodeTable::new("/".to_string());
+        assert_eq!(table.insert("/".to_string()), (FUSE_ROOT_ID, 0));
+        let (a, _) = table.insert("/a".to_string());
+        assert_eq!(table.lookups(a), Some(0));
+        assert_eq!(table.allocate("/a".to_string()), (a, 0));
+        assert_eq!(table.insert("/a".to_string()), (a, 0));
+        assert_eq!(table.lookups(a), Some(1));
+        assert_eq!(table.forget(a, 1), Some("/a".to_string()));
+    }
+}
@@ -0,0 +1,168 @@
+//! Request interruption
+//!
+//! When a syscall waiting for a filesystem operation is interrupted (e.g. by a signal), the
+//! kernel sends an interrupt request referring to the unique id of the original request. A
+//! filesystem that works on long running operations can obtain an `InterruptToken` for a
+//! request and poll it to cancel the work and reply with EINTR.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Callbacks to run once a request is interrupted
+#[derive(Default)]
+struct Callbacks(Mutex<Vec<Box<dyn FnOnce() + Send>>>);
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Callbacks({})", self.0.lock().unwrap().len())
+    }
+}
+
+/// In-flight state shared between the session and the tokens of a single request
+#[derive(Debug)]
+struct TokenState {
+    unique: u64,
+    interrupted: AtomicBool,
+    callbacks: Callbacks,
+    registry: Weak<Mutex<HashMap<u64, Weak<TokenState>>>>,
+}
+
+impl Drop for TokenState {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            let mut map = registry.lock().unwrap();
+            // The unique id may have been reused by a newer request, only remove our own entry
+            if matches!(map.get(&self.unique), Some(w) if w.strong_count() == 0) {
+                map.remove(&self.unique);
+            }
+        }
+    }
+}
+
+/// A token to check whether a request was interrupted by the kernel
+///
+/// Tokens can be cloned and sent to other threads together with the reply. Once interrupted,
+/// the filesystem should stop working on the request and reply with EINTR. Replying with the
+/// result of the operation is fine as well, if it is already available.
+#[derive(Clone, Debug)]
+pub struct InterruptToken(Arc<TokenState>);
+
+impl InterruptToken {
+    /// Returns the unique id of the request this token belongs to
+    pub fn unique(&self) -> u64 {
+        self.0.unique
+    }
+
+    /// Returns true if the kernel requested to interrupt the request
+    pub fn is_interrupted(&self) -> bool {
+        self.0.interrupted.load(Ordering::Acquire)
+    }
+
+    /// Call f once the kernel requests to interrupt the request, or right away if it already
+    /// did. This wakes up work which is blocked instead of polling, like a thread waiting
+    /// for a lock in `Filesystem::setlk()`. f is called on the session's thread and should
+    /// return quickly, e.g. by notifying a condition variable. It is never called if the
+    /// request isn't interrupted, or if all tokens of the request were dropped before, so
+    /// keep a token while waiting.
+    pub fn on_interrupt<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let mut callbacks = self.0.callbacks.0.lock().unwrap();
+        // Checked with the lock held, interrupt() sets the flag before taking the callbacks
+        if self.is_interrupted() {
+            drop(callbacks);
+            f();
+        } else {
+            callbacks.push(Box::new(f));
+        }
+    }
+}
+
+/// Registry of requests for which an interrupt token was handed out
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Interrupts(Arc<Mutex<HashMap<u64, Weak<TokenState>>>>);
+
+impl Interrupts {
+    /// Returns a token for the request with the given unique id. All tokens for the same
+    /// request share their state.
+    pub(crate) fn token(&self, unique: u64) -> InterruptToken {
+        let mut map = self.0.lock().unwrap();
+        if let Some(state) = map.get(&unique).and_then(Weak::upgrade) {
+            return InterruptToken(state);
+        }
+        let state = Arc::new(TokenState {
+            unique,
+            interrupted: AtomicBool::new(false),
+            callbacks: Callbacks::default(),
+            registry: Arc::downgrade(&self.0),
+        });
+        map.insert(unique, Arc::downgrade(&state));
+        InterruptToken(state)
+    }
+
+    /// Mark the request with the given unique id as interrupted. Returns false if no token
+    /// exists for the request, i.e. it was already replied or the filesystem doesn't check
+    /// for interrupts.
+    pub(crate) fn interrupt(&self, unique: u64) -> bool {
+        // Release the lock before the state may be dropped, which locks the registry again
+        let state = self.0.lock().unwrap().get(&unique).and_then(Weak::upgrade);
+        match state {
+            Some(state) => {
+                state.interrupted.store(true, Ordering::Release);
+                let callbacks = std::mem::take(&mut *state.callbacks.0.lock().unwrap());
+                for f in callbacks {
+                    f();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use super::Interrupts;
+
+    #[test]
+    fn interrupt_token() {
+        let interrupts = Interrupts::default();
+        let token = interrupts.token(42);
+        let other = interrupts.token(43);
+        assert!(!token.is_interrupted());
+        assert!(interrupts.interrupt(42));
+        assert!(token.is_interrupted());
+        assert!(interrupts.token(42).is_interrupted());
+        assert!(!other.is_interrupted());
+    }
+
+    #[test]
+    fn on_interrupt() {
+        let interrupts = Interrupts::default();
+        let token = interrupts.token(42);
+        let (tx, rx) = channel();
+        let sender = tx.clone();
+        token.on_interrupt(move || sender.send("first").unwrap());
+        assert!(rx.try_recv().is_err());
+        assert!(interrupts.interrupt(42));
+        assert_eq!(rx.try_recv(), Ok("first"));
+        // Called right away once interrupted, and only once
+        token.on_interrupt(move || tx.send("second").unwrap());
+        assert_eq!(rx.try_recv(), Ok("second"));
+        assert!(interrupts.interrupt(42));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn interrupt_after_drop() {
+        let interrupts = Interrupts::default();
+        let token = interrupts.token(42);
+        drop(token.clone());
+        assert!(interrupts.interrupt(42));
+        drop(token);
+        assert!(!interrupts.interrupt(42));
+        assert!(interrupts.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! Well-known ioctl commands
+//!
+//! `Filesystem::ioctl()` is passed the raw command number, which encodes the direction and
+//! size of its argument in an architecture dependent way (see ioctl(2)). `IoctlCommand`
+//! decodes the commands most filesystems want to answer, so they don't have to hand-roll the
+//! `_IOR`/`_IOW` encoding and the layout of the argument.
+
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use libc::c_long;
+
+// The direction bits and the size field width differ between architectures, see
+// include/uapi/asm-generic/ioctl.h and the arch specific overrides in the kernel
+#[cfg(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+))]
+mod encoding {
+    pub const READ: u32 = 2;
+    pub const WRITE: u32 = 4;
+    pub const DIR_SHIFT: u32 = 29;
+}
+#[cfg(not(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+)))]
+mod encoding {
+    pub const READ: u32 = 2;
+    pub const WRITE: u32 = 1;
+    pub const DIR_SHIFT: u32 = 30;
+}
+
+const fn ioc(dir: u32, ty: u8, nr: u8, size: usize) -> u32 {
+    (dir << encoding::DIR_SHIFT) | ((size as u32) << 16) | ((ty as u32) << 8) | nr as u32
+}
+
+/// `_IOR('f', 1, long)`: get the inode flags
+const FS_IOC_GETFLAGS: u32 = ioc(encoding::READ, b'f', 1, size_of::<c_long>());
+/// `_IOW('f', 2, long)`: set the inode flags
+const FS_IOC_SETFLAGS: u32 = ioc(encoding::WRITE, b'f', 2, size_of::<c_long>());
+/// `_IOR('f', 1, int)`: get the inode flags, sent for 32-bit processes on 64-bit kernels
+const FS_IOC32_GETFLAGS: u32 = ioc(encoding::READ, b'f', 1, size_of::<i32>());
+/// `_IOW('f', 2, int)`: set the inode flags, sent for 32-bit processes on 64-bit kernels
+const FS_IOC32_SETFLAGS: u32 = ioc(encoding::WRITE, b'f', 2, size_of::<i32>());
+
+/// A well-known ioctl command, decoded from the arguments of `Filesystem::ioctl()`
+///
+/// The inode flags are the `FS_*_FL` flags of chattr(1), e.g. `FS_IMMUTABLE_FL`. Although
+/// the commands are declared with a long argument, the flags are an int: depending on the
+/// kernel version and the calling process, the argument is 4 or 8 bytes, and only the first
+/// 4 bytes in native byte order hold the flags.
+///
+/// FIGETBSZ is answered by the kernel with the block size of the mount (the `blksize` mount
+/// option for fuseblk mounts, the page size otherwise) and never reaches the filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoctlCommand {
+    /// FS_IOC_GETFLAGS: reply with `ReplyIoctl::inode_flags()`
+    GetFlags,
+    /// FS_IOC_SETFLAGS with the new flags: reply with `ReplyIoctl::ioctl(0, &[])`, or with
+    /// EPERM or EOPNOTSUPP for flags that can't be set
+    SetFlags(u32),
+}
+
+impl IoctlCommand {
+    /// Decode the command and input data passed to `Filesystem::ioctl()`. Returns None for
+    /// other commands, or if the input data is too short.
+    pub fn parse(cmd: u32, in_data: &[u8]) -> Option<IoctlCommand> {
+        // On 32-bit architectures, the long and int variants are the same command
+        match cmd {
+            c if c == FS_IOC_GETFLAGS || c == FS_IOC32_GETFLAGS => Some(IoctlCommand::GetFlags),
+            c if c == FS_IOC_SETFLAGS || c == FS_IOC32_SETFLAGS => {
+                let flags = in_data.get(..4)?.try_into().unwrap();
+                Some(IoctlCommand::SetFlags(u32::from_ne_bytes(flags)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn command_numbers() {
+        assert_eq!(FS_IOC_GETFLAGS, 0x8008_6601);
+        assert_eq!(FS_IOC_SETFLAGS, 0x4008_6602);
+        assert_eq!(FS_IOC32_GETFLAGS, 0x8004_6601);
+        assert_eq!(FS_IOC32_SETFLAGS, 0x4004_6602);
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            IoctlCommand::parse(FS_IOC_GETFLAGS, &[]),
+            Some(IoctlCommand::GetFlags)
+        );
+        assert_eq!(
+            IoctlCommand::parse(FS_IOC32_GETFLAGS, &[]),
+            Some(IoctlCommand::GetFlags)
+        );
+        let flags = 0x10u32.to_ne_bytes(); // FS_IMMUTABLE_FL
+        assert_eq!(
+            IoctlCommand::parse(FS_IOC32_SETFLAGS, &flags),
+            Some(IoctlCommand::SetFlags(0x10))
+        );
+        // A long argument, of which only the first 4 bytes are the flags
+        let long = [&flags[..], &[0xff; 4]].concat();
+        assert_eq!(
+            IoctlCommand::parse(FS_IOC_SETFLAGS, &long),
+            Some(IoctlCommand::SetFlags(0x10))
+        );
+        assert_eq!(IoctlCommand::parse(FS_IOC_SETFLAGS, &flags[..2]), None);
+        assert_eq!(IoctlCommand::parse(0x5401, &[]), None); // TCGETS
+    }
+}
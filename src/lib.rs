@@ -19,11 +19,25 @@ use std::time::SystemTime;
 use std::{convert::AsRef, io::ErrorKind};
 
 use crate::ll::fuse_abi::consts::*;
+pub use crate::ll::fuse_abi::fuse_attr;
+#[cfg(feature = "abi-7-31")]
+pub use crate::ll::fuse_abi::fuse_removemapping_one;
 pub use crate::ll::fuse_abi::FUSE_ROOT_ID;
 pub use crate::ll::{fuse_abi::consts, TimeOrNow};
-use crate::mnt::mount_options::check_option_conflicts;
 use crate::session::MAX_WRITE_SIZE;
+pub use callback::CallbackFilesystem;
+pub use inode_table::InodeTable;
+pub use interrupt::InterruptToken;
+#[cfg(target_os = "linux")]
+pub use ioctl::IoctlCommand;
+pub use logging::LoggingFilesystem;
 pub use mnt::mount_options::MountOption;
+#[cfg(target_os = "linux")]
+pub use mnt::MountInfo;
+pub use notify::Notifier;
+pub use permissions::PermissionChecking;
+#[cfg(feature = "abi-7-11")]
+pub use reply::ReplyPoll;
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
@@ -33,31 +47,46 @@ pub use reply::{
     ReplyStatfs, ReplyWrite,
 };
 pub use request::Request;
-pub use session::{BackgroundSession, Session};
+pub use session::{
+    BackgroundSession, RetryPolicy, Session, SessionBuilder, SessionExit, SessionObserver,
+};
+pub use stats::SessionStats;
 #[cfg(feature = "abi-7-28")]
 use std::cmp::max;
 #[cfg(feature = "abi-7-13")]
 use std::cmp::min;
 
+mod callback;
 mod channel;
+mod dir_handles;
+mod inode_table;
+mod interrupt;
+#[cfg(target_os = "linux")]
+mod ioctl;
 mod ll;
+mod logging;
+mod lookups;
 mod mnt;
+mod notify;
+mod permissions;
 mod reply;
 mod request;
 mod session;
+mod signal;
+mod stats;
+mod write_combining;
 
 /// We generally support async reads
 #[cfg(all(not(target_os = "macos"), not(feature = "abi-7-10")))]
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
 #[cfg(all(not(target_os = "macos"), feature = "abi-7-10"))]
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_BIG_WRITES;
-// TODO: Add FUSE_EXPORT_SUPPORT
 
 /// On macOS, we additionally support case insensitiveness, volume renames and xtimes
 /// TODO: we should eventually let the filesystem implementation decide which flags to set
 #[cfg(target_os = "macos")]
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
-// TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
+// TODO: Add FUSE_BIG_WRITES (requires ABI 7.10)
 
 const fn default_init_flags(#[allow(unused_variables)] capabilities: u32) -> u32 {
     #[cfg(not(feature = "abi-7-28"))]
@@ -95,6 +124,230 @@ pub enum FileType {
     Socket,
 }
 
+// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
+// But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
+#[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+impl FileType {
+    /// Returns the file type given by the file type bits (S_IFMT) of a mode, e.g. as passed
+    /// to `Filesystem::mknod()`. Permission bits are ignored. Returns `None` if the bits
+    /// don't denote a known file type.
+    pub fn from_mode(mode: u32) -> Option<FileType> {
+        match mode as libc::mode_t & libc::S_IFMT {
+            libc::S_IFIFO => Some(FileType::NamedPipe),
+            libc::S_IFCHR => Some(FileType::CharDevice),
+            libc::S_IFBLK => Some(FileType::BlockDevice),
+            libc::S_IFDIR => Some(FileType::Directory),
+            libc::S_IFREG => Some(FileType::RegularFile),
+            libc::S_IFLNK => Some(FileType::Symlink),
+            libc::S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Returns the file type bits (S_IFMT) of a mode for this file type
+    pub fn to_mode(self) -> u32 {
+        (match self {
+            FileType::NamedPipe => libc::S_IFIFO,
+            FileType::CharDevice => libc::S_IFCHR,
+            FileType::BlockDevice => libc::S_IFBLK,
+            FileType::Directory => libc::S_IFDIR,
+            FileType::RegularFile => libc::S_IFREG,
+            FileType::Symlink => libc::S_IFLNK,
+            FileType::Socket => libc::S_IFSOCK,
+        }) as u32
+    }
+}
+
+/// Access mode of an opened file (O_ACCMODE bits of the open flags)
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AccessMode {
+    /// Opened for reading only (O_RDONLY)
+    ReadOnly,
+    /// Opened for writing only (O_WRONLY)
+    WriteOnly,
+    /// Opened for reading and writing (O_RDWR)
+    ReadWrite,
+}
+
+/// Typed access to the flags passed to `Filesystem::open()`, `Filesystem::opendir()` and
+/// `Filesystem::create()`, e.g. `OpenFlags(flags).is_append()`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct OpenFlags(pub i32);
+
+impl OpenFlags {
+    /// Returns the access mode the file is opened with
+    pub fn access_mode(self) -> AccessMode {
+        match self.0 & libc::O_ACCMODE {
+            libc::O_RDONLY => AccessMode::ReadOnly,
+            libc::O_WRONLY => AccessMode::WriteOnly,
+            _ => AccessMode::ReadWrite,
+        }
+    }
+
+    /// Returns true if all writes append to the end of the file (O_APPEND)
+    pub fn is_append(self) -> bool {
+        self.0 & libc::O_APPEND != 0
+    }
+
+    /// Returns true if the file should be truncated (O_TRUNC). The kernel only passes this
+    /// flag to `open()` if `KernelConfig::set_atomic_o_trunc()` was enabled in `init()`.
+    /// Otherwise it truncates the file by calling `setattr()` with a size of 0 before opening
+    /// it.
+    pub fn is_truncate(self) -> bool {
+        self.0 & libc::O_TRUNC != 0
+    }
+
+    /// Returns true if the file is opened in non-blocking mode (O_NONBLOCK)
+    pub fn is_nonblock(self) -> bool {
+        self.0 & libc::O_NONBLOCK != 0
+    }
+
+    /// Returns true if the file is opened for direct I/O, bypassing the page cache (O_DIRECT)
+    #[cfg(not(target_os = "macos"))]
+    pub fn is_direct(self) -> bool {
+        self.0 & libc::O_DIRECT != 0
+    }
+}
+
+impl From<i32> for OpenFlags {
+    fn from(flags: i32) -> Self {
+        OpenFlags(flags)
+    }
+}
+
+/// Set of optional operations a filesystem implements, see `Filesystem::supported_ops()`
+///
+/// Every operation has one bit, operations are combined with `|`, e.g.
+/// `Ops::LOOKUP | Ops::GETATTR | Ops::READDIRPLUS`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Ops(pub u64);
+
+impl Ops {
+    /// No operation
+    pub const NONE: Ops = Ops(0);
+    /// All operations. This is the default, which dispatches every request to the filesystem
+    pub const ALL: Ops = Ops(!0);
+    /// `lookup_inode()`. It has no opcode of its own, the kernel sends it as a lookup of "."
+    pub const LOOKUP_INODE: Ops = Ops(1 << 0);
+    /// `lookup()`
+    pub const LOOKUP: Ops = Ops(1 << 1);
+    /// `getattr()`
+    pub const GETATTR: Ops = Ops(1 << 3);
+    /// `setattr()`
+    pub const SETATTR: Ops = Ops(1 << 4);
+    /// `readlink()`
+    pub const READLINK: Ops = Ops(1 << 5);
+    /// `symlink()`
+    pub const SYMLINK: Ops = Ops(1 << 6);
+    /// `mknod()`
+    pub const MKNOD: Ops = Ops(1 << 8);
+    /// `mkdir()`
+    pub const MKDIR: Ops = Ops(1 << 9);
+    /// `unlink()`
+    pub const UNLINK: Ops = Ops(1 << 10);
+    /// `rmdir()`
+    pub const RMDIR: Ops = Ops(1 << 11);
+    /// `rename()`
+    pub const RENAME: Ops = Ops(1 << 12);
+    /// `link()`
+    pub const LINK: Ops = Ops(1 << 13);
+    /// `open()`
+    pub const OPEN: Ops = Ops(1 << 14);
+    /// `read()`
+    pub const READ: Ops = Ops(1 << 15);
+    /// `write()`
+    pub const WRITE: Ops = Ops(1 << 16);
+    /// `statfs()`
+    pub const STATFS: Ops = Ops(1 << 17);
+    /// `release()`
+    pub const RELEASE: Ops = Ops(1 << 18);
+    /// `fsync()`
+    pub const FSYNC: Ops = Ops(1 << 20);
+    /// `setxattr()`
+    pub const SETXATTR: Ops = Ops(1 << 21);
+    /// `getxattr()`
+    pub const GETXATTR: Ops = Ops(1 << 22);
+    /// `listxattr()`
+    pub const LISTXATTR: Ops = Ops(1 << 23);
+    /// `removexattr()`
+    pub const REMOVEXATTR: Ops = Ops(1 << 24);
+    /// `flush()`
+    pub const FLUSH: Ops = Ops(1 << 25);
+    /// `opendir()`
+    pub const OPENDIR: Ops = Ops(1 << 27);
+    /// `readdir()`
+    pub const READDIR: Ops = Ops(1 << 28);
+    /// `releasedir()`
+    pub const RELEASEDIR: Ops = Ops(1 << 29);
+    /// `fsyncdir()`
+    pub const FSYNCDIR: Ops = Ops(1 << 30);
+    /// `getlk()`
+    pub const GETLK: Ops = Ops(1 << 31);
+    /// `setlk()`, for both FUSE_SETLK and FUSE_SETLKW
+    pub const SETLK: Ops = Ops(1 << 32);
+    /// `access()`
+    pub const ACCESS: Ops = Ops(1 << 34);
+    /// `create()`
+    pub const CREATE: Ops = Ops(1 << 35);
+    /// `bmap()`
+    pub const BMAP: Ops = Ops(1 << 37);
+    /// `ioctl()`
+    pub const IOCTL: Ops = Ops(1 << 39);
+    /// `poll()`
+    pub const POLL: Ops = Ops(1 << 40);
+    /// `fallocate()`
+    pub const FALLOCATE: Ops = Ops(1 << 43);
+    /// `readdirplus()`
+    pub const READDIRPLUS: Ops = Ops(1 << 44);
+    /// `rename()` with flags (FUSE_RENAME2)
+    pub const RENAME2: Ops = Ops(1 << 45);
+    /// `lseek()`
+    pub const LSEEK: Ops = Ops(1 << 46);
+    /// `copy_file_range()`
+    pub const COPY_FILE_RANGE: Ops = Ops(1 << 47);
+    /// `setupmapping()`
+    pub const SETUPMAPPING: Ops = Ops(1 << 48);
+    /// `removemapping()`
+    pub const REMOVEMAPPING: Ops = Ops(1 << 49);
+    /// `syncfs()`
+    pub const SYNCFS: Ops = Ops(1 << 50);
+    /// `tmpfile()`
+    pub const TMPFILE: Ops = Ops(1 << 51);
+    /// `setvolname()` (macOS)
+    pub const SETVOLNAME: Ops = Ops(1 << 61);
+    /// `getxtimes()` (macOS)
+    pub const GETXTIMES: Ops = Ops(1 << 62);
+    /// `exchange()` (macOS)
+    pub const EXCHANGE: Ops = Ops(1 << 63);
+
+    /// Returns true if all operations of other are in this set
+    pub fn contains(self, other: Ops) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if requests with the given opcode are passed to the filesystem. Forgets
+    /// are always passed on, since the lookup counts must be dropped in any case, and so is
+    /// anything that has no bit, like CUSE_INIT.
+    pub(crate) fn contains_opcode(self, opcode: u32) -> bool {
+        match opcode {
+            // FUSE_SETLKW is handled by setlk()
+            33 => self.contains(Ops::SETLK),
+            // FUSE_FORGET and FUSE_BATCH_FORGET
+            2 | 42 => true,
+            0..=63 => self.0 & 1 << opcode != 0,
+            _ => true,
+        }
+    }
+}
+
+impl std::ops::BitOr for Ops {
+    type Output = Ops;
+
+    fn bitor(self, other: Ops) -> Ops {
+        Ops(self.0 | other.0)
+    }
+}
+
 /// File attributes
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "serializable", derive(Serialize, Deserialize))]
@@ -111,7 +364,8 @@ pub struct FileAttr {
     pub mtime: SystemTime,
     /// Time of last change
     pub ctime: SystemTime,
-    /// Time of creation (macOS only)
+    /// Time of creation (macOS only). The Linux kernel protocol has no birth time, so this is
+    /// ignored there and statx() never reports STATX_BTIME for files on FUSE filesystems.
     pub crtime: SystemTime,
     /// Kind of file (directory, file, pipe, etc)
     pub kind: FileType,
@@ -131,8 +385,160 @@ pub struct FileAttr {
     pub flags: u32,
 }
 
+/// Builder for `FileAttr`
+///
+/// Fields which are not set explicitly default to: size and blocks of 0, the current time for
+/// all timestamps, permissions of 0o644 (0o755 for directories), a link count of 1, the uid and
+/// gid of the current process, a rdev of 0, a block size of 512 and no flags.
+#[derive(Clone, Copy, Debug)]
+pub struct FileAttrBuilder {
+    attr: FileAttr,
+}
+
+impl FileAttrBuilder {
+    /// Create a new builder for a file of the given kind with the given inode number
+    pub fn new(ino: u64, kind: FileType) -> Self {
+        let now = SystemTime::now();
+        Self {
+            attr: FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind,
+                perm: if kind == FileType::Directory {
+                    0o755
+                } else {
+                    0o644
+                },
+                nlink: 1,
+                uid: unsafe { libc::geteuid() },
+                gid: unsafe { libc::getegid() },
+                rdev: 0,
+                blksize: 512,
+                flags: 0,
+            },
+        }
+    }
+
+    /// Set the size in bytes
+    pub fn size(mut self, size: u64) -> Self {
+        self.attr.size = size;
+        self
+    }
+
+    /// Set the size in blocks
+    pub fn blocks(mut self, blocks: u64) -> Self {
+        self.attr.blocks = blocks;
+        self
+    }
+
+    /// Set the time of last access
+    pub fn atime(mut self, atime: SystemTime) -> Self {
+        self.attr.atime = atime;
+        self
+    }
+
+    /// Set the time of last modification
+    pub fn mtime(mut self, mtime: SystemTime) -> Self {
+        self.attr.mtime = mtime;
+        self
+    }
+
+    /// Set the time of last change
+    pub fn ctime(mut self, ctime: SystemTime) -> Self {
+        self.attr.ctime = ctime;
+        self
+    }
+
+    /// Set the time of creation (macOS only, ignored on Linux)
+    pub fn crtime(mut self, crtime: SystemTime) -> Self {
+        self.attr.crtime = crtime;
+        self
+    }
+
+    /// Set the permissions
+    pub fn perm(mut self, perm: u16) -> Self {
+        self.attr.perm = perm;
+        self
+    }
+
+    /// Set the number of hard links
+    pub fn nlink(mut self, nlink: u32) -> Self {
+        self.attr.nlink = nlink;
+        self
+    }
+
+    /// Set the user id
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.attr.uid = uid;
+        self
+    }
+
+    /// Set the group id
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.attr.gid = gid;
+        self
+    }
+
+    /// Set the rdev
+    pub fn rdev(mut self, rdev: u32) -> Self {
+        self.attr.rdev = rdev;
+        self
+    }
+
+    /// Set the block size
+    pub fn blksize(mut self, blksize: u32) -> Self {
+        self.attr.blksize = blksize;
+        self
+    }
+
+    /// Set the flags (macOS only, see chflags(2))
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.attr.flags = flags;
+        self
+    }
+
+    /// Build the `FileAttr`
+    pub fn build(self) -> FileAttr {
+        self.attr
+    }
+}
+
+impl From<FileAttrBuilder> for FileAttr {
+    fn from(builder: FileAttrBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl FileAttr {
+    /// Returns the attributes encoded in the given `fuse_attr`, the reverse of converting
+    /// them into one. Returns None if the mode has no known file type. Fields which
+    /// `fuse_attr` doesn't have are set to their defaults: crtime to `UNIX_EPOCH` and flags
+    /// to 0 except on macOS, blksize to 0 without ABI 7.9.
+    pub fn from_fuse_attr(attr: &fuse_attr) -> Option<FileAttr> {
+        ll::reply::attr_from_fuse_attr(attr)
+    }
+}
+
+/// Encode the attributes as sent to the kernel in entry, attr and create replies, e.g. for
+/// building replies by hand. Times before the epoch are encoded like a timespec, with the
+/// nanoseconds counting forward from the negative seconds. The file type and permissions
+/// are combined into the mode.
+impl From<&FileAttr> for fuse_attr {
+    fn from(attr: &FileAttr) -> Self {
+        ll::reply::fuse_attr_from_attr(attr)
+    }
+}
+
 /// Configuration of the fuse kernel module connection
-#[derive(Debug)]
+///
+/// After init, the negotiated config is available from `Session::kernel_config()` and
+/// `BackgroundSession::kernel_config()`.
+#[derive(Clone, Debug)]
 pub struct KernelConfig {
     capabilities: u32,
     requested: u32,
@@ -143,6 +549,7 @@ pub struct KernelConfig {
     #[cfg(feature = "abi-7-13")]
     congestion_threshold: Option<u16>,
     max_write: u32,
+    max_read: Option<u32>,
     #[cfg(feature = "abi-7-23")]
     time_gran: Duration,
 }
@@ -160,6 +567,7 @@ impl KernelConfig {
             congestion_threshold: None,
             // use a max write size that fits into the session's buffer
             max_write: MAX_WRITE_SIZE as u32,
+            max_read: None,
             // 1ns means nano-second granularity.
             #[cfg(feature = "abi-7-23")]
             time_gran: Duration::new(0, 1),
@@ -194,6 +602,11 @@ impl KernelConfig {
 
     /// Set the maximum write size for a single request
     ///
+    /// Write sizes above 128KiB require FUSE_MAX_PAGES (ABI >= 7.28), which is requested
+    /// automatically if the kernel supports it. Otherwise, or if the value exceeds the kernel's
+    /// own limit for the max_pages setting, the kernel silently uses a smaller write size.
+    /// The buffer for receiving requests is sized to fit this value.
+    ///
     /// On success returns the previous value. On error returns the nearest value which will succeed
     pub fn set_max_write(&mut self, value: u32) -> Result<u32, u32> {
         if value == 0 {
@@ -209,6 +622,8 @@ impl KernelConfig {
 
     /// Set the maximum readahead size
     ///
+    /// With ABI >= 7.28, values above 128KiB also raise the maximum size of read requests.
+    ///
     /// On success returns the previous value. On error returns the nearest value which will succeed
     pub fn set_max_readahead(&mut self, value: u32) -> Result<u32, u32> {
         if value == 0 {
@@ -224,6 +639,15 @@ impl KernelConfig {
 
     /// Add a set of capabilities.
     ///
+    /// Most operations are sent by the kernel without negotiating a capability first, and if
+    /// the filesystem replies with ENOSYS the kernel falls back to a generic implementation
+    /// (e.g. for `lseek` and `copy_file_range`). Some operations however are only sent if
+    /// the matching capability was requested:
+    /// - `getlk`/`setlk`: FUSE_POSIX_LOCKS (and FUSE_FLOCK_LOCKS for flock(2), ABI >= 7.17)
+    /// - `readdirplus`: FUSE_DO_READDIRPLUS, optionally with FUSE_READDIRPLUS_AUTO (ABI >= 7.21)
+    /// - `ioctl` on directories: FUSE_HAS_IOCTL_DIR (ABI >= 7.18)
+    /// - `lookup_inode` and lookups of "..": FUSE_EXPORT_SUPPORT (ABI >= 7.10)
+    ///
     /// On success returns Ok, else return bits of capabilities not supported when capabilities you provided are not all supported by kernel.
     pub fn add_capabilities(&mut self, capabilities_to_add: u32) -> Result<(), u32> {
         if capabilities_to_add & self.capabilities != capabilities_to_add {
@@ -233,6 +657,94 @@ impl KernelConfig {
         Ok(())
     }
 
+    /// Request or stop requesting a single capability, for the boolean setters
+    ///
+    /// Returns whether it was requested before, or Err(false) if the kernel doesn't support it.
+    #[cfg(feature = "abi-7-9")]
+    fn set_capability(&mut self, flag: u32, value: bool) -> Result<bool, bool> {
+        if value && self.capabilities & flag == 0 {
+            return Err(false);
+        }
+        let previous = self.requested & flag != 0;
+        if value {
+            self.requested |= flag;
+        } else {
+            self.requested &= !flag;
+        }
+        Ok(previous)
+    }
+
+    /// Handle O_TRUNC in `open()` instead of a preceding `setattr()`
+    ///
+    /// Requests FUSE_ATOMIC_O_TRUNC, so that the kernel passes O_TRUNC to `open()` and the
+    /// filesystem can truncate and open the file in a single step. Otherwise the kernel
+    /// truncates the file with `setattr()` before opening it.
+    ///
+    /// On success returns the previous value. On error returns the nearest value which will succeed
+    #[cfg(feature = "abi-7-9")]
+    pub fn set_atomic_o_trunc(&mut self, value: bool) -> Result<bool, bool> {
+        self.set_capability(FUSE_ATOMIC_O_TRUNC, value)
+    }
+
+    /// Clear the setuid and setgid bits in the filesystem instead of the kernel
+    ///
+    /// Requests FUSE_HANDLE_KILLPRIV. The kernel then no longer clears the setuid and setgid
+    /// bits (and the security.capability xattr) itself when a file is written, truncated or
+    /// its owner is changed, and no longer sends a separate `setattr()` for that. Instead the
+    /// filesystem must clear them in `write()`, and in `setattr()` when the size, uid or gid
+    /// is changed, unless the caller has CAP_FSETID. With ABI >= 7.31, writes that must
+    /// clear the bits carry FUSE_WRITE_KILL_PRIV in write_flags.
+    ///
+    /// FUSE_HANDLE_KILLPRIV_V2 (ABI 7.33) is not supported. With it, the kernel marks every
+    /// operation that must clear the bits: writes with FUSE_WRITE_KILL_SUIDGID, truncating
+    /// setattrs with FATTR_KILL_SUIDGID and opens with O_TRUNC with FUSE_OPEN_KILL_SUIDGID.
+    ///
+    /// On success returns the previous value. On error returns the nearest value which will succeed
+    #[cfg(feature = "abi-7-26")]
+    pub fn set_handle_killpriv(&mut self, value: bool) -> Result<bool, bool> {
+        self.set_capability(FUSE_HANDLE_KILLPRIV, value)
+    }
+
+    /// Let the kernel enforce POSIX ACLs
+    ///
+    /// Requests FUSE_POSIX_ACL. If mounted with `MountOption::DefaultPermissions`, the kernel
+    /// then checks the ACL stored in the system.posix_acl_access xattr in addition to the
+    /// mode bits, and caches the ACL xattrs. The filesystem stores them like other xattrs
+    /// (see `setxattr()` and `getxattr()`), and must apply the default ACL of the parent
+    /// directory (system.posix_acl_default) to new files itself.
+    ///
+    /// On success returns the previous value. On error returns the nearest value which will succeed
+    #[cfg(feature = "abi-7-26")]
+    pub fn set_posix_acl(&mut self, value: bool) -> Result<bool, bool> {
+        self.set_capability(FUSE_POSIX_ACL, value)
+    }
+
+    /// Let the kernel cache symlink targets
+    ///
+    /// Requests FUSE_CACHE_SYMLINKS. The kernel then keeps the target replied to `readlink()`
+    /// in its page cache and doesn't send readlink again for the inode until the cache is
+    /// invalidated with `Notifier::inval_inode()`, which the filesystem has to do itself if
+    /// the target changes.
+    ///
+    /// On success returns the previous value. On error returns the nearest value which will succeed
+    #[cfg(feature = "abi-7-28")]
+    pub fn set_cache_symlinks(&mut self, value: bool) -> Result<bool, bool> {
+        self.set_capability(FUSE_CACHE_SYMLINKS, value)
+    }
+
+    /// Only invalidate cached file data on explicit request
+    ///
+    /// Requests FUSE_EXPLICIT_INVAL_DATA. By default the kernel drops the cached data of a
+    /// file when getattr reports a changed mtime or size. With this, cached data is kept
+    /// until the filesystem invalidates it with `Notifier::inval_inode()`, e.g. for
+    /// filesystems where mtime changes don't imply content changes. Disabled by default.
+    ///
+    /// On success returns the previous value. On error returns the nearest value which will succeed
+    #[cfg(feature = "abi-7-30")]
+    pub fn set_explicit_invalidate_data(&mut self, value: bool) -> Result<bool, bool> {
+        self.set_capability(FUSE_EXPLICIT_INVAL_DATA, value)
+    }
+
     /// Set the maximum number of pending background requests. Such as readahead requests.
     ///
     /// On success returns the previous value. On error returns the nearest value which will succeed
@@ -260,8 +772,50 @@ impl KernelConfig {
         Ok(previous)
     }
 
+    /// Returns the capabilities supported by the kernel (FUSE_* flags in `consts`)
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Returns the capabilities which are requested and supported by the kernel, i.e. the
+    /// ones enabled for the connection once init is replied
+    pub fn negotiated_capabilities(&self) -> u32 {
+        self.capabilities & self.requested
+    }
+
+    /// Returns the max size of write requests
+    pub fn max_write(&self) -> u32 {
+        self.max_write
+    }
+
+    /// Returns the max readahead size
+    pub fn max_readahead(&self) -> u32 {
+        self.max_readahead
+    }
+
+    /// Returns the max size of read requests set with `MountOption::MaxRead`, or None if the
+    /// filesystem was mounted without it. Unlike the other values, this isn't negotiated
+    /// during init but fixed at mount time, so it can't be changed here.
+    pub fn max_read(&self) -> Option<u32> {
+        self.max_read
+    }
+
+    /// Returns the max number of pending background requests
+    #[cfg(feature = "abi-7-13")]
+    pub fn max_background(&self) -> u16 {
+        self.max_background
+    }
+
+    /// Returns the timestamp granularity
+    #[cfg(feature = "abi-7-23")]
+    pub fn time_granularity(&self) -> Duration {
+        self.time_gran
+    }
+
+    /// Returns the threshold of background requests at which the kernel considers the
+    /// request queue congested, 3/4 of the max background requests unless set
     #[cfg(feature = "abi-7-13")]
-    fn congestion_threshold(&self) -> u16 {
+    pub fn congestion_threshold(&self) -> u16 {
         match self.congestion_threshold {
             // Default to a threshold of 3/4 of the max background threads
             None => (self.max_background as u32 * 3 / 4) as u16,
@@ -269,10 +823,58 @@ impl KernelConfig {
         }
     }
 
+    /// Returns the max number of pages of a request, which fits the max write and
+    /// readahead sizes
     #[cfg(feature = "abi-7-28")]
-    fn max_pages(&self) -> u16 {
+    pub fn max_pages(&self) -> u16 {
         ((max(self.max_write, self.max_readahead) - 1) / page_size::get() as u32) as u16 + 1
     }
+
+    /// Capabilities that make the kernel send the given operations
+    fn capabilities_of(ops: Ops) -> u32 {
+        let mut capabilities = 0;
+        if ops.contains(Ops::GETLK | Ops::SETLK) {
+            capabilities |= FUSE_POSIX_LOCKS;
+        }
+        #[cfg(feature = "abi-7-10")]
+        if ops.contains(Ops::LOOKUP | Ops::LOOKUP_INODE) {
+            capabilities |= FUSE_EXPORT_SUPPORT;
+        }
+        #[cfg(feature = "abi-7-21")]
+        if ops.contains(Ops::READDIRPLUS) {
+            capabilities |= FUSE_DO_READDIRPLUS;
+        }
+        capabilities
+    }
+
+    /// Request the capabilities of the operations a filesystem declared with
+    /// `Filesystem::supported_ops()`, as far as the kernel supports them. Called before
+    /// `Filesystem::init()`, which may still change them.
+    fn request_ops(&mut self, ops: Ops) {
+        if ops != Ops::ALL {
+            self.requested |= Self::capabilities_of(ops) & self.capabilities;
+        }
+    }
+
+    /// Stop requesting capabilities which make the kernel send operations the filesystem
+    /// doesn't support. Called after `Filesystem::init()`.
+    fn drop_unsupported(&mut self, ops: Ops) {
+        if !ops.contains(Ops::GETLK | Ops::SETLK) {
+            self.requested &= !FUSE_POSIX_LOCKS;
+            #[cfg(feature = "abi-7-17")]
+            {
+                self.requested &= !FUSE_FLOCK_LOCKS;
+            }
+        }
+        #[cfg(feature = "abi-7-10")]
+        if !ops.contains(Ops::LOOKUP | Ops::LOOKUP_INODE) {
+            self.requested &= !FUSE_EXPORT_SUPPORT;
+        }
+        #[cfg(feature = "abi-7-21")]
+        if !ops.contains(Ops::READDIRPLUS) {
+            self.requested &= !(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO);
+        }
+    }
 }
 
 /// Filesystem trait.
@@ -283,29 +885,80 @@ impl KernelConfig {
 /// nothing.
 #[allow(clippy::too_many_arguments)]
 pub trait Filesystem {
+    /// Returns the optional operations this filesystem implements. Called once before
+    /// `init()`.
+    ///
+    /// Requests for operations not in the returned set are replied with ENOSYS without
+    /// calling the filesystem, and the capabilities that make the kernel send them
+    /// (FUSE_POSIX_LOCKS and FUSE_FLOCK_LOCKS for `getlk()` and `setlk()`,
+    /// FUSE_DO_READDIRPLUS and FUSE_READDIRPLUS_AUTO for `readdirplus()`, FUSE_EXPORT_SUPPORT
+    /// for `lookup()` and `lookup_inode()`) are not requested.
+    /// The capabilities of the operations in a declared set are requested automatically,
+    /// `init()` can still change them. The default, `Ops::ALL`, dispatches every request and
+    /// leaves capabilities to `init()`.
+    ///
+    /// Operations the kernel sends without a capability, like `lseek()`, `copy_file_range()`,
+    /// `fallocate()`, `poll()` or `syncfs()`, stop being sent once they were replied with
+    /// ENOSYS. If the kernel supports zero-message opens (FUSE_NO_OPEN_SUPPORT and
+    /// FUSE_NO_OPENDIR_SUPPORT), leaving out `open()` or `opendir()` makes use of them.
+    fn supported_ops(&self) -> Ops {
+        Ops::ALL
+    }
+
     /// Initialize filesystem.
     /// Called before any other filesystem method.
-    /// The kernel module connection can be configured using the KernelConfig object
+    /// The kernel module connection can be configured using the KernelConfig object.
+    /// Returning an error refuses the mount: the error is sent to the kernel, the filesystem
+    /// is unmounted and the error is returned from `Session::run()` (and thereby `mount2()`)
+    /// or as `SessionExit::Error` from `BackgroundSession::join()`. `destroy()` is not called
+    /// in this case.
+    /// Optional capabilities are only requested if they are added to the config here, so
+    /// the kernel doesn't send operations that depend on them (like `readdirplus()`) unless
+    /// the filesystem enables them, see `KernelConfig::add_capabilities()`.
     fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
         Ok(())
     }
 
     /// Clean up filesystem.
-    /// Called on filesystem exit.
+    /// Called on filesystem exit, i.e. when the kernel sends FUSE_DESTROY or otherwise when the
+    /// session loop ends because the filesystem was unmounted. It is called exactly once if
+    /// `init()` succeeded, no matter how the filesystem was unmounted, and never if it failed.
     fn destroy(&mut self) {}
 
     /// Look up a directory entry by name and get its attributes.
+    /// If the filesystem supports being exported over NFS (FUSE_EXPORT_SUPPORT, which can be
+    /// requested using `KernelConfig::add_capabilities()` during init), the kernel also looks up
+    /// ".." to find the parent of a directory, and resolves NFS file handles to inodes that
+    /// are not cached anymore with `lookup_inode()`. In that case the (ino, generation) pairs
+    /// of all entries must be unique over the filesystem's lifetime. Names which don't exist
+    /// can be replied with `ReplyEntry::negative()`, so the kernel caches the miss instead of
+    /// sending a lookup for every access.
     fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
 
+    /// Look up an inode by its number and get its attributes.
+    /// With FUSE_EXPORT_SUPPORT, the kernel calls this to resolve an NFS file handle whose
+    /// inode is no longer cached, e.g. after it was forgotten. The reply counts as a lookup
+    /// like one of `lookup()`. If the inode number was reused since, reply with the current
+    /// generation so the kernel can tell the handle is stale, or with ESTALE if the inode
+    /// doesn't exist anymore. The kernel sends this as a lookup of "." in ino, and the
+    /// default implementation passes it on to `lookup()` as such.
+    fn lookup_inode(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEntry) {
+        self.lookup(req, ino, OsStr::new("."), reply);
+    }
+
     /// Forget about an inode.
     /// The nlookup parameter indicates the number of lookups previously performed on
     /// this inode. If the filesystem implements inode lifetimes, it is recommended that
     /// inodes acquire a single reference on each lookup, and lose nlookup references on
     /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
-    /// inodes will receive a forget message.
+    /// inodes will receive a forget message. Requests for an inode which is forgotten
+    /// concurrently may still arrive, replying ENOENT (or ESTALE) to them is always valid.
+    /// To be robust against miscounted lookups, don't let the count underflow if nlookup
+    /// is larger than it (`InodeTable::forget()` clamps it to zero). To find miscounted
+    /// lookups, `Session::set_track_lookups()` makes the session count them as well.
     fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
 
     /// Like forget, but take multiple forget requests at once for performance. The default
@@ -318,7 +971,10 @@ pub trait Filesystem {
     }
 
     /// Get file attributes.
-    fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+    /// fh is set if the attributes are requested for an open file (e.g. by fstat), which
+    /// allows answering with the attributes of an open but already unlinked file. Only
+    /// supported with ABI >= 7.9. `FileAttr::crtime` is only passed to the kernel on macOS.
+    fn getattr(&mut self, _req: &Request<'_>, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
@@ -345,12 +1001,21 @@ pub trait Filesystem {
     }
 
     /// Read symbolic link.
+    /// Reply with the target using `ReplyData::symlink()`, or its raw bytes using `data()`.
+    /// The target doesn't need to be valid UTF-8 and must not be NUL terminated. The kernel
+    /// accepts targets shorter than a page (PATH_MAX). With `KernelConfig::set_cache_symlinks()`
+    /// the kernel caches the target until it is invalidated with `Notifier::inval_inode()`.
     fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
     }
 
     /// Create file node.
-    /// Create a regular file, character device, block device, fifo or socket node.
+    /// Create a regular file, character device, block device, fifo or socket node. The file
+    /// type can be determined with `FileType::from_mode(mode)`.
+    /// umask: the umask of the creating process (only supported with ABI >= 7.12, 0 otherwise).
+    /// Unless FUSE_DONT_MASK is requested in `init`, the kernel has already applied it to
+    /// `mode`. Filesystems that request FUSE_DONT_MASK (e.g. to apply default ACLs instead)
+    /// receive the unmasked mode and must compute the final mode as `mode & !umask` themselves.
     fn mknod(
         &mut self,
         _req: &Request<'_>,
@@ -365,6 +1030,7 @@ pub trait Filesystem {
     }
 
     /// Create a directory.
+    /// umask: see `mknod()`
     fn mkdir(
         &mut self,
         _req: &Request<'_>,
@@ -388,6 +1054,8 @@ pub trait Filesystem {
     }
 
     /// Create a symbolic link.
+    /// The target is passed as given to symlink(2), byte for byte, and is not necessarily
+    /// valid UTF-8. Use `OsStrExt::as_bytes()` to store it without conversion.
     fn symlink(
         &mut self,
         _req: &Request<'_>,
@@ -400,6 +1068,9 @@ pub trait Filesystem {
     }
 
     /// Rename a file.
+    /// Reply EXDEV if the entry can't be moved to the new parent, e.g. because the
+    /// directories are stored on different backing devices. The error is passed on to the
+    /// application unchanged, tools like mv(1) then fall back to copying and unlinking.
     fn rename(
         &mut self,
         _req: &Request<'_>,
@@ -427,14 +1098,25 @@ pub trait Filesystem {
 
     /// Open a file.
     /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
-    /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
+    /// available in flags, and can be inspected with `OpenFlags`. The kernel has already
+    /// checked O_CREAT and O_EXCL. O_TRUNC is applied by the kernel with a `setattr()` of
+    /// size 0 before opening, unless `KernelConfig::set_atomic_o_trunc()` was enabled in
+    /// `init()`, then it is passed here and the filesystem must truncate the file itself.
+    /// O_APPEND must be honored by the filesystem, writes of such a handle should go to the
+    /// end of the file regardless of their offset (unless the writeback cache is enabled, in
+    /// which case the kernel handles it). Filesystem may store an arbitrary file handle (pointer, index,
     /// etc) in fh, and use this in other all other file operations (read, write, flush,
     /// release, fsync). Filesystem may also implement stateless file I/O and not store
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
+    ///
+    /// If this method isn't implemented and the kernel supports zero-message opens
+    /// (FUSE_NO_OPEN_SUPPORT, ABI >= 7.23), the default replies ENOSYS, which makes the
+    /// kernel treat this and all future opens as successful without sending open or release
+    /// requests. All file operations then get an fh of 0. Otherwise it opens with an fh of 0.
     fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+        reply.default_open();
     }
 
     /// Read data.
@@ -445,8 +1127,18 @@ pub trait Filesystem {
     /// operation. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value.
     ///
+    /// The kernel splits large reads into several requests, whose size never exceeds what
+    /// fits in max_pages pages. That is 128KiB by default, and with ABI >= 7.28 the pages
+    /// needed for the larger of max_write and max_readahead (see `KernelConfig`), up to the
+    /// kernel's own limit. So it's never necessary to buffer more than that, even for reads
+    /// of huge files. A reply is always sent to the kernel in a single write, so the data
+    /// can't be streamed in chunks.
+    ///
     /// flags: these are the file flags, such as O_SYNC. Only supported with ABI >= 7.9
-    /// lock_owner: only supported with ABI >= 7.9
+    /// lock_owner: only supported with ABI >= 7.9, and only set if the kernel sets
+    /// FUSE_READ_LOCKOWNER. It does so for reads which bypass the page cache, e.g. with
+    /// direct_io, where the process doing the read is known. A filesystem enforcing
+    /// mandatory locks can use it to check that the reader holds a lock (see `setlk()`).
     fn read(
         &mut self,
         _req: &Request<'_>,
@@ -469,10 +1161,20 @@ pub trait Filesystem {
     /// will be undefined if the open method didn't set any value.
     ///
     /// write_flags: will contain FUSE_WRITE_CACHE, if this write is from the page cache. If set,
-    /// the pid, uid, gid, and fh may not match the value that would have been sent if write cachin
-    /// is disabled
-    /// flags: these are the file flags, such as O_SYNC. Only supported with ABI >= 7.9
-    /// lock_owner: only supported with ABI >= 7.9
+    /// the pid, uid, gid, and fh may not match the value that would have been sent if write caching
+    /// is disabled. Writes without FUSE_WRITE_CACHE come directly from a write system call, e.g.
+    /// on files opened with O_DIRECT or direct_io. With ABI >= 7.31 it contains
+    /// FUSE_WRITE_KILL_PRIV if the setuid and setgid bits of the file must be cleared, see
+    /// `KernelConfig::set_handle_killpriv()`.
+    /// flags: these are the file flags, such as O_SYNC or O_DIRECT. Only supported with ABI >= 7.9
+    /// lock_owner: only supported with ABI >= 7.9, and only set if the kernel sets
+    /// FUSE_WRITE_LOCKOWNER. Like for `read()`, this is the case for writes which bypass the
+    /// page cache. Writes from the page cache can't be attributed to a lock owner.
+    ///
+    /// The data borrows the buffer the request was received into, it is not copied, so it can
+    /// be passed to pwrite() of a backing file as it is. The session receives requests with
+    /// read(), so FUSE_SPLICE_WRITE is never requested and the data is always contiguous. Only
+    /// writes combined with `Session::set_write_combining()` are copied once to be combined.
     fn write(
         &mut self,
         _req: &Request<'_>,
@@ -516,7 +1218,10 @@ pub trait Filesystem {
     /// error, but error values are not returned to close() or munmap() which triggered
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
-    /// open.
+    /// open. POSIX locks (setlk) are released in flush(), which is called for every close.
+    /// With ABI >= 7.17 lock_owner is only set if the filesystem requested FUSE_FLOCK_LOCKS
+    /// and the file has flock(2) locks, which must then be released for that owner. Before
+    /// 7.17 it is always set.
     fn release(
         &mut self,
         _req: &Request<'_>,
@@ -551,15 +1256,25 @@ pub trait Filesystem {
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
+    ///
+    /// Like `open()`, the default replies ENOSYS if the kernel supports zero-message
+    /// opendirs (FUSE_NO_OPENDIR_SUPPORT, ABI >= 7.29) so that it stops sending opendir and
+    /// releasedir requests, and opens with an fh of 0 otherwise.
+    ///
+    /// The fh is passed to readdir, readdirplus, fsyncdir and releasedir of the same open
+    /// directory, and to no request after releasedir. In debug builds the session warns
+    /// about handles used without being open or never released, see
+    /// `Session::set_track_dir_handles()`.
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+        reply.default_open();
     }
 
     /// Read directory.
     /// Send a buffer filled using buffer.fill(), with size not exceeding the
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
-    /// didn't set any value.
+    /// didn't set any value. See `ReplyDirectory::add()` for resuming at the given offset
+    /// when the buffer is full.
     fn readdir(
         &mut self,
         _req: &Request<'_>,
@@ -576,6 +1291,8 @@ pub trait Filesystem {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
+    /// Unlike readdir, every entry (except "." and "..") counts as a lookup of its inode,
+    /// which the kernel forgets later, see `ReplyDirectoryPlus::add()`.
     fn readdirplus(
         &mut self,
         _req: &Request<'_>,
@@ -603,9 +1320,10 @@ pub trait Filesystem {
     }
 
     /// Synchronize directory contents.
-    /// If the datasync parameter is set, then only the directory contents should
-    /// be flushed, not the meta data. fh will contain the value set by the opendir
-    /// method, or will be undefined if the opendir method didn't set any value.
+    /// Called on fsync(2) and fdatasync(2) of a directory (FUSE_FSYNCDIR), while `fsync()`
+    /// is only called for files. If the datasync parameter is set, then only the directory
+    /// contents should be flushed, not the meta data. fh will contain the value set by the
+    /// opendir method, or will be undefined if the opendir method didn't set any value.
     fn fsyncdir(
         &mut self,
         _req: &Request<'_>,
@@ -618,11 +1336,16 @@ pub trait Filesystem {
     }
 
     /// Get file system statistics.
+    /// See `ReplyStatfs::statfs()` for the fields, the default replies a filesystem without
+    /// any blocks or inodes and a maximum name length of 255.
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
         reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
     }
 
     /// Set an extended attribute.
+    /// flags are those of setxattr(2): with `libc::XATTR_CREATE` reply EEXIST if the attribute
+    /// already exists, with `libc::XATTR_REPLACE` reply ENODATA (ENOATTR on macOS) if it
+    /// doesn't. Without flags, the attribute is created or replaced.
     fn setxattr(
         &mut self,
         _req: &Request<'_>,
@@ -665,9 +1388,19 @@ pub trait Filesystem {
     }
 
     /// Check file access permissions.
-    /// This will be called for the access() system call. If the 'default_permissions'
-    /// mount option is given, this method is not called. This method is not called
-    /// under Linux kernel versions 2.4.x
+    /// This will be called for the access() system call, and by the kernel to check
+    /// permission to chdir into a directory. The session passes every access request on,
+    /// regardless of the mount options. But if the 'default_permissions' mount option is
+    /// given, the kernel checks the mode bits itself and never sends access requests, so
+    /// this method can't grant access the mode bits deny. To enforce POSIX ACLs on top of
+    /// the mode bits, use `KernelConfig::set_posix_acl()` (ABI >= 7.26) and store the ACL
+    /// xattrs instead. Without 'default_permissions', this method is called for access()
+    /// only; other operations must check permissions themselves, see `PermissionChecking`.
+    /// The default replies ENOSYS like libfuse does without an access operation: the kernel
+    /// then grants this and every later access check of the mount without asking again, so
+    /// access(2) and `test -x` work (executing a regular file still requires an execute bit).
+    /// Replying success instead would grant the same, but cost a request for every check.
+    /// This method is not called under Linux kernel versions 2.4.x
     fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -682,6 +1415,12 @@ pub trait Filesystem {
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
     /// and open() methods will be called instead.
+    /// The kernel usually only calls create() if the lookup of the name failed, but the file
+    /// may have been created in the meantime, e.g. by another client of a network filesystem.
+    /// If flags contain O_EXCL and the name already exists, reply with EEXIST. Without
+    /// O_EXCL, open the existing file and reply with its entry as if it had been created.
+    /// O_CREAT is always set in flags.
+    /// umask: see `mknod()`
     fn create(
         &mut self,
         _req: &Request<'_>,
@@ -718,6 +1457,13 @@ pub trait Filesystem {
     /// used to fill in this field in getlk(). Note: if the locking methods are not
     /// implemented, the kernel will still allow file locking to work locally.
     /// Hence these are only interesting for network filesystems and similar.
+    ///
+    /// If sleep is set (F_SETLKW), the filesystem should wait for a conflicting lock to be
+    /// released, e.g. by replying from another thread once the lock is granted. The waiting
+    /// process can only be interrupted by a signal if the filesystem gets the request's
+    /// `Request::interrupt_token()` before returning and replies EINTR once the request is
+    /// interrupted. Use `InterruptToken::on_interrupt()` to wake up the waiting thread.
+    /// Without this, an interrupted fcntl(F_SETLKW) hangs until the lock is granted.
     fn setlk(
         &mut self,
         _req: &Request<'_>,
@@ -749,6 +1495,12 @@ pub trait Filesystem {
     }
 
     /// control device
+    /// Common commands like FS_IOC_GETFLAGS and FS_IOC_SETFLAGS (chattr) can be decoded with
+    /// `IoctlCommand::parse()` on Linux. flags is a combination of FUSE_IOCTL_COMPAT and
+    /// FUSE_IOCTL_32BIT (ABI >= 7.16) for ioctls of 32-bit processes, and FUSE_IOCTL_DIR
+    /// (ABI >= 7.18) for ioctls on a directory, in which case fh is the handle replied to
+    /// opendir instead of open. The kernel only passes directory ioctls on with ABI 7.18 or
+    /// later, and fails them with ENOTTY otherwise.
     fn ioctl(
         &mut self,
         _req: &Request<'_>,
@@ -763,6 +1515,30 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Poll a file for IO readiness events, for poll(2), select(2) and epoll(7).
+    /// Reply with the events in `events` which are ready now using `ReplyPoll::poll()`
+    /// (events is 0 before ABI 7.21, then all ready events should be replied). If flags
+    /// contain FUSE_POLL_SCHEDULE_NOTIFY, the caller waits for events: keep the kernel
+    /// handle kh, e.g. in a map by fh, and once the readiness of the file changed (data
+    /// arrived, buffer space got free), call `Notifier::poll(kh)` from any thread. The kernel
+    /// then wakes up the waiters, which poll the file again. The handle belongs to the open
+    /// file and stays the same for every poll of it, drop it when the file is released.
+    /// If ENOSYS is returned, the kernel doesn't send polls again and treats all files as
+    /// always readable and writable.
+    #[cfg(feature = "abi-7-11")]
+    fn poll(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _kh: u64,
+        _events: u32,
+        _flags: u32,
+        reply: ReplyPoll,
+    ) {
+        reply.error(ENOSYS);
+    }
+
     /// Preallocate or deallocate space to a file
     fn fallocate(
         &mut self,
@@ -778,6 +1554,11 @@ pub trait Filesystem {
     }
 
     /// Reposition read/write file offset
+    /// The kernel handles SEEK_SET, SEEK_CUR and SEEK_END itself and only sends this for
+    /// SEEK_DATA and SEEK_HOLE. No capability needs to be requested. If ENOSYS is returned,
+    /// the kernel doesn't send it again and treats the whole file as data.
+    /// This is not called for files which were opened with FOPEN_NONSEEKABLE or
+    /// FOPEN_STREAM, since the kernel rejects seeking on them with ESPIPE.
     fn lseek(
         &mut self,
         _req: &Request<'_>,
@@ -791,6 +1572,9 @@ pub trait Filesystem {
     }
 
     /// Copy the specified range from the source inode to the destination inode
+    /// This is sent for copy_file_range(2) between two files of this filesystem. No
+    /// capability needs to be requested. If ENOSYS is returned, the kernel doesn't send it
+    /// again and falls back to copying the data with read and write requests.
     fn copy_file_range(
         &mut self,
         _req: &Request<'_>,
@@ -807,6 +1591,74 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Map a range of a file into the DAX window of a virtiofs device.
+    /// flags is a combination of FUSE_SETUPMAPPING_FLAG_WRITE and
+    /// FUSE_SETUPMAPPING_FLAG_READ, moffset is the offset into the DAX window.
+    #[cfg(feature = "abi-7-31")]
+    fn setup_mapping(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _foffset: u64,
+        _len: u64,
+        _flags: u64,
+        _moffset: u64,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove mappings from the DAX window of a virtiofs device.
+    /// Each mapping gives the offset into the DAX window (moffset) and the length of a range
+    /// set up with `setup_mapping()`.
+    #[cfg(feature = "abi-7-31")]
+    fn remove_mapping(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _mappings: &[fuse_removemapping_one],
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Synchronize the whole filesystem.
+    /// Called on syncfs(2) by kernels supporting protocol 7.34 or newer, to flush all
+    /// dirty data of the filesystem, e.g. data buffered globally instead of per file, which
+    /// isn't covered by `fsync()`. ino is the inode syncfs(2) was called on, usually the
+    /// root.
+    ///
+    /// There is no capability to negotiate: the kernel decides itself whether to send syncfs,
+    /// and currently only does so for virtio-fs, not for filesystems mounted through
+    /// /dev/fuse, since an unprivileged filesystem could block syncfs(2) indefinitely. The
+    /// default implementation replies ENOSYS, after which the kernel stops sending it and
+    /// syncfs(2) succeeds. With a declared `supported_ops()` set, it's only called if the set
+    /// contains `Ops::SYNCFS`.
+    fn syncfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create an unnamed temporary file in the parent directory and open it.
+    /// Called on open(2) with O_TMPFILE, e.g. to write a file and then give it a name with
+    /// linkat(2), by kernels supporting protocol 7.37 or newer. Reply like `create()`, with
+    /// the entry of the new inode, which starts with a link count of 0 and may be linked to
+    /// a name later with `link()`. Once it is released without being linked, it can be
+    /// removed. If this method replies ENOSYS, the kernel fails O_TMPFILE opens with
+    /// EOPNOTSUPP from then on.
+    /// umask: see `mknod()`
+    fn tmpfile(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        reply.error(ENOSYS);
+    }
+
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
@@ -835,6 +1687,14 @@ pub trait Filesystem {
     fn getxtimes(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyXTimes) {
         reply.error(ENOSYS);
     }
+
+    /// Android only: Get the canonical path of an inode, which is the path of the file backing
+    /// it on another filesystem. The kernel uses it to place inotify watches on files of the
+    /// mount. Reply with the path using `ReplyData::canonical_path()`.
+    #[cfg(target_os = "android")]
+    fn canonical_path(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
+        reply.error(ENOSYS);
+    }
 }
 
 /// Mount the given filesystem to the given mountpoint. This function will
@@ -861,10 +1721,33 @@ pub fn mount2<FS: Filesystem, P: AsRef<Path>>(
     mountpoint: P,
     options: &[MountOption],
 ) -> io::Result<()> {
-    check_option_conflicts(options)?;
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run())
 }
 
+/// Mount the given filesystem to the given mountpoint, retrying with backoff if the mount
+/// fails with a transient error (see `RetryPolicy`). Invalid options or mountpoints fail
+/// without retrying. Returns the session once mounted, which can then be run or spawned.
+pub fn mount_with_retry<FS: Filesystem, P: AsRef<Path>>(
+    filesystem: FS,
+    mountpoint: P,
+    options: &[MountOption],
+    retry_policy: &RetryPolicy,
+) -> io::Result<Session<FS>> {
+    Session::new_with_retry(filesystem, mountpoint.as_ref(), options, retry_policy)
+}
+
+/// Check the prerequisites for mounting on the given mountpoint without mounting: the
+/// options are valid, the mountpoint exists, is a directory or regular file and isn't a
+/// mountpoint yet, the FUSE device can be opened and the mount helper (fusermount) is
+/// present if it is needed. Returns a descriptive error for the first check which fails,
+/// e.g. to validate a configuration at startup.
+///
+/// Passing the checks doesn't guarantee that mounting succeeds, the kernel or the mount
+/// helper may still reject the mount.
+pub fn check_mount<P: AsRef<Path>>(mountpoint: P, options: &[MountOption]) -> io::Result<()> {
+    mnt::Mount::check(mountpoint.as_ref(), options)
+}
+
 /// Mount the given filesystem to the given mountpoint. This function spawns
 /// a background thread to handle filesystem operations while being mounted
 /// and therefore returns immediately. The returned handle should be stored
@@ -887,3 +1770,126 @@ pub fn spawn_mount<'a, FS: Filesystem + Send + 'static + 'a, P: AsRef<Path>>(
     let options = options.ok_or(ErrorKind::InvalidData)?;
     Session::new(filesystem, mountpoint.as_ref(), options.as_ref()).and_then(|se| se.spawn())
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{fuse_attr, AccessMode, FileAttr, FileAttrBuilder, FileType, OpenFlags};
+
+    #[test]
+    fn fuse_attr_conversion() {
+        let attr = FileAttrBuilder::new(0x11, FileType::CharDevice)
+            .size(0x22)
+            .blocks(0x33)
+            .atime(UNIX_EPOCH + Duration::new(1, 999_999_999))
+            .mtime(UNIX_EPOCH - Duration::new(1, 500_000_000))
+            .ctime(UNIX_EPOCH)
+            .perm(0o4755)
+            .nlink(2)
+            .uid(1000)
+            .gid(100)
+            .rdev(0x0103)
+            .blksize(4096)
+            .build();
+        let encoded = fuse_attr::from(&attr);
+        assert_eq!(
+            (encoded.ino, encoded.size, encoded.blocks),
+            (0x11, 0x22, 0x33)
+        );
+        assert_eq!((encoded.atime, encoded.atimensec), (1, 999_999_999));
+        assert_eq!((encoded.mtime, encoded.mtimensec), (-2, 500_000_000));
+        assert_eq!(encoded.mode, FileType::CharDevice.to_mode() | 0o4755);
+        assert_eq!(encoded.rdev, 0x0103);
+        #[cfg(feature = "abi-7-9")]
+        assert_eq!(encoded.blksize, 4096);
+
+        let decoded = FileAttr::from_fuse_attr(&encoded).unwrap();
+        let expected = FileAttr {
+            blksize: if cfg!(feature = "abi-7-9") { 4096 } else { 0 },
+            ..attr
+        };
+        #[cfg(not(target_os = "macos"))]
+        let expected = FileAttr {
+            crtime: UNIX_EPOCH,
+            ..expected
+        };
+        assert_eq!(decoded, expected);
+
+        let unknown = fuse_attr {
+            mode: 0o644,
+            ..encoded
+        };
+        assert_eq!(FileAttr::from_fuse_attr(&unknown), None);
+    }
+
+    #[test]
+    fn file_type_mode() {
+        let types = [
+            FileType::NamedPipe,
+            FileType::CharDevice,
+            FileType::BlockDevice,
+            FileType::Directory,
+            FileType::RegularFile,
+            FileType::Symlink,
+            FileType::Socket,
+        ];
+        for kind in types.iter() {
+            assert_eq!(FileType::from_mode(kind.to_mode() | 0o644), Some(*kind));
+        }
+        assert_eq!(FileType::from_mode(0o755), None);
+    }
+
+    #[test]
+    fn open_flags() {
+        let flags = OpenFlags(libc::O_WRONLY | libc::O_APPEND);
+        assert_eq!(flags.access_mode(), AccessMode::WriteOnly);
+        assert!(flags.is_append());
+        assert!(!flags.is_truncate());
+        assert!(!flags.is_nonblock());
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_TRUNC | libc::O_NONBLOCK);
+        assert_eq!(flags.access_mode(), AccessMode::ReadWrite);
+        assert!(!flags.is_append());
+        assert!(flags.is_truncate());
+        assert!(flags.is_nonblock());
+        assert_eq!(
+            OpenFlags(libc::O_RDONLY).access_mode(),
+            AccessMode::ReadOnly
+        );
+    }
+
+    #[cfg(feature = "abi-7-9")]
+    #[test]
+    fn capability_setters() {
+        use super::consts::*;
+        use super::KernelConfig;
+
+        type Setter = fn(&mut KernelConfig, bool) -> Result<bool, bool>;
+        let mut setters: Vec<(u32, Setter)> =
+            vec![(FUSE_ATOMIC_O_TRUNC, KernelConfig::set_atomic_o_trunc)];
+        #[cfg(feature = "abi-7-26")]
+        setters.push((FUSE_HANDLE_KILLPRIV, KernelConfig::set_handle_killpriv));
+        #[cfg(feature = "abi-7-26")]
+        setters.push((FUSE_POSIX_ACL, KernelConfig::set_posix_acl));
+        #[cfg(feature = "abi-7-28")]
+        setters.push((FUSE_CACHE_SYMLINKS, KernelConfig::set_cache_symlinks));
+        #[cfg(feature = "abi-7-30")]
+        setters.push((
+            FUSE_EXPLICIT_INVAL_DATA,
+            KernelConfig::set_explicit_invalidate_data,
+        ));
+
+        for (flag, set) in setters {
+            let mut config = KernelConfig::new(flag, 4096);
+            assert_eq!(config.requested & flag, 0);
+            assert_eq!(set(&mut config, true), Ok(false));
+            assert_ne!(config.requested & flag, 0);
+            assert_eq!(set(&mut config, true), Ok(true));
+            assert_eq!(set(&mut config, false), Ok(true));
+            assert_eq!(config.requested & flag, 0);
+            let mut config = KernelConfig::new(0, 4096);
+            assert_eq!(set(&mut config, true), Err(false));
+            assert_eq!(set(&mut config, false), Ok(false));
+        }
+    }
+}
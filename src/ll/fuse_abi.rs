@@ -80,7 +80,7 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
 pub const FUSE_ROOT_ID: u64 = 1;
 
 #[repr(C)]
-#[derive(Debug, AsBytes, Clone, Copy)]
+#[derive(Debug, AsBytes, FromBytes, Clone, Copy)]
 pub struct fuse_attr {
     pub ino: u64,
     pub size: u64,
@@ -233,6 +233,8 @@ pub mod consts {
     pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24; // kernel supports zero-message opendir
     #[cfg(feature = "abi-7-30")]
     pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25; // only invalidate cached pages on explicit request
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26; // init_out.map_alignment contains log2(byte alignment) of mapping offsets
 
     #[cfg(target_os = "macos")]
     pub const FUSE_ALLOCATE: u32 = 1 << 27;
@@ -297,6 +299,12 @@ pub mod consts {
     // fsync flags
     pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0; // Sync data only, not metadata
 
+    // Setupmapping flags
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0; // map the range writable
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_READ: u64 = 1 << 1; // map the range readable
+
     // The read buffer is required to be at least 8k, but may be much larger
     pub const FUSE_MIN_READ_BUFFER: usize = 8192;
 }
@@ -363,6 +371,14 @@ pub enum fuse_opcode {
     FUSE_LSEEK = 46,
     #[cfg(feature = "abi-7-28")]
     FUSE_COPY_FILE_RANGE = 47,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_SETUPMAPPING = 48,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_REMOVEMAPPING = 49,
+    // Added in 7.34, it doesn't change any structures and is therefore always decoded
+    FUSE_SYNCFS = 50,
+    // Added in 7.37, it reuses fuse_create_in and is therefore always decoded
+    FUSE_TMPFILE = 51,
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -370,6 +386,9 @@ pub enum fuse_opcode {
     FUSE_GETXTIMES = 62,
     #[cfg(target_os = "macos")]
     FUSE_EXCHANGE = 63,
+    // Android only, the opcode is not part of the upstream kernel
+    #[cfg(target_os = "android")]
+    FUSE_CANONICAL_PATH = 2016,
 
     #[cfg(feature = "abi-7-12")]
     CUSE_INIT = 4096,
@@ -434,6 +453,12 @@ impl TryFrom<u32> for fuse_opcode {
             46 => Ok(fuse_opcode::FUSE_LSEEK),
             #[cfg(feature = "abi-7-28")]
             47 => Ok(fuse_opcode::FUSE_COPY_FILE_RANGE),
+            #[cfg(feature = "abi-7-31")]
+            48 => Ok(fuse_opcode::FUSE_SETUPMAPPING),
+            #[cfg(feature = "abi-7-31")]
+            49 => Ok(fuse_opcode::FUSE_REMOVEMAPPING),
+            50 => Ok(fuse_opcode::FUSE_SYNCFS),
+            51 => Ok(fuse_opcode::FUSE_TMPFILE),
 
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
@@ -441,6 +466,8 @@ impl TryFrom<u32> for fuse_opcode {
             62 => Ok(fuse_opcode::FUSE_GETXTIMES),
             #[cfg(target_os = "macos")]
             63 => Ok(fuse_opcode::FUSE_EXCHANGE),
+            #[cfg(target_os = "android")]
+            2016 => Ok(fuse_opcode::FUSE_CANONICAL_PATH),
 
             #[cfg(feature = "abi-7-12")]
             4096 => Ok(fuse_opcode::CUSE_INIT),
@@ -499,7 +526,7 @@ impl TryFrom<u32> for fuse_notify_code {
 }
 
 #[repr(C)]
-#[derive(Debug, AsBytes)]
+#[derive(Debug, AsBytes, FromBytes)]
 pub struct fuse_entry_out {
     pub nodeid: u64,
     pub generation: u64,
@@ -542,7 +569,7 @@ pub struct fuse_getattr_in {
 }
 
 #[repr(C)]
-#[derive(Debug, AsBytes)]
+#[derive(Debug, AsBytes, FromBytes)]
 pub struct fuse_attr_out {
     pub attr_valid: u64,
     pub attr_valid_nsec: u32,
@@ -776,7 +803,7 @@ pub struct fuse_write_in {
 }
 
 #[repr(C)]
-#[derive(Debug, AsBytes)]
+#[derive(Debug, AsBytes, FromBytes)]
 pub struct fuse_write_out {
     pub size: u32,
     pub padding: u32,
@@ -978,7 +1005,7 @@ pub struct fuse_poll_in {
 
 #[cfg(feature = "abi-7-11")]
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, AsBytes)]
 pub struct fuse_poll_out {
     pub revents: u32,
     pub padding: u32,
@@ -986,7 +1013,7 @@ pub struct fuse_poll_out {
 
 #[cfg(feature = "abi-7-11")]
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, AsBytes)]
 pub struct fuse_notify_poll_wakeup_out {
     pub kh: u64,
 }
@@ -1019,7 +1046,7 @@ pub struct fuse_in_header {
 }
 
 #[repr(C)]
-#[derive(Debug, AsBytes)]
+#[derive(Debug, AsBytes, FromBytes)]
 pub struct fuse_out_header {
     pub len: u32,
     pub error: i32,
@@ -1046,7 +1073,7 @@ pub struct fuse_direntplus {
 
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, AsBytes)]
 pub struct fuse_notify_inval_inode_out {
     pub ino: u64,
     pub off: i64,
@@ -1055,7 +1082,7 @@ pub struct fuse_notify_inval_inode_out {
 
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, AsBytes)]
 pub struct fuse_notify_inval_entry_out {
     pub parent: u64,
     pub namelen: u32,
@@ -1135,3 +1162,30 @@ pub struct fuse_copy_file_range_in {
     pub len: u64,
     pub flags: u64,
 }
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+pub struct fuse_setupmapping_in {
+    pub fh: u64,
+    pub foffset: u64,
+    pub len: u64,
+    pub flags: u64,
+    pub moffset: u64,
+}
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+pub struct fuse_removemapping_in {
+    pub count: u32,
+}
+
+/// A range of the DAX window to unmap, see `Filesystem::remove_mapping()`
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_removemapping_one {
+    pub moffset: u64,
+    pub len: u64,
+}
@@ -12,11 +12,17 @@ use crate::FileType;
 use super::{fuse_abi as abi, Errno, FileHandle, Generation, INodeNo};
 use super::{Lock, RequestId};
 use smallvec::{smallvec, SmallVec};
-use zerocopy::AsBytes;
+use zerocopy::{AsBytes, FromBytes};
 
-const INLINE_DATA_THRESHOLD: usize = size_of::<u64>() * 4;
+/// Replies up to this size are stored inline instead of on the heap. This covers the replies
+/// to all metadata requests (the largest being `fuse_create_out`), so that e.g. a walk over a
+/// directory tree doesn't allocate for each lookup or getattr. It needs to be one of the
+/// array sizes supported by `SmallVec`.
+const INLINE_DATA_THRESHOLD: usize = 256;
 pub(crate) type ResponseBuf = SmallVec<[u8; INLINE_DATA_THRESHOLD]>;
 
+// Small data is stored inline on purpose, to avoid allocating for most replies
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Response {
     Error(i32),
@@ -89,6 +95,20 @@ impl Response {
         Self::from_struct(d.as_bytes())
     }
 
+    /// An entry with nodeid 0, which the kernel caches as a negative entry
+    pub(crate) fn new_negative_entry(entry_ttl: Duration) -> Self {
+        let d = abi::fuse_entry_out {
+            nodeid: 0,
+            generation: 0,
+            entry_valid: entry_ttl.as_secs(),
+            attr_valid: 0,
+            entry_valid_nsec: entry_ttl.subsec_nanos(),
+            attr_valid_nsec: 0,
+            attr: abi::fuse_attr::new_zeroed(),
+        };
+        Self::from_struct(d.as_bytes())
+    }
+
     pub(crate) fn new_attr(ttl: &Duration, attr: &Attr) -> Self {
         let r = abi::fuse_attr_out {
             attr_valid: ttl.as_secs(),
@@ -139,6 +159,15 @@ impl Response {
         Self::from_struct(&r)
     }
 
+    #[cfg(feature = "abi-7-11")]
+    pub(crate) fn new_poll(revents: u32) -> Self {
+        let r = abi::fuse_poll_out {
+            revents,
+            padding: 0,
+        };
+        Self::from_struct(&r)
+    }
+
     pub(crate) fn new_write(written: u32) -> Self {
         let r = abi::fuse_write_out {
             size: written,
@@ -243,27 +272,29 @@ pub(crate) fn time_from_system_time(system_time: &SystemTime) -> (i64, u32) {
     // Convert to signed 64-bit time with epoch at 0
     match system_time.duration_since(UNIX_EPOCH) {
         Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
-        Err(before_epoch_error) => (
-            -(before_epoch_error.duration().as_secs() as i64),
-            before_epoch_error.duration().subsec_nanos(),
-        ),
+        // Like a timespec, nanoseconds count forward from the (negative) seconds, so 1.5
+        // seconds before the epoch are -2 seconds plus 500ms
+        Err(before_epoch_error) => {
+            let duration = before_epoch_error.duration();
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nanos => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+/// Convert a timespec to a SystemTime. Nanoseconds always count forward, also from
+/// negative seconds.
+pub(crate) fn system_time_from_time(secs: i64, nsecs: u32) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs)
+    } else {
+        UNIX_EPOCH - Duration::new(secs.unsigned_abs(), 0) + Duration::new(0, nsecs)
     }
 }
-// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
-// But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
-#[allow(trivial_numeric_casts)]
 /// Returns the mode for a given file kind and permission
 pub(crate) fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
-    (match kind {
-        FileType::NamedPipe => libc::S_IFIFO,
-        FileType::CharDevice => libc::S_IFCHR,
-        FileType::BlockDevice => libc::S_IFBLK,
-        FileType::Directory => libc::S_IFDIR,
-        FileType::RegularFile => libc::S_IFREG,
-        FileType::Symlink => libc::S_IFLNK,
-        FileType::Socket => libc::S_IFSOCK,
-    }) as u32
-        | perm as u32
+    kind.to_mode() | perm as u32
 }
 /// Returns a fuse_attr from FileAttr
 pub(crate) fn fuse_attr_from_attr(attr: &crate::FileAttr) -> abi::fuse_attr {
@@ -301,6 +332,36 @@ pub(crate) fn fuse_attr_from_attr(attr: &crate::FileAttr) -> abi::fuse_attr {
     }
 }
 
+/// Returns a FileAttr from fuse_attr, or None if the mode has no known file type
+pub(crate) fn attr_from_fuse_attr(attr: &abi::fuse_attr) -> Option<crate::FileAttr> {
+    Some(crate::FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: system_time_from_time(attr.atime, attr.atimensec),
+        mtime: system_time_from_time(attr.mtime, attr.mtimensec),
+        ctime: system_time_from_time(attr.ctime, attr.ctimensec),
+        #[cfg(target_os = "macos")]
+        crtime: system_time_from_time(attr.crtime as i64, attr.crtimensec),
+        #[cfg(not(target_os = "macos"))]
+        crtime: UNIX_EPOCH,
+        kind: FileType::from_mode(attr.mode)?,
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        #[cfg(feature = "abi-7-9")]
+        blksize: attr.blksize,
+        #[cfg(not(feature = "abi-7-9"))]
+        blksize: 0,
+        #[cfg(target_os = "macos")]
+        flags: attr.flags,
+        #[cfg(not(target_os = "macos"))]
+        flags: 0,
+    })
+}
+
 // TODO: Add methods for creating this without making a `FileAttr` first.
 #[derive(Debug, Clone, Copy)]
 pub struct Attr {
@@ -321,6 +382,11 @@ impl From<crate::FileAttr> for Attr {
     }
 }
 
+/// Size of a directory entry of the given length, padded to 64bit alignment
+fn entry_size(entlen: usize) -> usize {
+    (entlen + size_of::<u64>() - 1) & !(size_of::<u64>() - 1)
+}
+
 #[derive(Debug)]
 struct EntListBuf {
     max_size: usize,
@@ -340,7 +406,7 @@ impl EntListBuf {
     #[must_use]
     fn push(&mut self, ent: [&[u8]; 2]) -> bool {
         let entlen = ent[0].len() + ent[1].len();
-        let entsize = (entlen + size_of::<u64>() - 1) & !(size_of::<u64>() - 1); // 64bit align
+        let entsize = entry_size(entlen);
         if self.buf.len() + entsize > self.max_size {
             return true;
         }
@@ -456,6 +522,11 @@ impl DirEntPlusList {
     pub(crate) fn new(max_size: usize) -> Self {
         Self(EntListBuf::new(max_size))
     }
+    /// Returns true if an entry with a name of the given length fits into the buffer
+    pub(crate) fn fits(&self, namelen: usize) -> bool {
+        let entsize = entry_size(size_of::<abi::fuse_direntplus>() + namelen);
+        self.0.buf.len() + entsize <= self.0.max_size
+    }
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
     /// value to request the next entries in further readdir calls
@@ -489,6 +560,23 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn system_time_conversion() {
+        let time = |secs: u64, nanos: u32| UNIX_EPOCH + Duration::new(secs, nanos);
+        assert_eq!(
+            time_from_system_time(&time(5, 123_456_789)),
+            (5, 123_456_789)
+        );
+        assert_eq!(time_from_system_time(&UNIX_EPOCH), (0, 0));
+        let before = |secs: u64, nanos: u32| UNIX_EPOCH - Duration::new(secs, nanos);
+        assert_eq!(time_from_system_time(&before(2, 0)), (-2, 0));
+        assert_eq!(
+            time_from_system_time(&before(1, 500_000_000)),
+            (-2, 500_000_000)
+        );
+        assert_eq!(time_from_system_time(&before(0, 1)), (-1, 999_999_999));
+    }
+
     #[test]
     fn reply_empty() {
         let r = Response::new_empty();
@@ -587,6 +675,45 @@ mod test {
         );
     }
 
+    #[test]
+    fn crtime_only_sent_on_macos() {
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = crate::FileAttrBuilder::new(0x11, FileType::RegularFile).build();
+        let with_crtime = crate::FileAttr {
+            crtime: time,
+            ..attr
+        };
+        let (a, b) = (
+            fuse_attr_from_attr(&attr),
+            fuse_attr_from_attr(&with_crtime),
+        );
+        #[cfg(target_os = "macos")]
+        {
+            assert_eq!((b.crtime, b.crtimensec), (0x1234, 0x5678));
+            assert_ne!(a.as_bytes(), b.as_bytes());
+        }
+        // fuse_attr has no birth time on Linux
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(a.as_bytes(), b.as_bytes());
+    }
+
+    #[test]
+    fn metadata_replies_inline() {
+        let attr = crate::FileAttrBuilder::new(0x11, FileType::RegularFile).build();
+        let ttl = Duration::new(1, 0);
+        let responses = [
+            Response::new_entry(INodeNo(0x11), Generation(0), &attr.into(), ttl, ttl),
+            Response::new_attr(&ttl, &attr.into()),
+            Response::new_create(&ttl, &attr.into(), Generation(0), FileHandle(1), 0),
+        ];
+        for r in responses.iter() {
+            match r {
+                Response::Data(v) => assert!(!v.spilled()),
+                Response::Error(_) => panic!("Unexpected error response"),
+            }
+        }
+    }
+
     #[test]
     fn reply_attr() {
         let mut expected = if cfg!(target_os = "macos") {
@@ -21,6 +21,9 @@ pub enum RequestError {
     UnknownOperation(u32),
     /// Not enough data for arguments (short read).
     ShortRead(usize, usize),
+    /// The request is longer than the length in its header, or the length is too small for
+    /// the header (unique id of the request, bytes read, length in the header).
+    LengthMismatch(RequestId, usize, usize),
     /// Insufficient argument data.
     InsufficientData,
 }
@@ -201,6 +204,11 @@ impl fmt::Display for RequestError {
             RequestError::ShortRead(len, total) => {
                 write!(f, "Short read of FUSE request ({} < {})", len, total)
             }
+            RequestError::LengthMismatch(unique, len, total) => write!(
+                f,
+                "Size of FUSE request {} doesn't match its header ({} != {})",
+                unique.0, len, total
+            ),
             RequestError::InsufficientData => write!(f, "Insufficient argument data"),
         }
     }
@@ -265,6 +273,7 @@ macro_rules! impl_request {
 }
 
 mod op {
+    use crate::ll::reply::system_time_from_time;
     use crate::ll::Response;
 
     use super::{
@@ -274,13 +283,10 @@ mod op {
     use super::{
         abi::consts::*, abi::*, FileHandle, INodeNo, Lock, LockOwner, Operation, RequestId,
     };
+    #[cfg(target_os = "macos")]
+    use std::time::Duration;
     use std::{
-        convert::TryInto,
-        ffi::OsStr,
-        fmt::Display,
-        num::NonZeroU32,
-        path::Path,
-        time::{Duration, SystemTime},
+        convert::TryInto, ffi::OsStr, fmt::Display, num::NonZeroU32, path::Path, time::SystemTime,
     };
     use zerocopy::AsBytes;
 
@@ -329,8 +335,21 @@ mod op {
     #[derive(Debug)]
     pub struct GetAttr<'a> {
         header: &'a fuse_in_header,
+        #[cfg(feature = "abi-7-9")]
+        arg: &'a fuse_getattr_in,
     }
     impl_request!(GetAttr<'_>);
+    impl<'a> GetAttr<'a> {
+        /// The file handle, if the attributes are requested for an open file (e.g. by
+        /// fstat). This is only set if FUSE_GETATTR_FH is set in the getattr flags.
+        pub fn file_handle(&self) -> Option<FileHandle> {
+            #[cfg(feature = "abi-7-9")]
+            if self.arg.getattr_flags & FUSE_GETATTR_FH != 0 {
+                return Some(FileHandle(self.arg.fh));
+            }
+            None
+        }
+    }
 
     /// Set file attributes.
     #[derive(Debug)]
@@ -821,7 +840,12 @@ mod op {
         pub fn value(&self) -> &'a [u8] {
             self.value
         }
-        // TODO: Document what are valid flags
+        /// Either 0, `XATTR_CREATE` (fail with EEXIST if the attribute exists) or
+        /// `XATTR_REPLACE` (fail with `Errno::NO_XATTR` if it doesn't).
+        ///
+        /// Since ABI 7.33 the kernel can send a larger `fuse_setxattr_in` with additional
+        /// `setxattr_flags`, but only if FUSE_SETXATTR_EXT was negotiated. We never request
+        /// it, so the struct always has the layout parsed here.
         pub fn flags(&self) -> i32 {
             self.arg.flags
         }
@@ -1318,7 +1342,7 @@ mod op {
         }
     }
 
-    /// Poll.  TODO: currently unsupported by fuser
+    /// Poll a file handle for IO readiness events.
     #[cfg(feature = "abi-7-11")]
     #[derive(Debug)]
     pub struct Poll<'a> {
@@ -1333,6 +1357,21 @@ mod op {
         pub fn file_handle(&self) -> FileHandle {
             FileHandle(self.arg.fh)
         }
+        /// The kernel's handle of the poll waiters, to pass to a poll wakeup notification
+        pub fn kernel_handle(&self) -> u64 {
+            self.arg.kh
+        }
+        /// Poll flags, e.g. FUSE_POLL_SCHEDULE_NOTIFY
+        pub fn flags(&self) -> u32 {
+            self.arg.flags
+        }
+        /// The requested poll events, or 0 if the kernel doesn't pass them (ABI < 7.21)
+        pub fn events(&self) -> u32 {
+            #[cfg(feature = "abi-7-21")]
+            return self.arg.events;
+            #[cfg(not(feature = "abi-7-21"))]
+            return 0;
+        }
     }
 
     /// NotifyReply.  TODO: currently unsupported by fuser
@@ -1526,6 +1565,98 @@ mod op {
         }
     }
 
+    /// Map a range of a file into the DAX window (virtiofs)
+    #[cfg(feature = "abi-7-31")]
+    #[derive(Debug)]
+    pub struct SetupMapping<'a> {
+        header: &'a fuse_in_header,
+        arg: &'a fuse_setupmapping_in,
+    }
+    #[cfg(feature = "abi-7-31")]
+    impl_request!(SetupMapping<'a>);
+    #[cfg(feature = "abi-7-31")]
+    impl<'a> SetupMapping<'a> {
+        /// The value set by the [Open] method. See [FileHandle].
+        pub fn file_handle(&self) -> FileHandle {
+            FileHandle(self.arg.fh)
+        }
+        /// Offset into the file to start the mapping at
+        pub fn foffset(&self) -> u64 {
+            self.arg.foffset
+        }
+        /// Length of the mapping
+        pub fn len(&self) -> u64 {
+            self.arg.len
+        }
+        /// A combination of `FUSE_SETUPMAPPING_FLAG_WRITE` and `FUSE_SETUPMAPPING_FLAG_READ`
+        pub fn flags(&self) -> u64 {
+            self.arg.flags
+        }
+        /// Offset into the DAX window to map the file range to
+        pub fn moffset(&self) -> u64 {
+            self.arg.moffset
+        }
+    }
+
+    /// Remove mappings from the DAX window (virtiofs)
+    #[cfg(feature = "abi-7-31")]
+    #[derive(Debug)]
+    pub struct RemoveMapping<'a> {
+        header: &'a fuse_in_header,
+        arg: &'a fuse_removemapping_in,
+        mappings: &'a [u8],
+    }
+    #[cfg(feature = "abi-7-31")]
+    impl_request!(RemoveMapping<'a>);
+    #[cfg(feature = "abi-7-31")]
+    impl<'a> RemoveMapping<'a> {
+        /// The mappings to remove. These follow the 4 byte `fuse_removemapping_in` and
+        /// are therefore not aligned, so they are copied out of the request.
+        pub fn mappings(&self) -> impl Iterator<Item = fuse_removemapping_one> + 'a {
+            self.mappings
+                .chunks_exact(std::mem::size_of::<fuse_removemapping_one>())
+                .take(self.arg.count as usize)
+                .map(|chunk| {
+                    let (moffset, len) = chunk.split_at(std::mem::size_of::<u64>());
+                    fuse_removemapping_one {
+                        moffset: u64::from_ne_bytes(moffset.try_into().unwrap()),
+                        len: u64::from_ne_bytes(len.try_into().unwrap()),
+                    }
+                })
+        }
+    }
+
+    /// Synchronize the whole filesystem, sent on syncfs(2)
+    #[derive(Debug)]
+    pub struct SyncFs<'a> {
+        header: &'a fuse_in_header,
+    }
+    impl_request!(SyncFs<'a>);
+
+    /// Create an unnamed temporary file in a directory and open it, sent on open(2) with
+    /// O_TMPFILE
+    #[derive(Debug)]
+    pub struct TmpFile<'a> {
+        header: &'a fuse_in_header,
+        arg: &'a fuse_create_in,
+    }
+    impl_request!(TmpFile<'a>);
+    impl<'a> TmpFile<'a> {
+        pub fn mode(&self) -> u32 {
+            self.arg.mode
+        }
+        /// Flags as passed to the open() call
+        pub fn flags(&self) -> i32 {
+            self.arg.flags
+        }
+        pub fn umask(&self) -> u32 {
+            #[cfg(not(feature = "abi-7-12"))]
+            return 0;
+            #[cfg(feature = "abi-7-12")]
+            self.arg.umask
+        }
+    }
+
     /// MacOS only: Rename the volume. Set `fuse_init_out.flags` during init to
     /// `FUSE_VOL_RENAME` to enable
     #[cfg(target_os = "macos")]
@@ -1552,6 +1683,17 @@ mod op {
     }
     #[cfg(target_os = "macos")]
     impl_request!(GetXTimes<'a>);
+
+    /// Android only: Query the canonical path of an inode, i.e. the path of the file
+    /// backing it on another filesystem. The request has no arguments, the reply is the NUL
+    /// terminated path.
+    #[cfg(target_os = "android")]
+    #[derive(Debug)]
+    pub struct CanonicalPath<'a> {
+        header: &'a fuse_in_header,
+    }
+    #[cfg(target_os = "android")]
+    impl_request!(CanonicalPath<'a>);
     // API TODO: Consider rename2(RENAME_EXCHANGE)
     /// macOS only (undocumented)
     #[cfg(target_os = "macos")]
@@ -1592,13 +1734,6 @@ mod op {
     #[cfg(feature = "abi-7-12")]
     impl_request!(CuseInit<'a>);
 
-    fn system_time_from_time(secs: i64, nsecs: u32) -> SystemTime {
-        if secs >= 0 {
-            SystemTime::UNIX_EPOCH + Duration::new(secs as u64, nsecs)
-        } else {
-            SystemTime::UNIX_EPOCH - Duration::new((-secs) as u64, nsecs)
-        }
-    }
     pub(crate) fn parse<'a>(
         header: &'a fuse_in_header,
         opcode: &fuse_opcode,
@@ -1614,7 +1749,11 @@ mod op {
                 header,
                 arg: data.fetch()?,
             }),
-            fuse_opcode::FUSE_GETATTR => Operation::GetAttr(GetAttr { header }),
+            fuse_opcode::FUSE_GETATTR => Operation::GetAttr(GetAttr {
+                header,
+                #[cfg(feature = "abi-7-9")]
+                arg: data.fetch()?,
+            }),
             fuse_opcode::FUSE_SETATTR => Operation::SetAttr(SetAttr {
                 header,
                 arg: data.fetch()?,
@@ -1668,7 +1807,9 @@ mod op {
                     arg: data.fetch()?,
                     data: data.fetch_all(),
                 };
-                assert!(out.data().len() == out.arg.size as usize);
+                if out.data().len() != out.arg.size as usize {
+                    return None;
+                }
                 out
             }),
             fuse_opcode::FUSE_STATFS => Operation::StatFs(StatFs { header }),
@@ -1687,7 +1828,9 @@ mod op {
                     name: data.fetch_str()?,
                     value: data.fetch_all(),
                 };
-                assert!(out.value.len() == out.arg.size as usize);
+                if out.value.len() != out.arg.size as usize {
+                    return None;
+                }
                 out
             }),
             fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr(GetXAttr {
@@ -1758,10 +1901,16 @@ mod op {
             }),
             fuse_opcode::FUSE_DESTROY => Operation::Destroy(Destroy { header }),
             #[cfg(feature = "abi-7-11")]
-            fuse_opcode::FUSE_IOCTL => Operation::IoCtl(IoCtl {
-                header,
-                arg: data.fetch()?,
-                data: data.fetch_all(),
+            fuse_opcode::FUSE_IOCTL => Operation::IoCtl({
+                let out = IoCtl {
+                    header,
+                    arg: data.fetch()?,
+                    data: data.fetch_all(),
+                };
+                if out.data.len() < out.arg.in_size as usize {
+                    return None;
+                }
+                out
             }),
             #[cfg(feature = "abi-7-11")]
             fuse_opcode::FUSE_POLL => Operation::Poll(Poll {
@@ -1808,6 +1957,34 @@ mod op {
                 header,
                 arg: data.fetch()?,
             }),
+            #[cfg(feature = "abi-7-31")]
+            fuse_opcode::FUSE_SETUPMAPPING => Operation::SetupMapping(SetupMapping {
+                header,
+                arg: data.fetch()?,
+            }),
+            #[cfg(feature = "abi-7-31")]
+            fuse_opcode::FUSE_REMOVEMAPPING => {
+                let arg: &fuse_removemapping_in = data.fetch()?;
+                let mappings = data.fetch_all();
+                if mappings.len()
+                    < arg.count as usize * std::mem::size_of::<fuse_removemapping_one>()
+                {
+                    return None;
+                }
+                Operation::RemoveMapping(RemoveMapping {
+                    header,
+                    arg,
+                    mappings,
+                })
+            }
+            // The argument is a fuse_syncfs_in, which only contains padding
+            fuse_opcode::FUSE_SYNCFS => Operation::SyncFs(SyncFs { header }),
+            fuse_opcode::FUSE_TMPFILE => Operation::TmpFile({
+                let arg = data.fetch()?;
+                // The kernel sends the name of the new dentry, which is always "/"
+                data.fetch_str()?;
+                TmpFile { header, arg }
+            }),
 
             #[cfg(target_os = "macos")]
             fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName(SetVolName {
@@ -1823,6 +2000,8 @@ mod op {
                 oldname: data.fetch_str()?.as_ref(),
                 newname: data.fetch_str()?.as_ref(),
             }),
+            #[cfg(target_os = "android")]
+            fuse_opcode::FUSE_CANONICAL_PATH => Operation::CanonicalPath(CanonicalPath { header }),
 
             #[cfg(feature = "abi-7-12")]
             fuse_opcode::CUSE_INIT => Operation::CuseInit(CuseInit {
@@ -1893,6 +2072,12 @@ pub enum Operation<'a> {
     Lseek(Lseek<'a>),
     #[cfg(feature = "abi-7-28")]
     CopyFileRange(CopyFileRange<'a>),
+    #[cfg(feature = "abi-7-31")]
+    SetupMapping(SetupMapping<'a>),
+    #[cfg(feature = "abi-7-31")]
+    RemoveMapping(RemoveMapping<'a>),
+    SyncFs(SyncFs<'a>),
+    TmpFile(TmpFile<'a>),
 
     #[cfg(target_os = "macos")]
     SetVolName(SetVolName<'a>),
@@ -1900,6 +2085,8 @@ pub enum Operation<'a> {
     GetXTimes(GetXTimes<'a>),
     #[cfg(target_os = "macos")]
     Exchange(Exchange<'a>),
+    #[cfg(target_os = "android")]
+    CanonicalPath(CanonicalPath<'a>),
 
     #[cfg(feature = "abi-7-12")]
     CuseInit(CuseInit<'a>),
@@ -1910,7 +2097,7 @@ impl<'a> fmt::Display for Operation<'a> {
         match self {
             Operation::Lookup(x) => write!(f, "LOOKUP name {:?}", x.name()),
             Operation::Forget(x) => write!(f, "FORGET nlookup {}", x.nlookup()),
-            Operation::GetAttr(_) => write!(f, "GETATTR"),
+            Operation::GetAttr(x) => write!(f, "GETATTR fh {:?}", x.file_handle()),
             Operation::SetAttr(x) => x.fmt(f),
             Operation::ReadLink(_) => write!(f, "READLINK"),
             Operation::SymLink(x) => {
@@ -2078,6 +2265,26 @@ impl<'a> fmt::Display for Operation<'a> {
                 x.dest(),
                 x.len()
             ),
+            #[cfg(feature = "abi-7-31")]
+            Operation::SetupMapping(x) => write!(
+                f,
+                "SETUPMAPPING fh {:?}, foffset {}, len {}, flags {:#x}, moffset {}",
+                x.file_handle(),
+                x.foffset(),
+                x.len(),
+                x.flags(),
+                x.moffset()
+            ),
+            #[cfg(feature = "abi-7-31")]
+            Operation::RemoveMapping(x) => write!(
+                f,
+                "REMOVEMAPPING mappings {:?}",
+                x.mappings().collect::<Vec<_>>()
+            ),
+            Operation::SyncFs(_) => write!(f, "SYNCFS"),
+            Operation::TmpFile(x) => {
+                write!(f, "TMPFILE mode {:#05o}, flags {:#x}", x.mode(), x.flags())
+            }
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName(x) => write!(f, "SETVOLNAME name {:?}", x.name()),
@@ -2091,6 +2298,8 @@ impl<'a> fmt::Display for Operation<'a> {
                 x.to(),
                 x.options()
             ),
+            #[cfg(target_os = "android")]
+            Operation::CanonicalPath(_) => write!(f, "CANONICAL_PATH"),
 
             #[cfg(feature = "abi-7-12")]
             Operation::CuseInit(_) => write!(f, "CUSE_INIT"),
@@ -2107,6 +2316,16 @@ pub struct AnyRequest<'a> {
 impl_request!(AnyRequest<'_>);
 
 impl<'a> AnyRequest<'a> {
+    /// Returns the raw opcode of this request, which may be an unknown operation
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Returns the total length of this request, including the header
+    pub fn total_len(&self) -> u32 {
+        self.header.len
+    }
+
     pub fn operation(&self) -> Result<Operation<'a>, RequestError> {
         // Parse/check opcode
         let opcode = fuse_opcode::try_from(self.header.opcode)
@@ -2146,9 +2365,15 @@ impl<'a> TryFrom<&'a [u8]> for AnyRequest<'a> {
         let header: &fuse_in_header = arg_iter
             .fetch()
             .ok_or_else(|| RequestError::ShortReadHeader(arg_iter.len()))?;
-        // Check data size
-        if data_len < header.len as usize {
-            return Err(RequestError::ShortRead(data_len, header.len as usize));
+        // Check data size. The kernel sends exactly one request per read, so the length in
+        // the header must match the size read.
+        let len = header.len as usize;
+        if data_len < len {
+            return Err(RequestError::ShortRead(data_len, len));
+        }
+        if data_len > len || len < mem::size_of::<fuse_in_header>() {
+            let unique = RequestId(header.unique);
+            return Err(RequestError::LengthMismatch(unique, data_len, len));
         }
         Ok(Self {
             header,
@@ -2235,6 +2460,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn length_mismatch() {
+        let mut data = AlignedData([0u8; 64]);
+        data.0[..56].copy_from_slice(&INIT_REQUEST[..]);
+        match AnyRequest::try_from(&data[..]) {
+            Err(RequestError::LengthMismatch(_, 64, 56)) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+        // A length smaller than the header itself
+        data.0[..4].copy_from_slice(&16u32.to_ne_bytes());
+        match AnyRequest::try_from(&data[..16]) {
+            Err(RequestError::ShortReadHeader(16)) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+        match AnyRequest::try_from(&data[..40]) {
+            Err(RequestError::LengthMismatch(_, 40, 16)) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+    }
+
+    #[test]
+    fn total_len() {
+        let req = AnyRequest::try_from(&INIT_REQUEST[..]).unwrap();
+        assert_eq!(req.total_len(), 56);
+        assert_eq!(req.opcode(), 26);
+    }
+
     #[test]
     fn init() {
         let req = AnyRequest::try_from(&INIT_REQUEST[..]).unwrap();
@@ -2277,4 +2529,124 @@ mod tests {
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    const REMOVEMAPPING_REQUEST: AlignedData<[u8; 76]> = AlignedData([
+        0x4c, 0x00, 0x00, 0x00, 0x31, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x02, 0x00, 0x00, 0x00, // count
+        0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // moffset
+        0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+        0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, // moffset
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+    ]);
+
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    #[test]
+    fn removemapping() {
+        let req = AnyRequest::try_from(&REMOVEMAPPING_REQUEST[..]).unwrap();
+        assert_eq!(req.header.len, 76);
+        assert_eq!(req.header.opcode, 49);
+        match req.operation().unwrap() {
+            Operation::RemoveMapping(x) => {
+                let mappings: Vec<_> = x.mappings().map(|m| (m.moffset, m.len)).collect();
+                assert_eq!(mappings, vec![(0x20_0000, 0x20_0000), (0x60_0000, 0x1000)]);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[cfg(target_endian = "little")]
+    const SYNCFS_REQUEST: AlignedData<[u8; 48]> = AlignedData([
+        0x30, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // padding
+    ]);
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn syncfs() {
+        let req = AnyRequest::try_from(&SYNCFS_REQUEST[..]).unwrap();
+        assert_eq!(req.header.opcode, 50);
+        match req.operation().unwrap() {
+            Operation::SyncFs(x) => assert_eq!(x.nodeid(), INodeNo(1)),
+            _ => panic!("Unexpected request operation"),
+        }
+        assert_eq!(req.operation().unwrap().to_string(), "SYNCFS");
+    }
+
+    #[cfg(all(target_endian = "little", not(target_os = "macos")))]
+    const SETXATTR_REQUEST: AlignedData<[u8; 57]> = AlignedData([
+        0x39, 0x00, 0x00, 0x00, 0x15, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // size, flags
+        0x75, 0x73, 0x65, 0x72, 0x2e, 0x61, 0x00, 0x78, // name, value
+        0x79,
+    ]);
+
+    #[cfg(all(target_endian = "little", not(target_os = "macos")))]
+    #[test]
+    fn setxattr() {
+        let req = AnyRequest::try_from(&SETXATTR_REQUEST[..]).unwrap();
+        match req.operation().unwrap() {
+            Operation::SetXAttr(x) => {
+                assert_eq!(x.name(), "user.a");
+                assert_eq!(x.value(), b"xy");
+                assert_eq!(x.flags(), libc::XATTR_CREATE);
+                assert_eq!(x.position(), 0);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    /// Decode a request like the session does, including formatting it for logging
+    fn decode(data: &[u8]) -> Result<String, RequestError> {
+        let req = AnyRequest::try_from(data)?;
+        let op = req.operation()?;
+        Ok(format!("{} {:?}", req, op))
+    }
+
+    #[test]
+    fn arbitrary_requests() {
+        // xorshift, so that failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let opcodes: Vec<u32> = (0..=64).chain(2010..=2020).chain(4096..=4097).collect();
+        for i in 0..50_000 {
+            let opcode = opcodes[random() as usize % opcodes.len()];
+            let arg_len = random() as usize % 128;
+            let mut data = AlignedData([0u8; 168]);
+            for byte in data.0[40..40 + arg_len].iter_mut() {
+                // Mostly small values and NULs, to get past size checks and find strings
+                *byte = match random() % 4 {
+                    0 => 0,
+                    1 => random() as u8,
+                    _ => random() as u8 % 8,
+                };
+            }
+            let mut len = 40 + arg_len;
+            // Some requests with a length not matching the data
+            if i % 20 == 0 {
+                len = random() as usize % len;
+            }
+            data.0[..4].copy_from_slice(&(len as u32).to_ne_bytes());
+            data.0[4..8].copy_from_slice(&opcode.to_ne_bytes());
+            let _ = decode(&data[..40 + arg_len]);
+            let _ = decode(&data[..len]);
+        }
+    }
 }
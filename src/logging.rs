@@ -0,0 +1,771 @@
+//! Request logging
+//!
+//! `LoggingFilesystem` wraps a filesystem to log every call with its arguments, and every
+//! reply with its error or size, at debug level. Unlike the raw requests logged by the
+//! session, this shows the calls as the filesystem sees them, which helps when debugging a
+//! filesystem in the field (e.g. with `RUST_LOG=fuser=debug` and env_logger).
+
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::io::{self, IoSlice};
+use std::path::Path;
+use std::time::SystemTime;
+
+use libc::c_int;
+use log::{debug, log_enabled, trace, Level};
+
+#[cfg(feature = "abi-7-31")]
+use crate::fuse_removemapping_one;
+#[cfg(feature = "abi-7-16")]
+use crate::ll::fuse_abi as abi;
+use crate::reply::{ReplySender, WrapSender};
+#[cfg(feature = "abi-7-11")]
+use crate::ReplyPoll;
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+use crate::{
+    Filesystem, KernelConfig, Ops, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+
+/// Log a call with the given arguments, formatting them only if debug logging is enabled
+macro_rules! log_request {
+    ($req:expr, $operation:expr $(, $arg:expr)*) => {
+        if log_enabled!(Level::Debug) {
+            let args: Vec<String> = vec![$(format!(concat!(stringify!($arg), ": {:?}"), $arg)),*];
+            debug!("[{}] {}({})", $req.unique(), $operation, args.join(", "));
+        }
+    };
+}
+
+/// Reply sender which logs replies before passing them on
+struct LogSender {
+    unique: u64,
+    operation: &'static str,
+    sender: Box<dyn ReplySender>,
+}
+
+impl ReplySender for LogSender {
+    fn send(&self, data: &[IoSlice<'_>]) -> io::Result<()> {
+        // The first buffer contains the fuse_out_header
+        let len: usize = data.iter().map(|d| d.len()).sum();
+        let error = data
+            .first()
+            .and_then(|header| header.get(4..8))
+            .map_or(0, |error| i32::from_ne_bytes(error.try_into().unwrap()));
+        if error == 0 {
+            debug!(
+                "[{}] {} -> ok, {} bytes",
+                self.unique,
+                self.operation,
+                len.saturating_sub(16)
+            );
+            if log_enabled!(Level::Trace) {
+                let bytes: Vec<u8> = data
+                    .iter()
+                    .skip(1)
+                    .flat_map(|d| d.iter().copied())
+                    .collect();
+                trace!("[{}] {} -> {:?}", self.unique, self.operation, bytes);
+            }
+        } else {
+            debug!(
+                "[{}] {} -> error: {}",
+                self.unique,
+                self.operation,
+                io::Error::from_raw_os_error(-error)
+            );
+        }
+        self.sender.send(data)
+    }
+}
+
+/// Wrap the sender of the given reply to log it, if debug logging is enabled
+fn logged<R: WrapSender>(req: &Request<'_>, operation: &'static str, mut reply: R) -> R {
+    if log_enabled!(Level::Debug) {
+        let unique = req.unique();
+        reply.wrap_sender(|sender| {
+            Box::new(LogSender {
+                unique,
+                operation,
+                sender,
+            })
+        });
+    }
+    reply
+}
+
+/// A filesystem wrapper that logs all calls and replies
+///
+/// Every call is logged with its arguments at debug level before it is passed on to the
+/// wrapped filesystem, data buffers only with their length. Replies are logged with their
+/// error, or the size of their data on success, and at trace level also with the data. If
+/// debug logging is disabled, calls and replies are passed on as they are.
+#[derive(Debug)]
+pub struct LoggingFilesystem<FS: Filesystem> {
+    inner: FS,
+}
+
+impl<FS: Filesystem> LoggingFilesystem<FS> {
+    /// Wrap the given filesystem
+    pub fn new(inner: FS) -> Self {
+        LoggingFilesystem { inner }
+    }
+
+    /// Returns a reference to the wrapped filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped filesystem
+    pub fn inner_mut(&mut self) -> &mut FS {
+        &mut self.inner
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+impl<FS: Filesystem> Filesystem for LoggingFilesystem<FS> {
+    fn supported_ops(&self) -> Ops {
+        self.inner.supported_ops()
+    }
+
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        log_request!(req, "init");
+        let result = self.inner.init(req, config);
+        if let Err(err) = result {
+            debug!("[{}] init -> error {}", req.unique(), err);
+        }
+        result
+    }
+
+    fn destroy(&mut self) {
+        debug!("destroy()");
+        self.inner.destroy()
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        log_request!(req, "lookup", parent, name);
+        let reply = logged(req, "lookup", reply);
+        self.inner.lookup(req, parent, name, reply)
+    }
+
+    fn lookup_inode(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEntry) {
+        log_request!(req, "lookup_inode", ino);
+        let reply = logged(req, "lookup_inode", reply);
+        self.inner.lookup_inode(req, ino, reply)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        log_request!(req, "forget", ino, nlookup);
+        self.inner.forget(req, ino, nlookup)
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[abi::fuse_forget_one]) {
+        log_request!(req, "batch_forget", nodes.len());
+        self.inner.batch_forget(req, nodes)
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        log_request!(req, "getattr", ino, fh);
+        let reply = logged(req, "getattr", reply);
+        self.inner.getattr(req, ino, fh, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        log_request!(
+            req, "setattr", ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime,
+            bkuptime, flags
+        );
+        let reply = logged(req, "setattr", reply);
+        self.inner.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        log_request!(req, "readlink", ino);
+        let reply = logged(req, "readlink", reply);
+        self.inner.readlink(req, ino, reply)
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        log_request!(req, "mknod", parent, name, mode, umask, rdev);
+        let reply = logged(req, "mknod", reply);
+        self.inner
+            .mknod(req, parent, name, mode, umask, rdev, reply)
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        log_request!(req, "mkdir", parent, name, mode, umask);
+        let reply = logged(req, "mkdir", reply);
+        self.inner.mkdir(req, parent, name, mode, umask, reply)
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        log_request!(req, "unlink", parent, name);
+        let reply = logged(req, "unlink", reply);
+        self.inner.unlink(req, parent, name, reply)
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        log_request!(req, "rmdir", parent, name);
+        let reply = logged(req, "rmdir", reply);
+        self.inner.rmdir(req, parent, name, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        log_request!(req, "symlink", parent, name, link);
+        let reply = logged(req, "symlink", reply);
+        self.inner.symlink(req, parent, name, link, reply)
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "rename", parent, name, newparent, newname, flags);
+        let reply = logged(req, "rename", reply);
+        self.inner
+            .rename(req, parent, name, newparent, newname, flags, reply)
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        log_request!(req, "link", ino, newparent, newname);
+        let reply = logged(req, "link", reply);
+        self.inner.link(req, ino, newparent, newname, reply)
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        log_request!(req, "open", ino, flags);
+        let reply = logged(req, "open", reply);
+        self.inner.open(req, ino, flags, reply)
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        log_request!(req, "read", ino, fh, offset, size, flags, lock_owner);
+        let reply = logged(req, "read", reply);
+        self.inner
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        log_request!(
+            req,
+            "write",
+            ino,
+            fh,
+            offset,
+            data.len(),
+            write_flags,
+            flags,
+            lock_owner
+        );
+        let reply = logged(req, "write", reply);
+        self.inner.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        log_request!(req, "flush", ino, fh, lock_owner);
+        let reply = logged(req, "flush", reply);
+        self.inner.flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "release", ino, fh, flags, lock_owner, flush);
+        let reply = logged(req, "release", reply);
+        self.inner
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        log_request!(req, "fsync", ino, fh, datasync);
+        let reply = logged(req, "fsync", reply);
+        self.inner.fsync(req, ino, fh, datasync, reply)
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        log_request!(req, "opendir", ino, flags);
+        let reply = logged(req, "opendir", reply);
+        self.inner.opendir(req, ino, flags, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        log_request!(req, "readdir", ino, fh, offset);
+        let reply = logged(req, "readdir", reply);
+        self.inner.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        log_request!(req, "readdirplus", ino, fh, offset);
+        let reply = logged(req, "readdirplus", reply);
+        self.inner.readdirplus(req, ino, fh, offset, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        log_request!(req, "releasedir", ino, fh, flags);
+        let reply = logged(req, "releasedir", reply);
+        self.inner.releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "fsyncdir", ino, fh, datasync);
+        let reply = logged(req, "fsyncdir", reply);
+        self.inner.fsyncdir(req, ino, fh, datasync, reply)
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        log_request!(req, "statfs", ino);
+        let reply = logged(req, "statfs", reply);
+        self.inner.statfs(req, ino, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "setxattr", ino, name, value.len(), flags, position);
+        let reply = logged(req, "setxattr", reply);
+        self.inner
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        log_request!(req, "getxattr", ino, name, size);
+        let reply = logged(req, "getxattr", reply);
+        self.inner.getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        log_request!(req, "listxattr", ino, size);
+        let reply = logged(req, "listxattr", reply);
+        self.inner.listxattr(req, ino, size, reply)
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        log_request!(req, "removexattr", ino, name);
+        let reply = logged(req, "removexattr", reply);
+        self.inner.removexattr(req, ino, name, reply)
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        log_request!(req, "access", ino, mask);
+        let reply = logged(req, "access", reply);
+        self.inner.access(req, ino, mask, reply)
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        log_request!(req, "create", parent, name, mode, umask, flags);
+        let reply = logged(req, "create", reply);
+        self.inner
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        log_request!(req, "getlk", ino, fh, lock_owner, start, end, typ, pid);
+        let reply = logged(req, "getlk", reply);
+        self.inner
+            .getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "setlk", ino, fh, lock_owner, start, end, typ, pid, sleep);
+        let reply = logged(req, "setlk", reply);
+        self.inner
+            .setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        log_request!(req, "bmap", ino, blocksize, idx);
+        let reply = logged(req, "bmap", reply);
+        self.inner.bmap(req, ino, blocksize, idx, reply)
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        log_request!(req, "ioctl", ino, fh, flags, cmd, in_data.len(), out_size);
+        let reply = logged(req, "ioctl", reply);
+        self.inner
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn poll(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        log_request!(req, "poll", ino, fh, kh, events, flags);
+        let reply = logged(req, "poll", reply);
+        self.inner.poll(req, ino, fh, kh, events, flags, reply)
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "fallocate", ino, fh, offset, length, mode);
+        let reply = logged(req, "fallocate", reply);
+        self.inner
+            .fallocate(req, ino, fh, offset, length, mode, reply)
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        log_request!(req, "lseek", ino, fh, offset, whence);
+        let reply = logged(req, "lseek", reply);
+        self.inner.lseek(req, ino, fh, offset, whence, reply)
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        log_request!(
+            req,
+            "copy_file_range",
+            ino_in,
+            fh_in,
+            offset_in,
+            ino_out,
+            fh_out,
+            offset_out,
+            len,
+            flags
+        );
+        let reply = logged(req, "copy_file_range", reply);
+        self.inner.copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+        )
+    }
+
+    #[cfg(feature = "abi-7-31")]
+    fn setup_mapping(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "setup_mapping", ino, fh, foffset, len, flags, moffset);
+        let reply = logged(req, "setup_mapping", reply);
+        self.inner
+            .setup_mapping(req, ino, fh, foffset, len, flags, moffset, reply)
+    }
+
+    #[cfg(feature = "abi-7-31")]
+    fn remove_mapping(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mappings: &[fuse_removemapping_one],
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "remove_mapping", ino, mappings.len());
+        let reply = logged(req, "remove_mapping", reply);
+        self.inner.remove_mapping(req, ino, mappings, reply)
+    }
+
+    fn syncfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEmpty) {
+        log_request!(req, "syncfs", ino);
+        let reply = logged(req, "syncfs", reply);
+        self.inner.syncfs(req, ino, reply)
+    }
+
+    fn tmpfile(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        log_request!(req, "tmpfile", parent, mode, umask, flags);
+        let reply = logged(req, "tmpfile", reply);
+        self.inner.tmpfile(req, parent, mode, umask, flags, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        log_request!(req, "setvolname", name);
+        let reply = logged(req, "setvolname", reply);
+        self.inner.setvolname(req, name, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        options: u64,
+        reply: ReplyEmpty,
+    ) {
+        log_request!(req, "exchange", parent, name, newparent, newname, options);
+        let reply = logged(req, "exchange", reply);
+        self.inner
+            .exchange(req, parent, name, newparent, newname, options, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        log_request!(req, "getxtimes", ino);
+        let reply = logged(req, "getxtimes", reply);
+        self.inner.getxtimes(req, ino, reply)
+    }
+
+    #[cfg(target_os = "android")]
+    fn canonical_path(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        log_request!(req, "canonical_path", ino);
+        let reply = logged(req, "canonical_path", reply);
+        self.inner.canonical_path(req, ino, reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use libc::ENOENT;
+
+    use super::LogSender;
+    use crate::reply::{parse_reply, ReplyCapture, WrapSender};
+    use crate::{Reply, ReplyData};
+
+    #[test]
+    fn replies_passed_on() {
+        let (tx, rx) = channel();
+        let mut reply: ReplyData = Reply::new(7, ReplyCapture(tx.clone()));
+        reply.wrap_sender(|sender| {
+            Box::new(LogSender {
+                unique: 7,
+                operation: "read",
+                sender,
+            })
+        });
+        reply.data(b"data");
+        let buf = rx.recv().unwrap();
+        assert_eq!(parse_reply::<[u8; 4]>(&buf), Ok(b"data"));
+
+        let mut reply: ReplyData = Reply::new(8, ReplyCapture(tx));
+        reply.wrap_sender(|sender| {
+            Box::new(LogSender {
+                unique: 8,
+                operation: "read",
+                sender,
+            })
+        });
+        reply.error(ENOENT);
+        assert_eq!(parse_reply::<()>(&rx.recv().unwrap()), Err(ENOENT));
+    }
+}
@@ -0,0 +1,181 @@
+//! Lookup count tracking
+//!
+//! Every entry replied to lookup, mknod, mkdir, symlink, link, create, tmpfile and readdirplus
+//! (except for "." and "..") increments the lookup count of its inode in the kernel, and
+//! forget drops lookups again. A filesystem must keep an inode until its count dropped to
+//! zero, so bookkeeping which drifts from the kernel's count leaks inodes or reuses them too
+//! early. If enabled with `Session::set_track_lookups()`, the session tallies the lookups it
+//! replied and the forgets it received, so the counts can be compared with the filesystem's.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::IoSlice;
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::ll::fuse_abi as abi;
+
+/// Lookup counts of inodes, as replied to the kernel and not forgotten yet
+#[derive(Debug, Default)]
+pub(crate) struct Lookups(Mutex<BTreeMap<u64, u64>>);
+
+impl Lookups {
+    /// Count the entries of a sent reply. The first buffer contains the fuse_out_header,
+    /// followed by a fuse_entry_out, or by fuse_direntplus entries for readdirplus.
+    pub(crate) fn replied(&self, readdirplus: bool, bufs: &[IoSlice<'_>]) {
+        let header = match bufs.first() {
+            Some(header) if header.len() >= 16 => header,
+            _ => return,
+        };
+        if i32::from_ne_bytes(header[4..8].try_into().unwrap()) != 0 {
+            return;
+        }
+        let data: Vec<u8> = bufs[1..]
+            .iter()
+            .flat_map(|buf| buf.iter().copied())
+            .collect();
+        let mut counts = self.0.lock().unwrap();
+        let mut add = |entry: &[u8]| {
+            // An inode number of 0 is a negative entry, which isn't counted
+            let ino = u64::from_ne_bytes(entry[0..8].try_into().unwrap());
+            if ino != 0 {
+                *counts.entry(ino).or_insert(0) += 1;
+            }
+        };
+        if !readdirplus {
+            if data.len() >= 8 {
+                add(&data);
+            }
+            return;
+        }
+        let entry_size = size_of::<abi::fuse_entry_out>();
+        let header_size = entry_size + size_of::<abi::fuse_dirent>();
+        let mut rest = &data[..];
+        while rest.len() >= header_size {
+            let namelen =
+                u32::from_ne_bytes(rest[entry_size + 16..entry_size + 20].try_into().unwrap());
+            let name_end = (header_size + namelen as usize).min(rest.len());
+            let name = &rest[header_size..name_end];
+            if name != b"." && name != b".." {
+                add(rest);
+            }
+            let size = (header_size + namelen as usize + 7) & !7;
+            rest = &rest[size.min(rest.len())..];
+        }
+    }
+
+    /// Drop nlookup lookups of the given inode for a forget, and warn if the kernel forgets
+    /// more lookups than were replied
+    pub(crate) fn forgot(&self, ino: u64, nlookup: u64) {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.get(&ino).copied().unwrap_or(0);
+        if nlookup > count {
+            warn!(
+                "Forget of {} lookup(s) of inode {}, but only {} were replied",
+                nlookup, ino, count
+            );
+        }
+        if nlookup >= count {
+            counts.remove(&ino);
+        } else {
+            counts.insert(ino, count - nlookup);
+        }
+    }
+
+    /// Returns the lookup counts of all inodes which weren't forgotten
+    pub(crate) fn counts(&self) -> BTreeMap<u64, u64> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Log the inodes which weren't forgotten, e.g. when the session ends. The kernel doesn't
+    /// send forgets when the filesystem is unmounted, so these are the counts the
+    /// filesystem's bookkeeping should have at that point.
+    pub(crate) fn log_outstanding(&self) {
+        let counts = self.0.lock().unwrap();
+        if counts.is_empty() {
+            return;
+        }
+        info!(
+            "{} inode(s) with outstanding lookups when the session ended",
+            counts.len()
+        );
+        for (ino, count) in counts.iter() {
+            info!("Inode {} has {} outstanding lookup(s)", ino, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::IoSlice;
+
+    use zerocopy::AsBytes;
+
+    use super::Lookups;
+    use crate::ll::fuse_abi as abi;
+
+    fn header(error: i32) -> abi::fuse_out_header {
+        abi::fuse_out_header {
+            len: 0,
+            error,
+            unique: 1,
+        }
+    }
+
+    #[test]
+    fn count_lookups() {
+        let lookups = Lookups::default();
+        let reply = |error: i32, ino: u64| {
+            let header = header(error);
+            let entry = [&ino.to_ne_bytes()[..], &[0; 32]].concat();
+            lookups.replied(
+                false,
+                &[IoSlice::new(header.as_bytes()), IoSlice::new(&entry)],
+            );
+        };
+        reply(0, 2);
+        reply(0, 2);
+        reply(0, 3);
+        reply(0, 0); // negative entry
+        reply(-libc::ENOENT, 4);
+        assert_eq!(
+            lookups.counts().into_iter().collect::<Vec<_>>(),
+            [(2, 2), (3, 1)]
+        );
+
+        lookups.forgot(2, 1);
+        lookups.forgot(3, 5);
+        assert_eq!(lookups.counts().into_iter().collect::<Vec<_>>(), [(2, 1)]);
+        lookups.forgot(2, 1);
+        assert!(lookups.counts().is_empty());
+    }
+
+    #[test]
+    fn count_readdirplus() {
+        use std::mem::size_of;
+
+        let mut data = vec![];
+        for (ino, name) in [(1u64, &b"."[..]), (1, b".."), (5, b"a"), (6, b"long name")] {
+            let size = size_of::<abi::fuse_entry_out>() + size_of::<abi::fuse_dirent>();
+            let mut entry = vec![0; size];
+            entry[0..8].copy_from_slice(&ino.to_ne_bytes());
+            let namelen = size_of::<abi::fuse_entry_out>() + 16;
+            entry[namelen..namelen + 4].copy_from_slice(&(name.len() as u32).to_ne_bytes());
+            entry.extend_from_slice(name);
+            entry.resize((entry.len() + 7) & !7, 0);
+            data.extend_from_slice(&entry);
+        }
+        let lookups = Lookups::default();
+        let header = header(0);
+        lookups.replied(
+            true,
+            &[IoSlice::new(header.as_bytes()), IoSlice::new(&data)],
+        );
+        assert_eq!(
+            lookups.counts().into_iter().collect::<Vec<_>>(),
+            [(5, 1), (6, 1)]
+        );
+    }
+}
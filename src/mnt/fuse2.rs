@@ -26,6 +26,12 @@ impl Mount {
             }
         })
     }
+
+    /// Check the prerequisites for mounting without mounting. libfuse locates the mount
+    /// helper itself, so its presence isn't checked.
+    pub fn check(mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+        super::check_prerequisites(mountpoint, options)
+    }
 }
 impl Drop for Mount {
     fn drop(&mut self) {
@@ -44,6 +44,12 @@ impl Mount {
             Ok((Arc::new(file), mount))
         })
     }
+
+    /// Check the prerequisites for mounting without mounting. libfuse locates the mount
+    /// helper itself, so its presence isn't checked.
+    pub fn check(mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+        super::check_prerequisites(mountpoint, options)
+    }
 }
 impl Drop for Mount {
     fn drop(&mut self) {
@@ -6,8 +6,8 @@
 #![warn(missing_debug_implementations)]
 #![allow(missing_docs)]
 
-use super::is_mounted;
 use super::mount_options::{option_to_string, MountOption};
+use super::{find_fusermount_bin, is_mounted, MountError, FUSERMOUNT3_BIN, FUSERMOUNT_BIN};
 use libc::c_int;
 use log::{debug, error};
 use std::ffi::{CStr, CString, OsStr};
@@ -19,12 +19,10 @@ use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
 use std::sync::Arc;
 use std::{mem, ptr};
 
-const FUSERMOUNT_BIN: &str = "fusermount";
-const FUSERMOUNT3_BIN: &str = "fusermount3";
 const FUSERMOUNT_COMM_ENV: &str = "_FUSE_COMMFD";
 
 #[derive(Debug)]
@@ -47,6 +45,25 @@ impl Mount {
             },
         ))
     }
+
+    /// Check the prerequisites for mounting without mounting. The mount helper is only
+    /// required if mounting directly isn't possible, i.e. without CAP_SYS_ADMIN (which a
+    /// process may have in its own user namespace only, e.g. in a rootless container) or if
+    /// auto unmount is requested.
+    pub fn check(mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+        super::check_prerequisites(mountpoint, options)?;
+        let needs_helper = !can_mount() || options.contains(&MountOption::AutoUnmount);
+        if needs_helper && find_fusermount_bin().is_none() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Mount helper {} or {} not found",
+                    FUSERMOUNT3_BIN, FUSERMOUNT_BIN
+                ),
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Mount {
@@ -93,6 +110,51 @@ fn fuse_mount_pure(
     }
 }
 
+/// Returns true if the process may call mount(), i.e. if it has CAP_SYS_ADMIN. Inside a user
+/// namespace the capability only applies to mount namespaces owned by it, which is where a
+/// rootless container mounts, and FUSE allows such mounts since Linux 4.18.
+fn can_mount() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        match std::fs::read_to_string("/proc/self/status") {
+            Ok(status) => has_cap_sys_admin(&status),
+            Err(_) => unsafe { libc::geteuid() == 0 },
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+}
+
+/// Returns true if the effective capabilities in the given /proc/<pid>/status include
+/// CAP_SYS_ADMIN
+#[cfg(target_os = "linux")]
+fn has_cap_sys_admin(status: &str) -> bool {
+    const CAP_SYS_ADMIN: u32 = 21;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok());
+    matches!(caps, Some(caps) if caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Returns true if the process runs in a user namespace other than the initial one, whose
+/// uid map covers all uids
+#[cfg(target_os = "linux")]
+fn in_user_namespace() -> bool {
+    match std::fs::read_to_string("/proc/self/uid_map") {
+        Ok(map) => !is_initial_uid_map(&map),
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_initial_uid_map(map: &str) -> bool {
+    let fields: Vec<&str> = map.split_whitespace().collect();
+    fields == ["0", "0", "4294967295"]
+}
+
 fn fuse_unmount_pure(mountpoint: &CStr) {
     #[cfg(target_os = "linux")]
     unsafe {
@@ -125,20 +187,8 @@ fn fuse_unmount_pure(mountpoint: &CStr) {
 }
 
 fn detect_fusermount_bin() -> String {
-    for name in [
-        FUSERMOUNT3_BIN.to_string(),
-        FUSERMOUNT_BIN.to_string(),
-        format!("/bin/{}", FUSERMOUNT3_BIN),
-        format!("/bin/{}", FUSERMOUNT_BIN),
-    ]
-    .iter()
-    {
-        if Command::new(name).arg("-h").output().is_ok() {
-            return name.to_string();
-        }
-    }
     // Default to fusermount3
-    FUSERMOUNT3_BIN.to_string()
+    find_fusermount_bin().unwrap_or_else(|| FUSERMOUNT3_BIN.to_string())
 }
 
 fn receive_fusermount_message(socket: &UnixStream) -> Result<File, Error> {
@@ -243,7 +293,10 @@ fn fuse_mount_fusermount(
 
     drop(child_socket); // close socket in parent
 
-    let file = receive_fusermount_message(&receive_socket)?;
+    let file = match receive_fusermount_message(&receive_socket) {
+        Ok(file) => file,
+        Err(err) => return Err(fusermount_error(err, fusermount_child)),
+    };
     let mut receive_socket = Some(receive_socket);
 
     if !options.contains(&MountOption::AutoUnmount) {
@@ -287,6 +340,47 @@ fn fuse_mount_fusermount(
     Ok((file, receive_socket))
 }
 
+/// Errors of fusermount's "mount failed: <description>" message that are known to be
+/// transient, by their description as printed by glibc and musl's strerror(3)
+const TRANSIENT_MOUNT_ERRORS: &[(&str, c_int)] = &[
+    ("Device or resource busy", libc::EBUSY),
+    ("Resource busy", libc::EBUSY),
+    ("Resource temporarily unavailable", libc::EAGAIN),
+    ("Interrupted system call", libc::EINTR),
+];
+
+/// Error of a fusermount which exited without passing a fuse device. If fusermount reports
+/// a failed mount with one of the known transient errors, its errno is kept, so that the
+/// mount can be retried.
+fn fusermount_error(err: Error, child: Child) -> Error {
+    let stderr = match child.wait_with_output() {
+        Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        Err(_) => return err,
+    };
+    debug!("fusermount: {}", stderr);
+    let errno = stderr
+        .lines()
+        .find_map(|line| line.split("mount failed: ").nth(1))
+        .and_then(transient_mount_errno);
+    match errno {
+        Some(errno) => MountError {
+            errno,
+            message: format!("fusermount failed: {}", stderr),
+        }
+        .into(),
+        None if !stderr.is_empty() => Error::new(err.kind(), format!("{}: {}", err, stderr)),
+        None => err,
+    }
+}
+
+/// Returns the errno of a known transient error with the given description
+fn transient_mount_errno(description: &str) -> Option<c_int> {
+    TRANSIENT_MOUNT_ERRORS
+        .iter()
+        .find(|(known, _)| *known == description.trim())
+        .map(|&(_, errno)| errno)
+}
+
 // If returned option is none. Then fusermount binary should be tried
 fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<File>, Error> {
     let fuse_device_name = "/dev/fuse";
@@ -406,12 +500,22 @@ fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<
     if result == -1 {
         let err = Error::last_os_error();
         if err.kind() == ErrorKind::PermissionDenied {
+            // The setuid helper usually can't mount from within a user namespace either
+            #[cfg(target_os = "linux")]
+            if in_user_namespace() {
+                debug!(
+                    "mount() in a user namespace failed: {}. The process needs CAP_SYS_ADMIN in \
+                     the user namespace which owns its mount namespace, and Linux 4.18 or later",
+                    err
+                );
+            }
             return Ok(None); // Retry with fusermount
         } else {
-            return Err(Error::new(
-                err.kind(),
-                format!("Error calling mount() at {:?}: {}", mountpoint, err),
-            ));
+            return Err(MountError {
+                errno: err.raw_os_error().unwrap(),
+                message: format!("Error calling mount() at {:?}: {}", mountpoint, err),
+            }
+            .into());
         }
     }
 
@@ -429,6 +533,7 @@ pub fn option_group(option: &MountOption) -> MountOptionGroup {
     match option {
         MountOption::FSName(_) => MountOptionGroup::Fusermount,
         MountOption::Subtype(_) => MountOptionGroup::Fusermount,
+        MountOption::MaxRead(_) => MountOptionGroup::KernelOption,
         MountOption::CUSTOM(_) => MountOptionGroup::KernelOption,
         MountOption::AutoUnmount => MountOptionGroup::Fusermount,
         MountOption::AllowOther => MountOptionGroup::KernelOption,
@@ -442,6 +547,11 @@ pub fn option_group(option: &MountOption) -> MountOptionGroup {
         MountOption::NoExec => MountOptionGroup::KernelFlag,
         MountOption::Atime => MountOptionGroup::KernelFlag,
         MountOption::NoAtime => MountOptionGroup::KernelFlag,
+        MountOption::DirAtime => MountOptionGroup::KernelFlag,
+        MountOption::NoDirAtime => MountOptionGroup::KernelFlag,
+        MountOption::RelAtime => MountOptionGroup::KernelFlag,
+        MountOption::NoRelAtime => MountOptionGroup::KernelFlag,
+        MountOption::StrictAtime => MountOptionGroup::KernelFlag,
         MountOption::DirSync => MountOptionGroup::KernelFlag,
         MountOption::Sync => MountOptionGroup::KernelFlag,
         MountOption::Async => MountOptionGroup::KernelFlag,
@@ -463,6 +573,11 @@ pub fn option_to_flag(option: &MountOption) -> libc::c_ulong {
         MountOption::NoExec => libc::MS_NOEXEC,
         MountOption::Atime => 0,
         MountOption::NoAtime => libc::MS_NOATIME,
+        MountOption::DirAtime => 0,
+        MountOption::NoDirAtime => libc::MS_NODIRATIME,
+        MountOption::RelAtime => libc::MS_RELATIME,
+        MountOption::NoRelAtime => 0,
+        MountOption::StrictAtime => libc::MS_STRICTATIME,
         MountOption::Async => 0,
         MountOption::Sync => libc::MS_SYNCHRONOUS,
         MountOption::DirSync => libc::MS_DIRSYNC,
@@ -485,6 +600,73 @@ pub fn option_to_flag(option: &MountOption) -> libc::c_int {
         MountOption::NoAtime => libc::MNT_NOATIME,
         MountOption::Async => 0,
         MountOption::Sync => libc::MNT_SYNCHRONOUS,
+        // macOS has no flags for these, they are ignored
+        MountOption::DirSync
+        | MountOption::DirAtime
+        | MountOption::NoDirAtime
+        | MountOption::RelAtime
+        | MountOption::NoRelAtime
+        | MountOption::StrictAtime => 0,
         _ => unreachable!(),
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::io::{Error, ErrorKind};
+    use std::process::{Command, Stdio};
+
+    use super::super::mount_errno;
+    use super::{fusermount_error, has_cap_sys_admin, is_initial_uid_map, transient_mount_errno};
+
+    #[test]
+    fn capabilities() {
+        let status =
+            |caps: &str| format!("Name:\tcat\nCapPrm:\t0\nCapEff:\t{}\nCapBnd:\t0\n", caps);
+        assert!(has_cap_sys_admin(&status("000001ffffffffff")));
+        assert!(has_cap_sys_admin(&status("0000000000200000")));
+        assert!(!has_cap_sys_admin(&status("0000000000000000")));
+        assert!(!has_cap_sys_admin(&status("00000000001fffff")));
+        assert!(!has_cap_sys_admin("Name:\tcat\n"));
+    }
+
+    #[test]
+    fn user_namespace() {
+        assert!(is_initial_uid_map("         0          0 4294967295\n"));
+        assert!(!is_initial_uid_map("         0       1000          1\n"));
+        assert!(!is_initial_uid_map(
+            "         0       1000          1\n         1     100000      65536\n"
+        ));
+    }
+
+    #[test]
+    fn fusermount_errno() {
+        assert_eq!(
+            transient_mount_errno("Device or resource busy"),
+            Some(libc::EBUSY)
+        );
+        assert_eq!(
+            transient_mount_errno("Resource temporarily unavailable\n"),
+            Some(libc::EAGAIN)
+        );
+        assert_eq!(transient_mount_errno("Permission denied"), None);
+
+        let fusermount = |stderr: &str| {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(format!("echo '{}' >&2", stderr))
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let eof = Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF");
+            fusermount_error(eof, child)
+        };
+        let err = fusermount("fusermount3: mount failed: Device or resource busy");
+        assert_eq!(mount_errno(&err), Some(libc::EBUSY));
+        assert!(err.to_string().contains("mount failed"));
+        let err = fusermount("fusermount3: bad mount point /mnt: No such file or directory");
+        assert_eq!(mount_errno(&err), None);
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("bad mount point"));
+    }
+}
@@ -19,10 +19,9 @@ pub mod mount_options;
 use fuse2_sys::fuse_args;
 #[cfg(any(test, not(feature = "libfuse")))]
 use std::fs::File;
-#[cfg(any(test, not(feature = "libfuse3")))]
 use std::io;
+use std::path::Path;
 
-#[cfg(any(feature = "libfuse", test))]
 use mount_options::MountOption;
 
 /// Helper function to provide options as a fuse_args struct
@@ -47,6 +46,127 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[MountOption], f: F)
     })
 }
 
+/// Check that the mountpoint exists and can be mounted on, to report mistakes with a more
+/// descriptive error than the mount helper would. Like fusermount, directories and regular
+/// files are accepted.
+pub(crate) fn check_mountpoint(mountpoint: &Path) -> io::Result<()> {
+    let error = |errno: libc::c_int, msg: String| {
+        io::Error::new(io::Error::from_raw_os_error(errno).kind(), msg)
+    };
+    match std::fs::metadata(mountpoint) {
+        Ok(metadata) if metadata.is_dir() || metadata.is_file() => Ok(()),
+        Ok(_) => Err(error(
+            libc::ENOTDIR,
+            format!(
+                "Mountpoint {} is not a directory or a regular file",
+                mountpoint.display()
+            ),
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(error(
+            libc::ENOENT,
+            format!("Mountpoint {} does not exist", mountpoint.display()),
+        )),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("Cannot access mountpoint {}: {}", mountpoint.display(), err),
+        )),
+    }
+}
+
+/// Check that nothing is mounted on the mountpoint yet. Like mountpoint(1), a path is
+/// considered a mountpoint if it is on a different device than its parent, or if it is the
+/// root directory.
+pub(crate) fn check_not_mounted(mountpoint: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mountpoint = mountpoint.canonicalize()?;
+    let metadata = std::fs::metadata(&mountpoint)?;
+    let mounted = match mountpoint.parent() {
+        Some(parent) => std::fs::metadata(parent)?.dev() != metadata.dev(),
+        None => true,
+    };
+    if mounted {
+        return Err(io::Error::new(
+            io::Error::from_raw_os_error(libc::EBUSY).kind(),
+            format!(
+                "Mountpoint {} is already a mountpoint",
+                mountpoint.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the FUSE device can be opened for reading and writing
+#[cfg(not(target_os = "macos"))]
+pub(crate) fn check_fuse_device() -> io::Result<()> {
+    const FUSE_DEVICE: &str = "/dev/fuse";
+
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(FUSE_DEVICE)
+    {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+            err.kind(),
+            format!("{} not found. Try 'modprobe fuse'", FUSE_DEVICE),
+        )),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("Cannot open {}: {}", FUSE_DEVICE, err),
+        )),
+    }
+}
+
+/// Check the prerequisites for mounting shared by all mount implementations: valid
+/// options, a mountpoint which exists and isn't mounted on yet and an accessible FUSE
+/// device. Returns the error of the first check which fails.
+pub(crate) fn check_prerequisites(mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+    mount_options::check_mount_options(options)?;
+    check_mountpoint(mountpoint)?;
+    check_not_mounted(mountpoint)?;
+    // macFUSE loads its kernel extension and opens one of several devices while mounting
+    #[cfg(not(target_os = "macos"))]
+    check_fuse_device()?;
+    Ok(())
+}
+
+/// Error of a failed mount, which keeps the errno of mount(2) or of fusermount together
+/// with a message naming the mountpoint
+#[cfg(not(feature = "libfuse"))]
+#[derive(Debug)]
+struct MountError {
+    errno: libc::c_int,
+    message: String,
+}
+
+#[cfg(not(feature = "libfuse"))]
+impl std::fmt::Display for MountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(not(feature = "libfuse"))]
+impl std::error::Error for MountError {}
+
+#[cfg(not(feature = "libfuse"))]
+impl From<MountError> for io::Error {
+    fn from(err: MountError) -> Self {
+        io::Error::new(io::Error::from_raw_os_error(err.errno).kind(), err)
+    }
+}
+
+/// Returns the errno of a failed mount, also if the error carries a message
+pub(crate) fn mount_errno(err: &io::Error) -> Option<libc::c_int> {
+    #[cfg(not(feature = "libfuse"))]
+    if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<MountError>()) {
+        return Some(err.errno);
+    }
+    err.raw_os_error()
+}
+
 #[cfg(feature = "libfuse2")]
 pub use fuse2::Mount;
 #[cfg(feature = "libfuse3")]
@@ -85,6 +205,176 @@ fn libc_umount(mnt: &CStr) -> io::Result<()> {
     }
 }
 
+#[cfg(any(target_os = "linux", not(feature = "libfuse")))]
+const FUSERMOUNT_BIN: &str = "fusermount";
+#[cfg(any(target_os = "linux", not(feature = "libfuse")))]
+const FUSERMOUNT3_BIN: &str = "fusermount3";
+
+/// Returns the name of the fusermount binary, preferring fusermount3
+#[cfg(any(target_os = "linux", not(feature = "libfuse")))]
+fn find_fusermount_bin() -> Option<String> {
+    use std::process::Command;
+
+    for name in [
+        FUSERMOUNT3_BIN.to_string(),
+        FUSERMOUNT_BIN.to_string(),
+        format!("/bin/{}", FUSERMOUNT3_BIN),
+        format!("/bin/{}", FUSERMOUNT_BIN),
+    ]
+    .iter()
+    {
+        if Command::new(name).arg("-h").output().is_ok() {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+/// Unmount the filesystem even if it is busy. On Linux, the mountpoint is detached lazily
+/// (`umount -l`), falling back to `fusermount -u -z` for non-root users, and the connection is
+/// aborted if possible (root only), failing outstanding requests so that the session loop ends
+/// right away instead of once the last open file is closed. Other systems unmount with
+/// MNT_FORCE.
+pub(crate) fn force_unmount(mountpoint: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+    #[cfg(target_os = "linux")]
+    {
+        use log::debug;
+        use std::process::Command;
+
+        // The fuse control filesystem names connections by device number. It is looked up in
+        // mountinfo, since a stat of the mountpoint would hang if the filesystem does.
+        let dev = mount_info(mountpoint).map(|info| info.dev);
+        if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::PermissionDenied {
+                return Err(err);
+            }
+            let bin = find_fusermount_bin().ok_or(err)?;
+            let output = Command::new(&bin)
+                .arg("-u")
+                .arg("-q")
+                .arg("-z")
+                .arg("--")
+                .arg(mountpoint)
+                .output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    bin,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+        let dev = match dev {
+            Ok(dev) => dev,
+            Err(err) => {
+                debug!("Could not abort connection: {}", err);
+                return Ok(());
+            }
+        };
+        let abort = format!("/sys/fs/fuse/connections/{}/abort", dev);
+        if let Err(err) = std::fs::write(&abort, "1") {
+            debug!("Could not abort connection via {}: {}", abort, err);
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        if unsafe { libc::unmount(path.as_ptr(), libc::MNT_FORCE) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Identifiers the kernel assigned to a mount, as listed in /proc/self/mountinfo
+///
+/// The kernel reports the device number as `st_dev` of every file on the mount, it isn't
+/// part of the attributes replied by the filesystem. The fuse control filesystem names the
+/// connection of a mount by it as well (/sys/fs/fuse/connections/<dev>).
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MountInfo {
+    /// Unique id of the mount, the first field of /proc/self/mountinfo
+    pub mount_id: u64,
+    /// Device number of the mount, the same as `st_dev` of its files
+    pub dev: u64,
+}
+
+/// Look up the given mountpoint in /proc/self/mountinfo. This doesn't access the mountpoint
+/// itself, since that would send a request to the filesystem, which may not be running yet.
+#[cfg(target_os = "linux")]
+pub(crate) fn mount_info(mountpoint: &Path) -> io::Result<MountInfo> {
+    // Resolve the parent only, for the same reason
+    let mountpoint = match (mountpoint.parent(), mountpoint.file_name()) {
+        (Some(parent), Some(name)) if parent != Path::new("") => parent.canonicalize()?.join(name),
+        (Some(_), Some(name)) => std::env::current_dir()?.join(name),
+        _ => mountpoint.canonicalize()?,
+    };
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    parse_mount_info(&mountinfo, &mountpoint).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No fuse mount at {} found", mountpoint.display()),
+        )
+    })
+}
+
+/// Returns the topmost fuse mount at the given mountpoint in the given mountinfo
+#[cfg(target_os = "linux")]
+fn parse_mount_info(mountinfo: &str, mountpoint: &Path) -> Option<MountInfo> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    mountinfo.lines().rev().find_map(|line| {
+        // mount id, parent id, major:minor, root, mount point, options, optional
+        // fields, a separator and then the filesystem type
+        let mut fields = line.split(' ');
+        let mount_id = fields.next()?.parse().ok()?;
+        let (major, minor) = fields.nth(1)?.split_once(':')?;
+        let path = unescape_mount_path(fields.nth(1)?);
+        let fstype = fields.skip_while(|field| *field != "-").nth(1)?;
+        if OsStr::from_bytes(&path) != mountpoint.as_os_str()
+            || !(fstype == "fuse" || fstype == "fuseblk" || fstype.starts_with("fuse."))
+        {
+            return None;
+        }
+        let (major, minor) = (major.parse().ok()?, minor.parse().ok()?);
+        // makedev only combines the numbers, like the kernel does for st_dev
+        let dev = unsafe { libc::makedev(major, minor) };
+        Some(MountInfo { mount_id, dev })
+    })
+}
+
+/// Decode the octal escapes of spaces, tabs, newlines and backslashes in mountinfo paths
+#[cfg(target_os = "linux")]
+fn unescape_mount_path(path: &str) -> Vec<u8> {
+    let bytes = path.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).and_then(|octal| {
+            let octal = std::str::from_utf8(octal).ok()?;
+            u8::from_str_radix(octal, 8).ok()
+        });
+        match escaped {
+            Some(byte) if bytes[i] == b'\\' => {
+                result.push(byte);
+                i += 4;
+            }
+            _ => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
 /// Warning: This will return true if the filesystem has been detached (lazy unmounted), but not
 /// yet destroyed by the kernel.
 #[cfg(any(test, not(feature = "libfuse")))]
@@ -123,6 +413,30 @@ mod test {
     use super::*;
     use std::{ffi::CStr, mem::ManuallyDrop};
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_mount_info() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+35 22 0:40 / /mnt/a\\040b rw,nosuid,nodev shared:20 - fuse.test test rw,user_id=0,group_id=0
+36 22 0:41 / /mnt/c rw,nosuid,nodev - fuse /dev/fuse rw,user_id=0,group_id=0
+37 36 0:42 / /mnt/c rw,nosuid,nodev - fuse /dev/fuse rw,user_id=0,group_id=0
+38 22 0:43 / /mnt/d rw,relatime - tmpfs tmpfs rw
+";
+        let info = |path: &str| super::parse_mount_info(mountinfo, Path::new(path));
+        assert_eq!(
+            info("/mnt/a b"),
+            Some(MountInfo {
+                mount_id: 35,
+                dev: unsafe { libc::makedev(0, 40) }
+            })
+        );
+        // The topmost of stacked mounts
+        assert_eq!(info("/mnt/c").unwrap().mount_id, 37);
+        assert_eq!(info("/mnt/d"), None);
+        assert_eq!(info("/mnt"), None);
+    }
+
     #[test]
     fn fuse_args() {
         with_fuse_args(
@@ -142,6 +456,55 @@ mod test {
             },
         );
     }
+    #[test]
+    fn mountpoint_checking() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(check_mountpoint(tmp.path()).is_ok());
+        let file = tmp.path().join("file");
+        File::create(&file).unwrap();
+        assert!(check_mountpoint(&file).is_ok());
+
+        let missing = tmp.path().join("missing");
+        let err = check_mountpoint(&missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains(&*missing.to_string_lossy()));
+        let err = check_mountpoint(Path::new("/dev/null")).unwrap_err();
+        let kind = io::Error::from_raw_os_error(libc::ENOTDIR).kind();
+        assert_eq!(err.kind(), kind);
+        assert_eq!(
+            err.to_string(),
+            "Mountpoint /dev/null is not a directory or a regular file"
+        );
+    }
+
+    #[test]
+    fn prerequisite_checking() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(check_not_mounted(tmp.path()).is_ok());
+        let busy = io::Error::from_raw_os_error(libc::EBUSY).kind();
+        let err = check_not_mounted(Path::new("/")).unwrap_err();
+        assert_eq!(err.kind(), busy);
+        assert_eq!(err.to_string(), "Mountpoint / is already a mountpoint");
+
+        // The first failing check is reported
+        let err = Mount::check(Path::new("/"), &[MountOption::RO, MountOption::RW]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Mount::check(&tmp.path().join("missing"), &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = Mount::check(Path::new("/"), &[]).unwrap_err();
+        assert_eq!(err.kind(), busy);
+    }
+
+    #[cfg(not(feature = "libfuse"))]
+    #[test]
+    fn mount_error_errno() {
+        // The kernel rejects unknown options, either for mount() or within fusermount
+        let tmp = tempfile::tempdir().unwrap();
+        let options = [MountOption::CUSTOM("no_such_option".to_string())];
+        let err = Mount::new(tmp.path(), &options).unwrap_err();
+        assert_eq!(mount_errno(&err), Some(libc::EINVAL));
+    }
+
     fn cmd_mount() -> String {
         std::str::from_utf8(
             std::process::Command::new("sh")
@@ -186,4 +549,16 @@ mod test {
         // Filesystem may have been lazy unmounted, so we can't assert this:
         // assert!(!is_mounted(&file));
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn force_unmount_unresponsive() {
+        // Nothing reads from the fuse device, so any request to the filesystem would block
+        let tmp = ManuallyDrop::new(tempfile::tempdir().unwrap());
+        let (_file, mount) = Mount::new(tmp.path(), &[]).unwrap();
+        force_unmount(tmp.path()).unwrap();
+        assert!(!cmd_mount().contains(&*tmp.path().to_string_lossy()));
+        drop(mount);
+        std::mem::ManuallyDrop::<_>::into_inner(tmp);
+    }
 }
@@ -1,6 +1,6 @@
+use std::ffi::OsStr;
 use std::io;
 use std::io::ErrorKind;
-use std::{collections::HashSet, ffi::OsStr};
 
 /// Mount options accepted by the FUSE filesystem type
 /// See 'man mount.fuse' for details
@@ -11,6 +11,10 @@ pub enum MountOption {
     FSName(String),
     /// Set the filesystem subtype in mtab
     Subtype(String),
+    /// Limit the size of read requests to the given number of bytes. The kernel rounds it
+    /// up to at least 4096, and the negotiated max readahead and max pages limit reads too.
+    /// Must not be 0.
+    MaxRead(u32),
     /// Allows passing an option which is not otherwise supported in these enums
     #[allow(clippy::upper_case_acronyms)]
     CUSTOM(String),
@@ -19,7 +23,8 @@ pub enum MountOption {
     /// Allow all users to access files on this filesystem. By default access is restricted to the
     /// user who mounted it
     AllowOther,
-    /// Allow the root user to access this filesystem, in addition to the user who mounted it
+    /// Allow the root user to access this filesystem, in addition to the user who mounted it.
+    /// This is enforced by fuser rather than the kernel, see `Request::is_permitted()`
     AllowRoot,
     /// Automatically unmount when the mounting process exits
     AutoUnmount,
@@ -47,6 +52,17 @@ pub enum MountOption {
     Atime,
     /// Don't update inode access time
     NoAtime,
+    /// Update directory inode access time (the default)
+    DirAtime,
+    /// Don't update directory inode access time
+    NoDirAtime,
+    /// Update inode access time only if it is older than the modification or change time, or
+    /// more than a day old
+    RelAtime,
+    /// Don't use relatime
+    NoRelAtime,
+    /// Always update inode access time, overriding noatime and relatime defaults
+    StrictAtime,
     /// All modifications to directories will be done synchronously
     DirSync,
     /// All I/O will be done synchronously
@@ -58,6 +74,24 @@ pub enum MountOption {
 }
 
 impl MountOption {
+    /// Parse a comma-separated list of options as given to `mount -o`, e.g.
+    /// "allow_other,ro,fsname=myfs". Empty entries are ignored. Options which fuser
+    /// doesn't know fail with `ErrorKind::InvalidInput`, pass them as `MountOption::CUSTOM`
+    /// instead.
+    pub fn parse_options(options: &str) -> io::Result<Vec<MountOption>> {
+        options
+            .split(',')
+            .filter(|option| !option.is_empty())
+            .map(|option| match MountOption::from_str(option) {
+                MountOption::CUSTOM(option) => Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown or invalid mount option '{}'", option),
+                )),
+                option => Ok(option),
+            })
+            .collect()
+    }
+
     pub(crate) fn from_str(s: &str) -> MountOption {
         match s {
             "auto_unmount" => MountOption::AutoUnmount,
@@ -74,35 +108,133 @@ impl MountOption {
             "noexec" => MountOption::NoExec,
             "atime" => MountOption::Atime,
             "noatime" => MountOption::NoAtime,
+            "diratime" => MountOption::DirAtime,
+            "nodiratime" => MountOption::NoDirAtime,
+            "relatime" => MountOption::RelAtime,
+            "norelatime" => MountOption::NoRelAtime,
+            "strictatime" => MountOption::StrictAtime,
             "dirsync" => MountOption::DirSync,
             "sync" => MountOption::Sync,
             "async" => MountOption::Async,
             x if x.starts_with("fsname=") => MountOption::FSName(x[7..].into()),
             x if x.starts_with("subtype=") => MountOption::Subtype(x[8..].into()),
+            x if x.starts_with("max_read=") => match x[9..].parse() {
+                Ok(max_read) => MountOption::MaxRead(max_read),
+                Err(_) => MountOption::CUSTOM(x.into()),
+            },
             x => MountOption::CUSTOM(x.into()),
         }
     }
 }
 
+/// Validate mount options before invoking the mount helper, to report mistakes with a more
+/// descriptive error than the mount helper would
+pub(crate) fn check_mount_options(options: &[MountOption]) -> io::Result<()> {
+    check_option_conflicts(options)?;
+    if options.contains(&MountOption::MaxRead(0)) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid mount option 'max_read=0'",
+        ));
+    }
+    check_user_allow_other(options)
+}
+
 pub fn check_option_conflicts(options: &[MountOption]) -> Result<(), io::Error> {
-    let mut options_set = HashSet::new();
-    options_set.extend(options.iter().cloned());
-    let conflicting: HashSet<MountOption> = options.iter().map(conflicts_with).flatten().collect();
-    let intersection: Vec<MountOption> = conflicting.intersection(&options_set).cloned().collect();
-    if !intersection.is_empty() {
+    let mut conflicts = vec![];
+    for (i, option) in options.iter().enumerate() {
+        for other in conflicts_with(option) {
+            if options[i + 1..].contains(&other) {
+                conflicts.push(format!(
+                    "'{}' and '{}'",
+                    option_name(option),
+                    option_name(&other)
+                ));
+            }
+        }
+    }
+    if !conflicts.is_empty() {
         Err(io::Error::new(
             ErrorKind::InvalidInput,
-            format!("Conflicting mount options found: {:?}", intersection),
+            format!("Conflicting mount options found: {}", conflicts.join(", ")),
         ))
     } else {
         Ok(())
     }
 }
 
+/// Path of the fuse configuration file read by fusermount
+#[cfg(target_os = "linux")]
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// Users other than root may only mount with allow_other (which is also used to implement
+/// allow_root and auto_unmount) if 'user_allow_other' is set in /etc/fuse.conf. Otherwise
+/// fusermount refuses to mount with a message that is easy to miss.
+#[cfg(target_os = "linux")]
+fn check_user_allow_other(options: &[MountOption]) -> io::Result<()> {
+    let option = match options.iter().find(|o| needs_allow_other(o)) {
+        Some(option) => option,
+        None => return Ok(()),
+    };
+    if unsafe { libc::getuid() } == 0 {
+        return Ok(());
+    }
+    let allowed = match std::fs::read_to_string(FUSE_CONF) {
+        Ok(conf) => fuse_conf_allows_other(&conf),
+        Err(err) if err.kind() == ErrorKind::NotFound => false,
+        // Let fusermount decide if the configuration can't be read
+        Err(_) => true,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Mount option '{}' requires 'user_allow_other' to be set in {} when mounting \
+                 as a user other than root",
+                option_name(option),
+                FUSE_CONF
+            ),
+        ))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_user_allow_other(_options: &[MountOption]) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns true if the option makes fuser mount with allow_other
+#[cfg(target_os = "linux")]
+fn needs_allow_other(option: &MountOption) -> bool {
+    matches!(
+        option,
+        MountOption::AllowOther | MountOption::AllowRoot | MountOption::AutoUnmount
+    )
+}
+
+/// Returns true if the given contents of fuse.conf contain the user_allow_other option
+#[cfg(target_os = "linux")]
+fn fuse_conf_allows_other(conf: &str) -> bool {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .any(|line| line == "user_allow_other")
+}
+
+/// Name of the option as given by the user, for error messages
+fn option_name(option: &MountOption) -> String {
+    match option {
+        MountOption::AllowRoot => "allow_root".to_string(),
+        _ => option_to_string(option),
+    }
+}
+
 fn conflicts_with(option: &MountOption) -> Vec<MountOption> {
     match option {
         MountOption::FSName(_) => vec![],
         MountOption::Subtype(_) => vec![],
+        MountOption::MaxRead(_) => vec![],
         MountOption::CUSTOM(_) => vec![],
         MountOption::AllowOther => vec![MountOption::AllowRoot],
         MountOption::AllowRoot => vec![MountOption::AllowOther],
@@ -117,7 +249,20 @@ fn conflicts_with(option: &MountOption) -> Vec<MountOption> {
         MountOption::Exec => vec![MountOption::NoExec],
         MountOption::NoExec => vec![MountOption::Exec],
         MountOption::Atime => vec![MountOption::NoAtime],
-        MountOption::NoAtime => vec![MountOption::Atime],
+        MountOption::NoAtime => vec![
+            MountOption::Atime,
+            MountOption::RelAtime,
+            MountOption::StrictAtime,
+        ],
+        MountOption::DirAtime => vec![MountOption::NoDirAtime],
+        MountOption::NoDirAtime => vec![MountOption::DirAtime],
+        MountOption::RelAtime => vec![
+            MountOption::NoAtime,
+            MountOption::NoRelAtime,
+            MountOption::StrictAtime,
+        ],
+        MountOption::NoRelAtime => vec![MountOption::RelAtime],
+        MountOption::StrictAtime => vec![MountOption::NoAtime, MountOption::RelAtime],
         MountOption::DirSync => vec![],
         MountOption::Sync => vec![MountOption::Async],
         MountOption::Async => vec![MountOption::Sync],
@@ -129,6 +274,7 @@ pub fn option_to_string(option: &MountOption) -> String {
     match option {
         MountOption::FSName(name) => format!("fsname={}", name),
         MountOption::Subtype(subtype) => format!("subtype={}", subtype),
+        MountOption::MaxRead(max_read) => format!("max_read={}", max_read),
         MountOption::CUSTOM(value) => value.to_string(),
         MountOption::AutoUnmount => "auto_unmount".to_string(),
         MountOption::AllowOther => "allow_other".to_string(),
@@ -146,6 +292,11 @@ pub fn option_to_string(option: &MountOption) -> String {
         MountOption::NoExec => "noexec".to_string(),
         MountOption::Atime => "atime".to_string(),
         MountOption::NoAtime => "noatime".to_string(),
+        MountOption::DirAtime => "diratime".to_string(),
+        MountOption::NoDirAtime => "nodiratime".to_string(),
+        MountOption::RelAtime => "relatime".to_string(),
+        MountOption::NoRelAtime => "norelatime".to_string(),
+        MountOption::StrictAtime => "strictatime".to_string(),
         MountOption::DirSync => "dirsync".to_string(),
         MountOption::Sync => "sync".to_string(),
         MountOption::Async => "async".to_string(),
@@ -188,6 +339,38 @@ mod test {
     fn option_checking() {
         assert!(check_option_conflicts(&[MountOption::Suid, MountOption::NoSuid]).is_err());
         assert!(check_option_conflicts(&[MountOption::Suid, MountOption::NoExec]).is_ok());
+        assert!(check_option_conflicts(&[MountOption::NoAtime, MountOption::RelAtime]).is_err());
+        assert!(check_option_conflicts(&[MountOption::StrictAtime, MountOption::NoAtime]).is_err());
+        assert!(check_option_conflicts(&[MountOption::RelAtime, MountOption::NoDirAtime]).is_ok());
+        assert!(check_mount_options(&[MountOption::MaxRead(0)]).is_err());
+        assert!(check_mount_options(&[MountOption::MaxRead(4096)]).is_ok());
+    }
+
+    #[test]
+    fn option_conflict_message() {
+        let err =
+            check_option_conflicts(&[MountOption::RO, MountOption::AllowRoot, MountOption::RW])
+                .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Conflicting mount options found: 'ro' and 'rw'"
+        );
+        let err =
+            check_option_conflicts(&[MountOption::AllowOther, MountOption::AllowRoot]).unwrap_err();
+        assert!(err.to_string().contains("'allow_other' and 'allow_root'"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fuse_conf_parsing() {
+        assert!(fuse_conf_allows_other("user_allow_other\n"));
+        assert!(fuse_conf_allows_other(
+            "# mount_max = 1000\n  user_allow_other  # allow it\n"
+        ));
+        assert!(!fuse_conf_allows_other("#user_allow_other\n"));
+        assert!(!fuse_conf_allows_other("mount_max = 1000\n"));
+        assert!(!fuse_conf_allows_other(""));
     }
     #[test]
     fn option_round_trip() {
@@ -195,6 +378,7 @@ mod test {
         for x in [
             FSName("Blah".to_owned()),
             Subtype("Bloo".to_owned()),
+            MaxRead(131072),
             CUSTOM("bongos".to_owned()),
             AllowOther,
             AutoUnmount,
@@ -209,6 +393,11 @@ mod test {
             NoExec,
             Atime,
             NoAtime,
+            DirAtime,
+            NoDirAtime,
+            RelAtime,
+            NoRelAtime,
+            StrictAtime,
             DirSync,
             Sync,
             Async,
@@ -219,6 +408,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_option_string() {
+        use super::MountOption::*;
+
+        assert_eq!(MountOption::parse_options("").unwrap(), []);
+        assert_eq!(
+            MountOption::parse_options("allow_other,ro,,fsname=myfs,max_read=4096").unwrap(),
+            [AllowOther, RO, FSName("myfs".to_owned()), MaxRead(4096)]
+        );
+        let err = MountOption::parse_options("ro,bogus").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Unknown or invalid mount option 'bogus'");
+        assert!(MountOption::parse_options("max_read=lots").is_err());
+    }
+
     #[test]
     fn test_parse_options() {
         use super::MountOption::*;
@@ -0,0 +1,143 @@
+//! Filesystem notifications
+//!
+//! A notifier can be used to send unsolicited notifications to the kernel driver, e.g. to
+//! invalidate cached data of an inode after it was changed by a backend. Notifications are not
+//! tied to a request and can be sent from any thread while the session is running.
+
+#[cfg(feature = "abi-7-11")]
+use std::{convert::TryInto, io, io::IoSlice, mem::size_of};
+#[cfg(feature = "abi-7-12")]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+#[cfg(feature = "abi-7-11")]
+use zerocopy::AsBytes;
+
+#[cfg(feature = "abi-7-11")]
+use crate::channel::ChannelSender;
+use crate::channel::WeakChannelSender;
+#[cfg(feature = "abi-7-11")]
+use crate::ll::fuse_abi as abi;
+#[cfg(feature = "abi-7-11")]
+use crate::reply::ReplySender;
+
+/// A handle to send notifications to the kernel driver
+///
+/// The notifier only holds a weak reference to the session's channel. Once the session has
+/// ended, all notifications fail with `EBADF` and `is_alive()` returns false, so background
+/// threads sending notifications can detect when to stop.
+#[derive(Clone, Debug)]
+pub struct Notifier(WeakChannelSender);
+
+impl Notifier {
+    pub(crate) fn new(sender: WeakChannelSender) -> Self {
+        Self(sender)
+    }
+
+    /// Returns true if the session this notifier belongs to is still running
+    pub fn is_alive(&self) -> bool {
+        self.0.is_alive()
+    }
+
+    /// Invalidate the kernel cache for a given inode (metadata and data in the
+    /// given range). A negative `offset` invalidates only the attributes, a `len`
+    /// of 0 invalidates all data from `offset` to the end of the file.
+    #[cfg(feature = "abi-7-12")]
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        let r = abi::fuse_notify_inval_inode_out {
+            ino,
+            off: offset,
+            len,
+        };
+        self.send(
+            abi::fuse_notify_code::FUSE_NOTIFY_INVAL_INODE,
+            &[IoSlice::new(r.as_bytes())],
+        )
+    }
+
+    /// Invalidate the kernel cache for a given directory entry
+    #[cfg(feature = "abi-7-12")]
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        let r = abi::fuse_notify_inval_entry_out {
+            parent,
+            namelen: name
+                .len()
+                .try_into()
+                .map_err(|_| io::Error::from_raw_os_error(libc::ENAMETOOLONG))?,
+            padding: 0,
+        };
+        // The kernel expects the name to be NUL terminated
+        self.send(
+            abi::fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY,
+            &[
+                IoSlice::new(r.as_bytes()),
+                IoSlice::new(name.as_bytes()),
+                IoSlice::new(&[0]),
+            ],
+        )
+    }
+
+    /// Wake up the waiters of a file after its readiness changed, with the kernel handle
+    /// passed to `Filesystem::poll()` along with FUSE_POLL_SCHEDULE_NOTIFY
+    #[cfg(feature = "abi-7-11")]
+    pub fn poll(&self, kh: u64) -> io::Result<()> {
+        let r = abi::fuse_notify_poll_wakeup_out { kh };
+        self.send(
+            abi::fuse_notify_code::FUSE_POLL,
+            &[IoSlice::new(r.as_bytes())],
+        )
+    }
+
+    /// Returns a sender for the session's channel, or `EBADF` if the session has ended
+    #[cfg(feature = "abi-7-11")]
+    fn sender(&self) -> io::Result<ChannelSender> {
+        self.0
+            .upgrade()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn send(&self, code: abi::fuse_notify_code, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let sender = self.sender()?;
+        let datalen: usize = data.iter().map(|d| d.len()).sum();
+        // Notifications use a unique id of 0 and pass the notification code in the error field
+        let header = abi::fuse_out_header {
+            unique: 0,
+            error: code as i32,
+            len: (size_of::<abi::fuse_out_header>() + datalen)
+                .try_into()
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?,
+        };
+        let mut v = vec![IoSlice::new(header.as_bytes())];
+        v.extend_from_slice(data);
+        sender.send(&v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::channel::Channel;
+
+    use super::Notifier;
+
+    #[test]
+    fn notifier_dies_with_channel() {
+        let ch = Channel::new(Arc::new(tempfile::tempfile().unwrap()));
+        let notifier = Notifier::new(ch.weak_sender());
+        assert!(notifier.is_alive());
+        drop(ch);
+        assert!(!notifier.is_alive());
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    #[test]
+    fn inval_inode_after_session_end() {
+        let ch = Channel::new(Arc::new(tempfile::tempfile().unwrap()));
+        let notifier = Notifier::new(ch.weak_sender()).clone();
+        notifier.inval_inode(1, 0, 0).unwrap();
+        drop(ch);
+        let err = notifier.inval_inode(1, 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+}
@@ -0,0 +1,862 @@
+//! Permission checking in userspace
+//!
+//! The kernel checks file permissions itself if the filesystem is mounted with the
+//! `default_permissions` option. If that is not possible, e.g. because the mount setup
+//! doesn't allow it, `PermissionChecking` can wrap a filesystem to enforce the standard Unix
+//! permission checks before requests are passed on to it.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::time::SystemTime;
+
+use libc::{c_int, EACCES, EIO, ENOENT, EPERM, R_OK, W_OK, X_OK};
+
+#[cfg(feature = "abi-7-31")]
+use crate::fuse_removemapping_one;
+use crate::ll::fuse_abi as abi;
+use crate::reply::{parse_reply, ReplyCapture};
+#[cfg(feature = "abi-7-11")]
+use crate::ReplyPoll;
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+use crate::{
+    AccessMode, Filesystem, KernelConfig, OpenFlags, Ops, Reply, ReplyAttr, ReplyBmap, ReplyCreate,
+    ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock,
+    ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+
+/// Owner and mode of a file, as needed for permission checks
+#[derive(Debug)]
+struct Owner {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl From<&abi::fuse_attr> for Owner {
+    fn from(attr: &abi::fuse_attr) -> Self {
+        Owner {
+            mode: attr.mode,
+            uid: attr.uid,
+            gid: attr.gid,
+        }
+    }
+}
+
+// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
+// But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
+#[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+/// Check whether a user may access a file with the given access mask (a combination of
+/// R_OK, W_OK and X_OK). Root may read and write any file, but only execute files with at
+/// least one execute bit set.
+fn check_access(owner: &Owner, uid: u32, gid: u32, mask: c_int) -> Result<(), c_int> {
+    let mask = mask as u32 & 0o7;
+    if uid == 0 {
+        let is_dir = owner.mode & libc::S_IFMT as u32 == libc::S_IFDIR as u32;
+        return if mask & X_OK as u32 == 0 || is_dir || owner.mode & 0o111 != 0 {
+            Ok(())
+        } else {
+            Err(EACCES)
+        };
+    }
+    let granted = if uid == owner.uid {
+        owner.mode >> 6
+    } else if gid == owner.gid {
+        owner.mode >> 3
+    } else {
+        owner.mode
+    } & 0o7;
+    if mask & !granted == 0 {
+        Ok(())
+    } else {
+        Err(EACCES)
+    }
+}
+
+/// Returns the access mask needed to open a file with the given flags
+fn open_mask(flags: i32) -> c_int {
+    let flags = OpenFlags(flags);
+    let mask = match flags.access_mode() {
+        AccessMode::ReadOnly => R_OK,
+        AccessMode::WriteOnly => W_OK,
+        AccessMode::ReadWrite => R_OK | W_OK,
+    };
+    if flags.is_truncate() {
+        mask | W_OK
+    } else {
+        mask
+    }
+}
+
+/// A filesystem wrapper that enforces standard Unix permissions in userspace
+///
+/// Before passing a request on to the wrapped filesystem, the mode and owner of the affected
+/// files are looked up using its `getattr()` and `lookup()` methods and checked against the
+/// uid and gid of the request:
+///
+/// * lookup requires execute permission on the directory
+/// * creating (mknod, mkdir, symlink, link, create, tmpfile) and removing (unlink, rmdir, rename)
+///   entries requires write and execute permission on the directory. In directories with the
+///   sticky bit set, only the owner of the entry or the directory may remove or replace it
+/// * open and opendir require read and/or write permission, depending on the access mode, and
+///   write permission to truncate the file
+/// * changing the mode or setting specific times requires ownership, changing the owner
+///   requires root. The owner may change the group to its own group. Setting the times to
+///   the current time or truncating a file by path requires write permission
+/// * access is answered by the wrapper itself
+///
+/// Root (uid 0) bypasses all checks, except that files can only be executed if at least one
+/// execute bit is set. Supplementary groups of the requesting process are unknown to the
+/// filesystem, so only the primary gid is checked against the group of a file.
+///
+/// The wrapped filesystem must eventually reply to the `getattr()` and `lookup()` calls made
+/// by the wrapper; replying from another thread is fine, the wrapper blocks until the reply
+/// arrives. Entries looked up by the wrapper are forgotten again right away. Zero-message
+/// opens are disabled for the wrapped filesystem, since the kernel wouldn't send the opens to
+/// check anymore. The wrapped filesystem must therefore not reply ENOSYS to open or opendir.
+#[derive(Debug)]
+pub struct PermissionChecking<FS: Filesystem> {
+    inner: FS,
+}
+
+impl<FS: Filesystem> PermissionChecking<FS> {
+    /// Wrap the given filesystem
+    pub fn new(inner: FS) -> Self {
+        PermissionChecking { inner }
+    }
+
+    /// Returns a reference to the wrapped filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped filesystem
+    pub fn inner_mut(&mut self) -> &mut FS {
+        &mut self.inner
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Get owner and mode of the given inode from the wrapped filesystem
+    fn owner(&mut self, req: &Request<'_>, ino: u64) -> Result<Owner, c_int> {
+        let (tx, rx) = channel();
+        self.inner
+            .getattr(req, ino, None, Reply::new(req.unique(), ReplyCapture(tx)));
+        let buf = rx.recv().map_err(|_| EIO)?;
+        parse_reply::<abi::fuse_attr_out>(&buf).map(|out| Owner::from(&out.attr))
+    }
+
+    /// Get owner and mode of the given directory entry from the wrapped filesystem, or None
+    /// if it doesn't exist
+    fn entry_owner(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<Option<Owner>, c_int> {
+        let (tx, rx) = channel();
+        self.inner.lookup(
+            req,
+            parent,
+            name,
+            Reply::new(req.unique(), ReplyCapture(tx)),
+        );
+        let buf = rx.recv().map_err(|_| EIO)?;
+        match parse_reply::<abi::fuse_entry_out>(&buf) {
+            Ok(out) => {
+                // The kernel doesn't know about this lookup, so balance the lookup count
+                if out.nodeid != 0 {
+                    self.inner.forget(req, out.nodeid, 1);
+                }
+                Ok(Some(Owner::from(&out.attr)))
+            }
+            Err(ENOENT) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check access to the given inode
+    fn check(&mut self, req: &Request<'_>, ino: u64, mask: c_int) -> Result<(), c_int> {
+        let owner = self.owner(req, ino)?;
+        check_access(&owner, req.uid(), req.gid(), mask)
+    }
+
+    /// Check whether the given entry may be removed or replaced
+    #[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+    fn check_remove(&mut self, req: &Request<'_>, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let dir = self.owner(req, parent)?;
+        check_access(&dir, req.uid(), req.gid(), W_OK | X_OK)?;
+        if dir.mode & libc::S_ISVTX as u32 == 0 || req.uid() == 0 || req.uid() == dir.uid {
+            return Ok(());
+        }
+        match self.entry_owner(req, parent, name)? {
+            Some(entry) if entry.uid != req.uid() => Err(EPERM),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether the given attributes may be changed
+    #[allow(clippy::too_many_arguments)]
+    fn check_setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        fh: Option<u64>,
+    ) -> Result<(), c_int> {
+        let owner = self.owner(req, ino)?;
+        let is_root = req.uid() == 0;
+        let is_owner = is_root || req.uid() == owner.uid;
+        if mode.is_some() && !is_owner {
+            return Err(EPERM);
+        }
+        if matches!(uid, Some(uid) if uid != owner.uid) && !is_root {
+            return Err(EPERM);
+        }
+        // The owner may change the group to its own group
+        let may_chgrp = |gid| is_root || (req.uid() == owner.uid && gid == req.gid());
+        if matches!(gid, Some(gid) if gid != owner.gid && !may_chgrp(gid)) {
+            return Err(EPERM);
+        }
+        // Truncating an open file was already checked when opening it
+        if size.is_some() && fh.is_none() {
+            check_access(&owner, req.uid(), req.gid(), W_OK)?;
+        }
+        let times = [atime, mtime];
+        if !is_owner {
+            if times
+                .iter()
+                .any(|t| matches!(t, Some(TimeOrNow::SpecificTime(_))))
+            {
+                return Err(EPERM);
+            }
+            if times.iter().any(|t| matches!(t, Some(TimeOrNow::Now))) {
+                check_access(&owner, req.uid(), req.gid(), W_OK)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+impl<FS: Filesystem> Filesystem for PermissionChecking<FS> {
+    fn supported_ops(&self) -> Ops {
+        // Access requests are answered by the permission checks
+        self.inner.supported_ops() | Ops::ACCESS
+    }
+
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.inner.init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.inner.destroy()
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.check(req, parent, X_OK) {
+            Ok(()) => self.inner.lookup(req, parent, name, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn lookup_inode(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEntry) {
+        // Resolving a file handle doesn't search a directory
+        self.inner.lookup_inode(req, ino, reply)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inner.forget(req, ino, nlookup)
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[abi::fuse_forget_one]) {
+        self.inner.batch_forget(req, nodes)
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        self.inner.getattr(req, ino, fh, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.check_setattr(req, ino, mode, uid, gid, size, atime, mtime, fh) {
+            Ok(()) => self.inner.setattr(
+                req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+                flags, reply,
+            ),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.inner.readlink(req, ino, reply)
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.check(req, parent, W_OK | X_OK) {
+            Ok(()) => self
+                .inner
+                .mknod(req, parent, name, mode, umask, rdev, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.check(req, parent, W_OK | X_OK) {
+            Ok(()) => self.inner.mkdir(req, parent, name, mode, umask, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.check_remove(req, parent, name) {
+            Ok(()) => self.inner.unlink(req, parent, name, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.check_remove(req, parent, name) {
+            Ok(()) => self.inner.rmdir(req, parent, name, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.check(req, parent, W_OK | X_OK) {
+            Ok(()) => self.inner.symlink(req, parent, name, link, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // The target is replaced (or exchanged), so it must be removable as well
+        let result = self
+            .check_remove(req, parent, name)
+            .and_then(|()| self.check_remove(req, newparent, newname));
+        match result {
+            Ok(()) => self
+                .inner
+                .rename(req, parent, name, newparent, newname, flags, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.check(req, newparent, W_OK | X_OK) {
+            Ok(()) => self.inner.link(req, ino, newparent, newname, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.check(req, ino, open_mask(flags)) {
+            // Zero-message opens would skip the permission check of all future opens
+            Ok(()) => self
+                .inner
+                .open(req, ino, flags, reply.with_no_open_support(false)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.inner
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.inner.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.inner.flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.inner.fsync(req, ino, fh, datasync, reply)
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.check(req, ino, open_mask(flags)) {
+            Ok(()) => self
+                .inner
+                .opendir(req, ino, flags, reply.with_no_open_support(false)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        self.inner.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        self.inner.readdirplus(req, ino, fh, offset, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.inner.releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.inner.fsyncdir(req, ino, fh, datasync, reply)
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.inner.statfs(req, ino, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.inner.getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.inner.listxattr(req, ino, size, reply)
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.removexattr(req, ino, name, reply)
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        // Don't pass the request on. If the wrapped filesystem doesn't implement access, the
+        // kernel would stop sending access requests.
+        match self.check(req, ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.check(req, parent, W_OK | X_OK) {
+            Ok(()) => self
+                .inner
+                .create(req, parent, name, mode, umask, flags, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.inner
+            .getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.inner.bmap(req, ino, blocksize, idx, reply)
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.inner
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn poll(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        self.inner.poll(req, ino, fh, kh, events, flags, reply)
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .fallocate(req, ino, fh, offset, length, mode, reply)
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.inner.lseek(req, ino, fh, offset, whence, reply)
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.inner.copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+        )
+    }
+
+    #[cfg(feature = "abi-7-31")]
+    fn setup_mapping(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .setup_mapping(req, ino, fh, foffset, len, flags, moffset, reply)
+    }
+
+    #[cfg(feature = "abi-7-31")]
+    fn remove_mapping(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mappings: &[fuse_removemapping_one],
+        reply: ReplyEmpty,
+    ) {
+        self.inner.remove_mapping(req, ino, mappings, reply)
+    }
+
+    fn syncfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyEmpty) {
+        self.inner.syncfs(req, ino, reply)
+    }
+
+    fn tmpfile(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.check(req, parent, W_OK | X_OK) {
+            Ok(()) => self.inner.tmpfile(req, parent, mode, umask, flags, reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.setvolname(req, name, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        options: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .exchange(req, parent, name, newparent, newname, options, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        self.inner.getxtimes(req, ino, reply)
+    }
+
+    #[cfg(target_os = "android")]
+    fn canonical_path(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.inner.canonical_path(req, ino, reply)
+    }
+}
+
+#[cfg(test)]
+#[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use libc::{EACCES, ENOENT, R_OK, W_OK, X_OK};
+
+    use super::{check_access, open_mask, Owner};
+    use crate::ll::fuse_abi as abi;
+    use crate::reply::{parse_reply, ReplyCapture};
+    use crate::{FileAttrBuilder, FileType, Reply, ReplyAttr};
+
+    const FILE: Owner = Owner {
+        mode: libc::S_IFREG as u32 | 0o640,
+        uid: 1000,
+        gid: 100,
+    };
+
+    #[test]
+    fn access_bits() {
+        assert_eq!(check_access(&FILE, 1000, 100, R_OK | W_OK), Ok(()));
+        assert_eq!(check_access(&FILE, 1000, 100, X_OK), Err(EACCES));
+        assert_eq!(check_access(&FILE, 1001, 100, R_OK), Ok(()));
+        assert_eq!(check_access(&FILE, 1001, 100, W_OK), Err(EACCES));
+        assert_eq!(check_access(&FILE, 1001, 101, R_OK), Err(EACCES));
+        // Owner bits apply to the owner even if the group bits would grant more
+        let owner = Owner {
+            mode: 0o070,
+            ..FILE
+        };
+        assert_eq!(check_access(&owner, 1000, 100, R_OK), Err(EACCES));
+        assert_eq!(check_access(&owner, 1001, 100, R_OK), Ok(()));
+    }
+
+    #[test]
+    fn root_access() {
+        assert_eq!(check_access(&FILE, 0, 0, R_OK | W_OK), Ok(()));
+        assert_eq!(check_access(&FILE, 0, 0, X_OK), Err(EACCES));
+        let executable = Owner {
+            mode: 0o001,
+            ..FILE
+        };
+        assert_eq!(check_access(&executable, 0, 0, X_OK), Ok(()));
+    }
+
+    #[test]
+    fn open_flags() {
+        assert_eq!(open_mask(libc::O_RDONLY), R_OK);
+        assert_eq!(open_mask(libc::O_WRONLY), W_OK);
+        assert_eq!(open_mask(libc::O_RDWR), R_OK | W_OK);
+        assert_eq!(open_mask(libc::O_RDONLY | libc::O_TRUNC), R_OK | W_OK);
+    }
+
+    #[test]
+    fn captured_attr_reply() {
+        let (tx, rx) = channel();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, ReplyCapture(tx));
+        let attr = FileAttrBuilder::new(2, FileType::Directory)
+            .perm(0o1777)
+            .uid(1000)
+            .gid(100)
+            .build();
+        reply.attr(&Duration::from_secs(1), &attr);
+        let buf = rx.recv().unwrap();
+        let out = parse_reply::<abi::fuse_attr_out>(&buf).unwrap();
+        assert_eq!(out.attr.mode, libc::S_IFDIR as u32 | 0o1777);
+        assert_eq!((out.attr.uid, out.attr.gid), (1000, 100));
+
+        let (tx, rx) = channel();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, ReplyCapture(tx));
+        reply.error(ENOENT);
+        let buf = rx.recv().unwrap();
+        assert_eq!(parse_reply::<abi::fuse_attr_out>(&buf).unwrap_err(), ENOENT);
+    }
+}
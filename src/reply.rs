@@ -4,7 +4,14 @@
 //! result of an operation. The reply can optionally be sent to another thread to asynchronously
 //! work on an operation and provide the result later. Also it allows replying with a block of
 //! data without cloning the data. A reply *must always* be used (by calling either ok() or
-//! error() exactly once).
+//! error() exactly once). All reply methods consume the reply, so replying twice to the same
+//! request is a compile time error. Dropping an unused reply sends an EIO error.
+//!
+//! Every reply type is `Send + 'static`: it owns the unique id of its request and a handle to
+//! the fd of the session, so it can outlive the filesystem method it was passed to. The
+//! session loop goes on dispatching other requests meanwhile, and replies may be sent in any
+//! order; the kernel matches them to their requests by the unique id. If the session has
+//! ended by the time a reply is sent, sending fails and the error is logged.
 
 use crate::ll::{
     self,
@@ -15,18 +22,28 @@ use crate::ll::{
     reply::{DirEntList, DirEntOffset, DirEntry},
     INodeNo,
 };
-use libc::c_int;
-use log::{error, warn};
+use libc::{c_int, EIO, ENOSYS};
+use log::{debug, error, warn};
+use std::cell::Cell;
 use std::convert::AsRef;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::hash::Hash;
 use std::io::IoSlice;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
 use std::time::Duration;
+use zerocopy::{AsBytes, FromBytes, LayoutVerified};
 
 #[cfg(target_os = "macos")]
 use std::time::SystemTime;
 
-use crate::{FileAttr, FileType};
+use crate::ll::fuse_abi as abi;
+use crate::session::DEFAULT_TTL;
+use crate::{FileAttr, FileType, InodeTable, FUSE_ROOT_ID};
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
@@ -74,8 +91,14 @@ impl ReplyRaw {
         assert!(self.sender.is_some());
         let sender = self.sender.take().unwrap();
         let res = response.with_iovec(self.unique, |iov| sender.send(iov));
-        if let Err(err) = res {
-            error!("Failed to send FUSE reply: {}", err);
+        match res {
+            Ok(()) => {}
+            // The kernel already abandoned the request, e.g. because it was interrupted
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => debug!(
+                "Request {} no longer exists, reply was dropped",
+                self.unique.0
+            ),
+            Err(err) => error!("Failed to send FUSE reply: {}", err),
         }
     }
     fn send_ll(mut self, response: &ll::Response) {
@@ -89,18 +112,163 @@ impl ReplyRaw {
     }
 }
 
+thread_local! {
+    /// Error to reply for replies dropped while a filesystem method panics, see
+    /// `Session::set_panic_errno()`
+    static PANIC_ERRNO: Cell<c_int> = const { Cell::new(libc::EIO) };
+}
+
+/// Call f, replying the given error for replies dropped by a panic of f
+pub(crate) fn with_panic_errno<T, F: FnOnce() -> T>(errno: c_int, f: F) -> T {
+    /// Restores the previous error, also when unwinding
+    struct Restore(c_int);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            PANIC_ERRNO.with(|e| e.set(self.0));
+        }
+    }
+    let _restore = Restore(PANIC_ERRNO.with(|e| e.replace(errno)));
+    f()
+}
+
 impl Drop for ReplyRaw {
     fn drop(&mut self) {
         if self.sender.is_some() {
+            let errno = if thread::panicking() {
+                PANIC_ERRNO.with(Cell::get)
+            } else {
+                libc::EIO
+            };
             warn!(
-                "Reply not sent for operation {}, replying with I/O error",
-                self.unique.0
+                "Reply not sent for operation {}, replying with error {}",
+                self.unique.0, errno
             );
-            self.send_ll_mut(&ll::Response::new_error(ll::Errno::EIO));
+            self.send_ll_mut(&ll::Response::new_error(ll::Errno::from_i32(errno)));
+        }
+    }
+}
+
+/// Receiver of the replies captured by `ReplyCapture`
+pub(crate) trait ReplySink: Send + 'static {
+    /// Called with the reply, copied to an aligned buffer
+    fn captured(&self, reply: Vec<u64>);
+}
+
+impl ReplySink for Sender<Vec<u64>> {
+    fn captured(&self, reply: Vec<u64>) {
+        // The wrapper may have given up waiting, there's nobody to report an error to then
+        let _ = self.send(reply);
+    }
+}
+
+/// Reply sender which passes the reply of a filesystem method back to the session or a
+/// wrapper instead of the kernel. The reply is copied to an aligned buffer, so it can be
+/// parsed again with `parse_reply()`.
+pub(crate) struct ReplyCapture<S>(pub(crate) S);
+
+impl<S: ReplySink> ReplySender for ReplyCapture<S> {
+    fn send(&self, data: &[IoSlice<'_>]) -> std::io::Result<()> {
+        let len: usize = data.iter().map(|d| d.len()).sum();
+        let mut buf = vec![0u64; len / 8 + 1];
+        let mut pos = 0;
+        for d in data {
+            buf.as_bytes_mut()[pos..pos + d.len()].copy_from_slice(d);
+            pos += d.len();
+        }
+        self.0.captured(buf);
+        Ok(())
+    }
+}
+
+/// Parse a captured reply, returning the error code of error replies
+pub(crate) fn parse_reply<T: FromBytes>(buf: &[u64]) -> Result<&T, c_int> {
+    let (header, data) =
+        LayoutVerified::<_, abi::fuse_out_header>::new_from_prefix(buf.as_bytes()).ok_or(EIO)?;
+    if header.error != 0 {
+        return Err(-header.error);
+    }
+    LayoutVerified::<_, T>::new_from_prefix(data)
+        .map(|(data, _)| data.into_ref())
+        .ok_or(EIO)
+}
+
+/// Replace the sender of a reply, for filesystem wrappers which observe the replies of the
+/// wrapped filesystem without changing them
+pub(crate) trait WrapSender {
+    fn wrap_sender<F: FnOnce(Box<dyn ReplySender>) -> Box<dyn ReplySender>>(&mut self, f: F);
+}
+
+impl WrapSender for ReplyRaw {
+    fn wrap_sender<F: FnOnce(Box<dyn ReplySender>) -> Box<dyn ReplySender>>(&mut self, f: F) {
+        if let Some(sender) = self.sender.take() {
+            self.sender = Some(f(sender));
         }
     }
 }
 
+impl ReplySender for Box<dyn ReplySender> {
+    fn send(&self, data: &[IoSlice<'_>]) -> std::io::Result<()> {
+        (**self).send(data)
+    }
+}
+
+macro_rules! wrap_sender {
+    ($($reply:ty),*) => {
+        $(
+            impl WrapSender for $reply {
+                fn wrap_sender<F: FnOnce(Box<dyn ReplySender>) -> Box<dyn ReplySender>>(
+                    &mut self,
+                    f: F,
+                ) {
+                    self.reply.wrap_sender(f)
+                }
+            }
+        )*
+    };
+}
+
+wrap_sender!(
+    ReplyEmpty,
+    ReplyData,
+    ReplyEntry,
+    ReplyAttr,
+    ReplyOpen,
+    ReplyWrite,
+    ReplyStatfs,
+    ReplyCreate,
+    ReplyLock,
+    ReplyBmap,
+    ReplyIoctl,
+    ReplyDirectory,
+    ReplyDirectoryPlus,
+    ReplyXattr,
+    ReplyLseek
+);
+#[cfg(target_os = "macos")]
+wrap_sender!(ReplyXTimes);
+#[cfg(feature = "abi-7-11")]
+wrap_sender!(ReplyPoll);
+
+/// Set once a warning about a zero generation number was logged
+static ZERO_GENERATION_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Warn (once) if an entry is replied with a generation of 0 while the filesystem may be
+/// exported over NFS. This is fine as long as inode numbers are never reused, which fuser
+/// can't check.
+fn check_generation(export_support: bool, ino: u64, generation: u64) {
+    if export_support
+        && generation == 0
+        && ino != FUSE_ROOT_ID
+        && !ZERO_GENERATION_WARNED.swap(true, Ordering::Relaxed)
+    {
+        warn!(
+            "Replying with generation 0 for inode {} while FUSE_EXPORT_SUPPORT is enabled. \
+             Reused inode numbers must be assigned a new generation number",
+            ino
+        );
+    }
+}
+
 ///
 /// Empty reply
 ///
@@ -132,6 +300,10 @@ impl ReplyEmpty {
 ///
 /// Data reply
 ///
+/// The FUSE protocol doesn't allow replying with data and an error at the same time. If a
+/// read fails after some data has already been retrieved, reply either with the valid data
+/// (a short read) or with the error, but not both. Since `data()` and `error()` consume the
+/// reply, it can only be used once.
 #[derive(Debug)]
 pub struct ReplyData {
     reply: ReplyRaw,
@@ -146,11 +318,37 @@ impl Reply for ReplyData {
 }
 
 impl ReplyData {
-    /// Reply to a request with the given data
+    /// Reply to a request with the given data. Data of up to 256 bytes is copied to a reply
+    /// buffer on the stack, larger data to one allocated for this reply. Reply buffers
+    /// aren't pooled, since the allocation is small next to copying the data.
     pub fn data(self, data: &[u8]) {
         self.reply.send_ll(&ll::Response::new_data(data));
     }
 
+    /// Reply to a readlink request with the target of the symlink. The target is sent as is,
+    /// without a NUL terminator. Paths can't contain NUL bytes, a target with a NUL byte is
+    /// cut off before it (e.g. a C string with its terminator).
+    pub fn symlink<P: AsRef<Path>>(self, target: P) {
+        let target = target.as_ref().as_os_str();
+        let target = match memchr::memchr(0, target.as_bytes()) {
+            Some(len) => {
+                warn!("Symlink target {:?} contains a NUL byte", target);
+                &target.as_bytes()[..len]
+            }
+            None => target.as_bytes(),
+        };
+        self.data(target);
+    }
+
+    /// Reply to a canonical path request with the given path. Unlike a symlink target, the
+    /// path is sent NUL terminated.
+    #[cfg(target_os = "android")]
+    pub fn canonical_path<P: AsRef<Path>>(self, path: P) {
+        let mut path = path.as_ref().as_os_str().as_bytes().to_vec();
+        path.push(0);
+        self.data(&path);
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -163,19 +361,53 @@ impl ReplyData {
 #[derive(Debug)]
 pub struct ReplyEntry {
     reply: ReplyRaw,
+    export_support: bool,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
 }
 
 impl Reply for ReplyEntry {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEntry {
         ReplyEntry {
             reply: Reply::new(unique, sender),
+            export_support: false,
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
         }
     }
 }
 
 impl ReplyEntry {
-    /// Reply to a request with the given entry
+    /// Enable checking the generation number, if the filesystem may be exported over NFS
+    pub(crate) fn with_export_support(mut self, export_support: bool) -> ReplyEntry {
+        self.export_support = export_support;
+        self
+    }
+
+    /// Set the ttls used by `entry_default_ttl()`
+    pub(crate) fn with_default_ttl(
+        mut self,
+        entry_ttl: Duration,
+        attr_ttl: Duration,
+    ) -> ReplyEntry {
+        self.entry_ttl = entry_ttl;
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    /// Reply to a request with the given entry.
+    ///
+    /// The kernel caches the entry (the mapping from name to inode) and the attributes for the
+    /// given ttl. Until it expires, lookups of the name and stat calls are answered from the
+    /// cache without sending a request. `Duration::ZERO` disables caching, so the kernel
+    /// revalidates the entry on every access, which is correct for files that may change
+    /// behind the kernel's back but costs a request each time.
+    ///
+    /// If the filesystem may be exported over NFS (FUSE_EXPORT_SUPPORT), the (ino, generation)
+    /// pair must be unique over the filesystem's lifetime. So when an inode number is reused,
+    /// it must be given a new generation number.
     pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        check_generation(self.export_support, attr.ino, generation);
         self.reply.send_ll(&ll::Response::new_entry(
             ll::INodeNo(attr.ino),
             ll::Generation(generation),
@@ -185,6 +417,39 @@ impl ReplyEntry {
         ));
     }
 
+    /// Reply to a request with the given attributes, using the same ttl for the entry and
+    /// the attributes and a generation of 0
+    pub fn entry_with<A: Into<FileAttr>>(self, attr: A, ttl: Duration) {
+        self.entry(&ttl, &attr.into(), 0);
+    }
+
+    /// Reply to a request with the given attributes, using the session's default entry and
+    /// attribute ttls (see `Session::set_entry_ttl()` and `Session::set_attr_ttl()`)
+    pub fn entry_default_ttl<A: Into<FileAttr>>(self, attr: A, generation: u64) {
+        let attr = attr.into();
+        check_generation(self.export_support, attr.ino, generation);
+        self.reply.send_ll(&ll::Response::new_entry(
+            ll::INodeNo(attr.ino),
+            ll::Generation(generation),
+            &(&attr).into(),
+            self.attr_ttl,
+            self.entry_ttl,
+        ));
+    }
+
+    /// Reply to a lookup with a negative entry: the name doesn't exist, and the kernel may
+    /// cache that for the given ttl. Until it expires, lookups of the name fail with ENOENT
+    /// without sending a request, unlike replying `ENOENT`, which isn't cached. Creating the
+    /// name through this mount, or invalidating it with `Notifier::inval_entry()`, drops the
+    /// negative entry.
+    ///
+    /// Negative entries are sent as an entry with inode number 0, which the kernel accepts
+    /// since ABI 7.4 and only in reply to lookup. Replies to mknod, mkdir, symlink and link
+    /// with inode 0 fail with EIO.
+    pub fn negative(self, ttl: &Duration) {
+        self.reply.send_ll(&ll::Response::new_negative_entry(*ttl));
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -197,23 +462,39 @@ impl ReplyEntry {
 #[derive(Debug)]
 pub struct ReplyAttr {
     reply: ReplyRaw,
+    ttl: Duration,
 }
 
 impl Reply for ReplyAttr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyAttr {
         ReplyAttr {
             reply: Reply::new(unique, sender),
+            ttl: DEFAULT_TTL,
         }
     }
 }
 
 impl ReplyAttr {
-    /// Reply to a request with the given attribute
+    /// Set the ttl used by `attr_default_ttl()`
+    pub(crate) fn with_default_ttl(mut self, ttl: Duration) -> ReplyAttr {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Reply to a request with the given attribute. The kernel caches the attributes for the
+    /// given ttl, `Duration::ZERO` makes it request them again on every access.
     pub fn attr(self, ttl: &Duration, attr: &FileAttr) {
         self.reply
             .send_ll(&ll::Response::new_attr(ttl, &attr.into()));
     }
 
+    /// Reply to a request with the given attribute, using the session's default attribute
+    /// ttl (see `Session::set_attr_ttl()`)
+    pub fn attr_default_ttl<A: Into<FileAttr>>(self, attr: A) {
+        let ttl = self.ttl;
+        self.attr(&ttl, &attr.into());
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -258,18 +539,45 @@ impl ReplyXTimes {
 #[derive(Debug)]
 pub struct ReplyOpen {
     reply: ReplyRaw,
+    no_open_support: bool,
 }
 
 impl Reply for ReplyOpen {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyOpen {
         ReplyOpen {
             reply: Reply::new(unique, sender),
+            no_open_support: false,
         }
     }
 }
 
 impl ReplyOpen {
-    /// Reply to a request with the given open result
+    /// Set whether the kernel supports zero-message opens, i.e. treats ENOSYS as success and
+    /// doesn't send further open (or opendir) requests
+    pub(crate) fn with_no_open_support(mut self, no_open_support: bool) -> ReplyOpen {
+        self.no_open_support = no_open_support;
+        self
+    }
+
+    /// Reply to a request for a filesystem that doesn't implement open (or opendir). If the
+    /// kernel supports zero-message opens, reply ENOSYS so that it stops sending them,
+    /// otherwise open with a file handle of 0.
+    pub(crate) fn default_open(self) {
+        if self.no_open_support {
+            self.reply.error(ENOSYS);
+        } else {
+            self.opened(0, 0);
+        }
+    }
+
+    /// Reply to a request with the given open result. `flags` is a combination of the
+    /// `FOPEN_*` constants, e.g. `FOPEN_DIRECT_IO`, `FOPEN_KEEP_CACHE`, `FOPEN_NONSEEKABLE`
+    /// (ABI >= 7.10) or `FOPEN_STREAM` (ABI >= 7.31). For non-seekable and stream-like files
+    /// the kernel rejects `lseek()` with ESPIPE and ignores the file position.
+    ///
+    /// Passing reads and writes through to a backing file (FUSE_PASSTHROUGH and the
+    /// `backing_id` of fuse_open_out) is not supported, it needs ABI 7.40, while the newest
+    /// supported ABI is 7.31.
     pub fn opened(self, fh: u64, flags: u32) {
         self.reply
             .send_ll(&ll::Response::new_open(ll::FileHandle(fh), flags))
@@ -287,19 +595,43 @@ impl ReplyOpen {
 #[derive(Debug)]
 pub struct ReplyWrite {
     reply: ReplyRaw,
+    requested: Option<u32>,
 }
 
 impl Reply for ReplyWrite {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyWrite {
         ReplyWrite {
             reply: Reply::new(unique, sender),
+            requested: None,
         }
     }
 }
 
 impl ReplyWrite {
-    /// Reply to a request with the given open result
+    /// Set the number of bytes the kernel requested to write, to check the written size
+    pub(crate) fn with_requested(mut self, requested: u32) -> ReplyWrite {
+        self.requested = Some(requested);
+        self
+    }
+
+    /// Reply to a request with the number of bytes written.
+    ///
+    /// A short write (less than the size of the data) is passed on to the kernel as is. With
+    /// direct_io it becomes the result of the write system call, see `Filesystem::write()`.
+    /// Replying with more than the size of the data is a protocol violation, which the kernel
+    /// answers by failing the write with EIO. Such a reply is therefore logged and replaced by
+    /// an EIO error.
     pub fn written(self, size: u32) {
+        if let Some(requested) = self.requested {
+            if size > requested {
+                error!(
+                    "Replied to write of {} bytes with a larger size of {}",
+                    requested, size
+                );
+                self.reply.error(libc::EIO);
+                return;
+            }
+        }
         self.reply.send_ll(&ll::Response::new_write(size))
     }
 
@@ -326,7 +658,16 @@ impl Reply for ReplyStatfs {
 }
 
 impl ReplyStatfs {
-    /// Reply to a request with the given open result
+    /// Reply to a request with the given filesystem statistics, the fields of statvfs(3):
+    /// * blocks, bfree, bavail: total, free and for unprivileged users available blocks,
+    ///   in units of frsize (`f_blocks`, `f_bfree`, `f_bavail`)
+    /// * files, ffree: total and free inodes (`f_files`, `f_ffree`)
+    /// * bsize: preferred I/O block size (`f_bsize`)
+    /// * namelen: maximum length of a file name (`f_namemax`), which is what
+    ///   `pathconf(_PC_NAME_MAX)` returns. Programs sizing buffers by it break if it is 0,
+    ///   use 255 unless the filesystem has a different limit.
+    /// * frsize: fragment size, the unit of the block counts (`f_frsize`). The kernel passes
+    ///   it on as is, so with 0 df(1) and other tools computing sizes from it show nothing.
     #[allow(clippy::too_many_arguments)]
     pub fn statfs(
         self,
@@ -356,19 +697,29 @@ impl ReplyStatfs {
 #[derive(Debug)]
 pub struct ReplyCreate {
     reply: ReplyRaw,
+    export_support: bool,
 }
 
 impl Reply for ReplyCreate {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
         ReplyCreate {
             reply: Reply::new(unique, sender),
+            export_support: false,
         }
     }
 }
 
 impl ReplyCreate {
-    /// Reply to a request with the given entry
+    /// Enable checking the generation number, if the filesystem may be exported over NFS
+    pub(crate) fn with_export_support(mut self, export_support: bool) -> ReplyCreate {
+        self.export_support = export_support;
+        self
+    }
+
+    /// Reply to a request with the given entry. See `ReplyEntry::entry()` regarding the ttl
+    /// and the generation number.
     pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        check_generation(self.export_support, attr.ino, generation);
         self.reply.send_ll(&ll::Response::new_create(
             ttl,
             &attr.into(),
@@ -467,6 +818,43 @@ impl ReplyIoctl {
             .send_ll(&ll::Response::new_ioctl(result, &[IoSlice::new(data)]));
     }
 
+    /// Reply to FS_IOC_GETFLAGS with the given inode flags, see `IoctlCommand`
+    #[cfg(target_os = "linux")]
+    pub fn inode_flags(self, flags: u32) {
+        self.ioctl(0, &flags.to_ne_bytes());
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error(self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+///
+/// Poll Reply
+///
+#[cfg(feature = "abi-7-11")]
+#[derive(Debug)]
+pub struct ReplyPoll {
+    reply: ReplyRaw,
+}
+
+#[cfg(feature = "abi-7-11")]
+impl Reply for ReplyPoll {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyPoll {
+        ReplyPoll {
+            reply: Reply::new(unique, sender),
+        }
+    }
+}
+
+#[cfg(feature = "abi-7-11")]
+impl ReplyPoll {
+    /// Reply to a request with the poll events which are ready now, e.g. `libc::POLLIN`
+    pub fn poll(self, revents: u32) {
+        self.reply.send_ll(&ll::Response::new_poll(revents))
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -494,6 +882,13 @@ impl ReplyDirectory {
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
     /// value to request the next entries in further readdir calls
+    ///
+    /// If true is returned, the entry didn't fit and was not added: stop adding entries and
+    /// reply. The offset of an entry is the position to resume after it, the next readdir
+    /// is passed the offset of the last entry that was added, so the rejected entry must be
+    /// added again by that readdir. If not even the first entry fits, don't reply with the
+    /// empty buffer, as the kernel takes that as the end of the directory. Reply EINVAL
+    /// instead, like getdents does for a buffer which is too small.
     #[must_use]
     pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
         let name = name.as_ref();
@@ -505,6 +900,28 @@ impl ReplyDirectory {
         ))
     }
 
+    /// Add entries of a directory listing to the reply buffer, until the buffer is full.
+    /// The iterator must yield all entries of the directory, beginning with the first, in
+    /// the same order for every readdir request of an open directory handle. Each entry gets
+    /// its index plus one as offset, so `start_offset` is the offset the kernel passed to
+    /// readdir and the entries before it are skipped. Returns the offset to resume from,
+    /// which equals the number of entries once the listing is complete. If it equals
+    /// `start_offset` although entries were left, not even the first one fit (see `add()`).
+    pub fn fill_from<I: Iterator<Item = (u64, FileType, OsString)>>(
+        &mut self,
+        iter: I,
+        start_offset: i64,
+    ) -> i64 {
+        let mut offset = start_offset.max(0);
+        for (ino, kind, name) in iter.skip(offset as usize) {
+            if self.add(ino, offset + 1, kind, name) {
+                break;
+            }
+            offset += 1;
+        }
+        offset
+    }
+
     /// Reply to a request with the filled directory buffer
     pub fn ok(self) {
         self.reply.send_ll(&self.data.into());
@@ -537,6 +954,12 @@ impl ReplyDirectoryPlus {
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
     /// value to request the next entries in further readdir calls
+    ///
+    /// Like for `ReplyDirectory::add()`, true means the entry wasn't added. The kernel
+    /// increments the lookup count of every entry added with a non-zero inode number (except
+    /// "." and ".."), just like for entries replied to lookup, and sends forget for them
+    /// later. So every added entry must be counted as looked up, and entries which didn't fit
+    /// must not be. `add_from_table()` does this with an `InodeTable`.
     pub fn add<T: AsRef<OsStr>>(
         &mut self,
         ino: u64,
@@ -558,6 +981,33 @@ impl ReplyDirectoryPlus {
         ))
     }
 
+    /// Add an entry for the given key of an inode table, like `add()`, counting the lookup
+    /// the kernel makes for it. Its inode number and generation are allocated with
+    /// `InodeTable::allocate()` only if the entry fits, and replace the inode number of attr.
+    /// "." and ".." aren't counted by the kernel, so their key is allocated with
+    /// `InodeTable::insert()` instead, which doesn't count a lookup.
+    pub fn add_from_table<K: Clone + Eq + Hash, T: AsRef<OsStr>>(
+        &mut self,
+        table: &mut InodeTable<K>,
+        key: K,
+        offset: i64,
+        name: T,
+        ttl: &Duration,
+        attr: &FileAttr,
+    ) -> bool {
+        let name = name.as_ref();
+        if !self.buf.fits(name.len()) {
+            return true;
+        }
+        let (ino, generation) = if name == "." || name == ".." {
+            table.insert(key)
+        } else {
+            table.allocate(key)
+        };
+        let attr = FileAttr { ino, ..*attr };
+        self.add(ino, offset, name, ttl, &attr, generation)
+    }
+
     /// Reply to a request with the filled directory buffer
     pub fn ok(self) {
         self.reply.send_ll(&self.buf.into());
@@ -633,7 +1083,7 @@ impl ReplyLseek {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{FileAttr, FileType};
+    use crate::{FileAttr, FileAttrBuilder, FileType};
     use std::io::IoSlice;
     use std::sync::mpsc::{channel, Sender};
     use std::thread;
@@ -882,6 +1332,21 @@ mod test {
         reply.opened(0x1122, 0x33);
     }
 
+    #[test]
+    #[cfg(feature = "abi-7-31")]
+    fn reply_open_stream() {
+        use crate::consts::{FOPEN_NONSEEKABLE, FOPEN_STREAM};
+        let sender = AssertSender {
+            expected: vec![
+                0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00,
+                0x00, 0x00, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00,
+            ],
+        };
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
+        reply.opened(0x1122, FOPEN_NONSEEKABLE | FOPEN_STREAM);
+    }
+
     #[test]
     fn reply_write() {
         let sender = AssertSender {
@@ -894,6 +1359,52 @@ mod test {
         reply.written(0x1122);
     }
 
+    #[test]
+    fn reply_symlink() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyData = Reply::new(0xdeadbeef, tx.clone());
+        reply.symlink("../target");
+        let data = rx.recv().unwrap();
+        assert_eq!(data[0..4], 25u32.to_ne_bytes());
+        assert_eq!(data[16..], *b"../target");
+        let reply: ReplyData = Reply::new(0xdeadbeef, tx);
+        reply.symlink(OsStr::from_bytes(b"\xff/target\0"));
+        let data = rx.recv().unwrap();
+        assert_eq!(data[16..], *b"\xff/target");
+    }
+
+    #[test]
+    fn reply_open_default() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, tx.clone());
+        reply.default_open();
+        let data = rx.recv().unwrap();
+        assert_eq!(data[4..8], 0i32.to_ne_bytes());
+        assert_eq!(data[16..24], 0u64.to_ne_bytes());
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, tx);
+        reply.with_no_open_support(true).default_open();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[4..8], (-libc::ENOSYS).to_ne_bytes());
+    }
+
+    #[test]
+    fn reply_write_requested() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        // A short write is passed on to the kernel unchanged
+        let reply: ReplyWrite = Reply::new(0xdeadbeef, tx.clone());
+        reply.with_requested(0x1000).written(0x800);
+        let data = rx.recv().unwrap();
+        assert_eq!(data[4..8], 0i32.to_ne_bytes());
+        assert_eq!(data[16..20], 0x800u32.to_ne_bytes());
+        // Writing more than requested is replaced by an error
+        let reply: ReplyWrite = Reply::new(0xdeadbeef, tx);
+        reply.with_requested(0x1000).written(0x1001);
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[4..8], (-libc::EIO).to_ne_bytes());
+    }
+
     #[test]
     fn reply_statfs() {
         let sender = AssertSender {
@@ -1051,6 +1562,234 @@ mod test {
         reply.data(&[0x11, 0x22, 0x33, 0x44]);
     }
 
+    #[test]
+    fn reply_data_sent_once() {
+        let (tx, rx) = channel::<()>();
+        let reply: ReplyData = Reply::new(0xdeadbeef, tx);
+        reply.error(libc::EIO);
+        rx.recv().unwrap();
+        // The sender was consumed by the reply, so no second reply can be sent on drop
+        assert!(rx.recv().is_err());
+    }
+
+    impl super::ReplySender for Sender<Vec<u8>> {
+        fn send(&self, data: &[IoSlice<'_>]) -> std::io::Result<()> {
+            let mut v = vec![];
+            for x in data {
+                v.extend_from_slice(x)
+            }
+            Sender::send(self, v).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reply_entry_default_ttl() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, tx);
+        reply
+            .with_default_ttl(Duration::new(0x11, 0x22), Duration::new(0x33, 0x44))
+            .entry_default_ttl(FileAttrBuilder::new(0x55, FileType::RegularFile), 0x66);
+        let data = rx.recv().unwrap();
+        // fuse_entry_out: nodeid, generation, entry_valid, attr_valid, entry_valid_nsec, attr_valid_nsec
+        assert_eq!(
+            data[16..56],
+            [
+                0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reply_ioctl_inode_flags() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyIoctl = Reply::new(0xdeadbeef, tx);
+        reply.inode_flags(0x10);
+        let data = rx.recv().unwrap();
+        // fuse_ioctl_out: result, flags, in_iovs, out_iovs, followed by the data
+        assert_eq!(data[16..24], [0; 8]);
+        assert_eq!(data[32..], 0x10u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn reply_directory_plus_from_table() {
+        let mut table = InodeTable::new("/".to_string());
+        let attr = FileAttrBuilder::new(0, FileType::RegularFile).into();
+        let (tx, rx) = channel::<Vec<u8>>();
+        // Room for two entries with short names
+        let size = (std::mem::size_of::<crate::ll::fuse_abi::fuse_direntplus>() + 1 + 7) & !7;
+        let mut reply = ReplyDirectoryPlus::new(0xdeadbeef, tx, 2 * size);
+        let ttl = Duration::ZERO;
+        // The key of ".." isn't in the table yet, it's allocated without counting a lookup
+        assert!(!reply.add_from_table(&mut table, "/..".to_string(), 1, "..", &ttl, &attr));
+        assert!(!reply.add_from_table(&mut table, "/a".to_string(), 2, "a", &ttl, &attr));
+        // Doesn't fit, so it isn't counted
+        assert!(reply.add_from_table(&mut table, "/b".to_string(), 3, "b", &ttl, &attr));
+        reply.ok();
+        assert_eq!(table.lookups(2), Some(0));
+        assert_eq!(table.lookups(3), Some(1));
+        assert_eq!(table.ino(&"/b".to_string()), None);
+        // The allocated inode number replaces the one of the attributes
+        let data = rx.recv().unwrap();
+        assert_eq!(data[16..24], 2u64.to_ne_bytes());
+        let entry = &data[16 + size..];
+        assert_eq!(entry[0..8], 3u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn reply_negative_entry() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, tx);
+        reply.negative(&Duration::new(0x11, 0x22));
+        let data = rx.recv().unwrap();
+        assert_eq!(data[4..8], [0; 4]);
+        // fuse_entry_out: nodeid, generation, entry_valid, attr_valid, entry_valid_nsec, attr_valid_nsec
+        assert_eq!(
+            data[16..56],
+            [
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+        assert!(data[56..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reply_attr_default_ttl() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, tx);
+        reply
+            .with_default_ttl(Duration::ZERO)
+            .attr_default_ttl(FileAttrBuilder::new(0x55, FileType::RegularFile));
+        let data = rx.recv().unwrap();
+        // fuse_attr_out: attr_valid, attr_valid_nsec
+        assert_eq!(data[16..28], [0; 12]);
+    }
+
+    #[test]
+    fn reply_zero_ttl() {
+        let attr = FileAttrBuilder::new(0x55, FileType::RegularFile).into();
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, tx.clone());
+        reply.entry(&Duration::ZERO, &attr, 0);
+        // fuse_entry_out: nodeid, generation, entry_valid, attr_valid, entry_valid_nsec, attr_valid_nsec
+        assert_eq!(rx.recv().unwrap()[32..56], [0; 24]);
+        let reply: ReplyCreate = Reply::new(0xdeadbeef, tx);
+        reply.created(&Duration::ZERO, &attr, 0, 0, 0);
+        assert_eq!(rx.recv().unwrap()[32..56], [0; 24]);
+    }
+
+    #[test]
+    fn reply_directory_fill_from() {
+        let entries = || {
+            vec![
+                (1, FileType::Directory, OsString::from(".")),
+                (1, FileType::Directory, OsString::from("..")),
+                (2, FileType::RegularFile, OsString::from("a")),
+            ]
+            .into_iter()
+        };
+        // Each entry takes 32 bytes (fuse_dirent and padded name)
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 64);
+        assert_eq!(reply.fill_from(entries(), 0), 2);
+        reply.ok();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16 + 64);
+        // Offset of the second entry
+        assert_eq!(data[16 + 32 + 8..16 + 32 + 16], 2u64.to_ne_bytes());
+
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 64);
+        assert_eq!(reply.fill_from(entries(), 2), 3);
+        reply.ok();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16 + 32);
+        assert_eq!(data[16..24], 2u64.to_ne_bytes());
+        assert_eq!(data[24..32], 3u64.to_ne_bytes());
+        assert_eq!(data[40], b'a');
+
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx, 64);
+        assert_eq!(reply.fill_from(entries(), 3), 3);
+        reply.ok();
+        assert_eq!(rx.recv().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn reply_directory_full() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        // Room for "hello" (32 bytes), but not for "world.rs" as well
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 48);
+        assert!(!reply.add(0xaabb, 1, FileType::Directory, "hello"));
+        assert!(reply.add(0xccdd, 2, FileType::RegularFile, "world.rs"));
+        reply.ok();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16 + 32);
+        assert_eq!(data[24..32], 1u64.to_ne_bytes());
+
+        // Resuming from the offset of the last entry added
+        let entries = vec![
+            (0xaabb, FileType::Directory, OsString::from("hello")),
+            (0xccdd, FileType::RegularFile, OsString::from("world.rs")),
+        ];
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 48);
+        assert_eq!(reply.fill_from(entries.clone().into_iter(), 1), 2);
+        reply.ok();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16 + 32);
+        assert_eq!(data[16..24], 0xccddu64.to_ne_bytes());
+
+        // Not even the first entry fits
+        let long_name = "x".repeat(100);
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 48);
+        assert!(reply.add(0xaabb, 1, FileType::Directory, &long_name));
+        reply.error(libc::EINVAL);
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[4..8], (-libc::EINVAL).to_ne_bytes());
+        let entries = vec![(0xaabb, FileType::Directory, OsString::from(long_name))];
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx, 48);
+        assert_eq!(reply.fill_from(entries.into_iter(), 0), 0);
+    }
+
+    #[test]
+    fn reply_directory_non_utf8_name() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx, 4096);
+        let name = OsStr::from_bytes(b"caf\xe9\xff");
+        assert!(!reply.add(0xaabb, 1, FileType::RegularFile, name));
+        reply.ok();
+        let data = rx.recv().unwrap();
+        // fuse_dirent: ino, off, namelen, type, followed by the name
+        assert_eq!(data[32..36], 5u32.to_ne_bytes());
+        assert_eq!(data[40..45], *b"caf\xe9\xff");
+    }
+
+    #[test]
+    fn replies_are_send() {
+        fn assert_send<T: Send + 'static>() {}
+        assert_send::<ReplyEntry>();
+        assert_send::<ReplyAttr>();
+        assert_send::<ReplyData>();
+        assert_send::<ReplyEmpty>();
+        assert_send::<ReplyOpen>();
+        assert_send::<ReplyWrite>();
+        assert_send::<ReplyStatfs>();
+        assert_send::<ReplyCreate>();
+        assert_send::<ReplyLock>();
+        assert_send::<ReplyBmap>();
+        assert_send::<ReplyIoctl>();
+        assert_send::<ReplyLseek>();
+        assert_send::<ReplyDirectory>();
+        assert_send::<ReplyDirectoryPlus>();
+        assert_send::<ReplyXattr>();
+        #[cfg(feature = "abi-7-11")]
+        assert_send::<ReplyPoll>();
+    }
+
     #[test]
     fn async_reply() {
         let (tx, rx) = channel::<()>();
@@ -5,27 +5,37 @@
 //!
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
-use crate::ll::{fuse_abi as abi, Errno, Response};
+use crate::ll::{fuse_abi as abi, Errno, FileHandle, RequestError, Response};
 use log::{debug, error, warn};
 use std::convert::TryFrom;
-#[cfg(feature = "abi-7-28")]
 use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::Arc;
 
 use crate::channel::ChannelSender;
+use crate::interrupt::{InterruptToken, Interrupts};
 use crate::ll::Request as _;
 #[cfg(feature = "abi-7-21")]
 use crate::reply::ReplyDirectoryPlus;
-use crate::reply::{Reply, ReplyDirectory, ReplySender};
-use crate::session::{Session, SessionACL};
+use crate::reply::{
+    with_panic_errno, Reply, ReplyAttr, ReplyCapture, ReplyCreate, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplySender, ReplyWrite,
+};
+use crate::session::{panic_message, Session, SessionACL};
+use crate::stats::Counters;
+use crate::write_combining::WriteErrorReply;
 use crate::Filesystem;
-use crate::{ll, KernelConfig};
+use crate::{ll, KernelConfig, MountOption};
 
 /// Request data structure
 #[derive(Debug)]
 pub struct Request<'a> {
     /// Channel sender for sending the reply
     ch: ChannelSender,
+    /// Registry of interruptible requests of the session
+    interrupts: Interrupts,
     /// Request raw data
     data: &'a [u8],
     /// Parsed request
@@ -33,27 +43,113 @@ pub struct Request<'a> {
 }
 
 impl<'a> Request<'a> {
-    /// Create a new request from the given data
-    pub(crate) fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
+    /// Create a new request from the given data. A request whose size doesn't match the
+    /// length in its header is replied with EIO.
+    pub(crate) fn new(
+        ch: ChannelSender,
+        interrupts: Interrupts,
+        data: &'a [u8],
+    ) -> Result<Request<'a>, RequestError> {
         let request = match ll::AnyRequest::try_from(data) {
             Ok(request) => request,
             Err(err) => {
                 error!("{}", err);
-                return None;
+                let unique = match err {
+                    RequestError::LengthMismatch(unique, _, _) => Some(unique),
+                    // A short read still contains the complete header with the unique id
+                    RequestError::ShortRead(..) => Some(ll::RequestId(u64::from_ne_bytes(
+                        data[8..16].try_into().unwrap(),
+                    ))),
+                    _ => None,
+                };
+                if let Some(unique) = unique {
+                    let res =
+                        Response::new_error(Errno::EIO).with_iovec(unique, |iov| ch.send(iov));
+                    if let Err(err) = res {
+                        warn!("Request {:?}: Failed to send reply: {}", unique, err)
+                    }
+                }
+                return Err(err);
             }
         };
 
-        Some(Self { ch, data, request })
+        // The span is closed once the sender was dropped by the reply, which may happen on
+        // another thread after dispatching has finished
+        #[cfg(feature = "tracing")]
+        let ch = ch.with_span(tracing::debug_span!(
+            "fuse_request",
+            opcode = ?abi::fuse_opcode::try_from(request.opcode()).ok(),
+            unique = request.unique().0,
+            nodeid = request.nodeid().0,
+            errno = tracing::field::Empty,
+        ));
+
+        Ok(Self {
+            ch,
+            interrupts,
+            data,
+            request,
+        })
+    }
+
+    /// Count this request in the given session statistics, as well as the reply to it
+    pub(crate) fn with_stats(mut self, stats: Arc<Counters>) -> Self {
+        let opcode = self.request.opcode();
+        stats.request(opcode);
+        self.ch = self.ch.with_stats(stats, opcode);
+        self
+    }
+
+    /// Pass a write request to the filesystem, sending the reply to the given sender instead
+    /// of the kernel
+    pub(crate) fn write_to<FS: Filesystem, S: ReplySender>(&self, filesystem: &mut FS, sender: S) {
+        if let Ok(ll::Operation::Write(x)) = self.request.operation() {
+            let reply: ReplyWrite = Reply::new(self.request.unique().into(), sender);
+            filesystem.write(
+                self,
+                self.request.nodeid().into(),
+                x.file_handle().into(),
+                x.offset(),
+                x.data(),
+                x.write_flags(),
+                x.flags(),
+                x.lock_owner().map(|l| l.into()),
+                reply.with_requested(x.data().len() as u32),
+            );
+        }
     }
 
     /// Dispatch request to the given filesystem.
     /// This calls the appropriate filesystem operation method for the
     /// request and sends back the returned reply to the kernel
     pub(crate) fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
+        #[cfg(feature = "tracing")]
+        let _entered = self.ch.span().enter();
         debug!("{}", self.request);
         let unique = self.request.unique();
-
-        let res = match self.dispatch_req(se) {
+        let result = if se.catch_panics {
+            let errno = se.panic_errno;
+            let dispatch = AssertUnwindSafe(|| self.dispatch_filesystem(se));
+            match panic::catch_unwind(|| with_panic_errno(errno, dispatch)) {
+                Ok(result) => result,
+                Err(panic) if self.request.opcode() == abi::fuse_opcode::FUSE_INIT as u32 => {
+                    panic::resume_unwind(panic)
+                }
+                // Unwinding dropped the reply, which replied the panic errno if it wasn't
+                // used yet
+                Err(panic) => {
+                    error!(
+                        "Filesystem panicked handling request {:?}: {}",
+                        unique,
+                        panic_message(&*panic)
+                    );
+                    return;
+                }
+            }
+        } else {
+            self.dispatch_filesystem(se)
+        };
+        let res = match result {
             Ok(Some(resp)) => resp,
             Ok(None) => return,
             Err(errno) => self.request.reply_err(errno),
@@ -65,11 +161,41 @@ impl<'a> Request<'a> {
         }
     }
 
+    /// Pass buffered writes and then the request on to the filesystem
+    fn dispatch_filesystem<FS: Filesystem>(
+        &self,
+        se: &mut Session<FS>,
+    ) -> Result<Option<Response>, Errno> {
+        // Pass buffered writes on before any request which doesn't continue them, so the
+        // filesystem has all data before it handles anything else
+        let pending = se.write_combining.as_mut();
+        if let Some(pending) = pending.and_then(|c| c.take_unless_continued(&self.request)) {
+            se.write_pending(pending);
+        }
+        if let Some(observer) = &se.observer {
+            let unique = self.request.unique();
+            observer.on_request(self.request.opcode(), unique.into());
+        }
+        self.dispatch_req(se)
+    }
+
     fn dispatch_req<FS: Filesystem>(
         &self,
         se: &mut Session<FS>,
     ) -> Result<Option<Response>, Errno> {
-        let op = self.request.operation().map_err(|_| Errno::ENOSYS)?;
+        let op = match self.request.operation() {
+            Ok(op) => op,
+            // Truncated or inconsistent arguments, e.g. from a misbehaving proxy
+            Err(RequestError::InsufficientData) => {
+                warn!(
+                    "Invalid arguments of FUSE request {} (opcode {})",
+                    self.request.unique().0,
+                    self.request.opcode()
+                );
+                return Err(Errno::EINVAL);
+            }
+            Err(_) => return Err(Errno::ENOSYS),
+        };
         // Implement allow_root & access check for auto_unmount
         if (se.allowed == SessionACL::RootAndOwner
             && self.request.uid() != se.session_owner
@@ -82,6 +208,7 @@ impl<'a> Request<'a> {
                     // Only allow operations that the kernel may issue without a uid set
                     ll::Operation::Init(_)
                     | ll::Operation::Destroy(_)
+                    | ll::Operation::Interrupt(_)
                     | ll::Operation::Read(_)
                     | ll::Operation::ReadDir(_)
                     | ll::Operation::ReadDirPlus(_)
@@ -101,6 +228,7 @@ impl<'a> Request<'a> {
                     // Only allow operations that the kernel may issue without a uid set
                     ll::Operation::Init(_)
                     | ll::Operation::Destroy(_)
+                    | ll::Operation::Interrupt(_)
                     | ll::Operation::Read(_)
                     | ll::Operation::ReadDir(_)
                     | ll::Operation::Write(_)
@@ -114,6 +242,12 @@ impl<'a> Request<'a> {
                 }
             }
         }
+        if se.read_only && modifies_filesystem(&op) {
+            return Err(Errno::EROFS);
+        }
+        if !se.supported_ops.contains_opcode(self.request.opcode()) {
+            return Err(Errno::ENOSYS);
+        }
         match op {
             // Filesystem initialization
             ll::Operation::Init(x) => {
@@ -128,10 +262,30 @@ impl<'a> Request<'a> {
                 se.proto_minor = v.minor();
 
                 let mut config = KernelConfig::new(x.capabilities(), x.max_readahead());
-                // Call filesystem init method and give it a chance to return an error
-                se.filesystem
-                    .init(self, &mut config)
-                    .map_err(Errno::from_i32)?;
+                config.max_read = se
+                    .mount_options
+                    .iter()
+                    .rev()
+                    .find_map(|option| match option {
+                        MountOption::MaxRead(max_read) => Some(*max_read),
+                        _ => None,
+                    });
+                if let Some(max_write) = se.default_max_write {
+                    // Validated by SessionBuilder
+                    let _ = config.set_max_write(max_write);
+                }
+                se.supported_ops = se.filesystem.supported_ops();
+                config.request_ops(se.supported_ops);
+                // Call filesystem init method and give it a chance to return an error.
+                // The error is sent to the kernel, which then aborts the connection, and
+                // the session loop ends with the error.
+                if let Err(err) = se.filesystem.init(self, &mut config) {
+                    let errno = Errno::from_i32(err);
+                    error!("Filesystem init failed, aborting mount: {:?}", errno);
+                    se.init_error = Some(errno.0.into());
+                    return Err(errno);
+                }
+                config.drop_unsupported(se.supported_ops);
 
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
@@ -144,6 +298,23 @@ impl<'a> Request<'a> {
                     config.max_readahead,
                     config.max_write
                 );
+                #[cfg(feature = "abi-7-10")]
+                {
+                    se.export_support =
+                        x.capabilities() & config.requested & abi::consts::FUSE_EXPORT_SUPPORT != 0;
+                }
+                // Zero-message opens don't need to be requested, the kernel announces them
+                #[cfg(feature = "abi-7-23")]
+                {
+                    se.no_open_support = x.capabilities() & abi::consts::FUSE_NO_OPEN_SUPPORT != 0;
+                }
+                #[cfg(feature = "abi-7-29")]
+                {
+                    se.no_opendir_support =
+                        x.capabilities() & abi::consts::FUSE_NO_OPENDIR_SUPPORT != 0;
+                }
+                se.max_write = config.max_write;
+                se.negotiated.set(&config);
                 se.initialized = true;
                 return Ok(Some(x.reply(&config)));
             }
@@ -154,8 +325,7 @@ impl<'a> Request<'a> {
             }
             // Filesystem destroyed
             ll::Operation::Destroy(x) => {
-                se.filesystem.destroy();
-                se.destroyed = true;
+                se.destroy();
                 return Ok(Some(x.reply()));
             }
             // Any operation is invalid after destroy
@@ -164,26 +334,46 @@ impl<'a> Request<'a> {
                 return Err(Errno::EIO);
             }
 
-            ll::Operation::Interrupt(_) => {
-                // TODO: handle FUSE_INTERRUPT
-                return Err(Errno::ENOSYS);
+            ll::Operation::Interrupt(x) => {
+                // The kernel doesn't expect a reply if the request was found. If it wasn't,
+                // it was either already replied or the filesystem never checks for
+                // interrupts, so there's nothing to do either.
+                if !se.interrupts.interrupt(x.unique().into()) {
+                    debug!("No interruptible request {:?} found", x.unique());
+                }
+                return Ok(None);
             }
 
             ll::Operation::Lookup(x) => {
-                se.filesystem.lookup(
-                    self,
-                    self.request.nodeid().into(),
-                    &x.name().as_ref(),
-                    self.reply(),
-                );
+                let reply = self
+                    .reply_with::<ReplyEntry>(self.entry_sender(se))
+                    .with_export_support(se.export_support)
+                    .with_default_ttl(se.entry_ttl, se.attr_ttl);
+                let name: &OsStr = x.name().as_ref();
+                // With FUSE_EXPORT_SUPPORT, the kernel resolves NFS file handles of inodes it
+                // doesn't know anymore by looking up "." in them
+                if se.export_support && name == "." {
+                    se.filesystem
+                        .lookup_inode(self, self.request.nodeid().into(), reply);
+                } else {
+                    se.filesystem
+                        .lookup(self, self.request.nodeid().into(), name, reply);
+                }
             }
             ll::Operation::Forget(x) => {
+                if let Some(lookups) = &se.lookups {
+                    lookups.forgot(self.request.nodeid().into(), x.nlookup());
+                }
                 se.filesystem
                     .forget(self, self.request.nodeid().into(), x.nlookup()); // no reply
             }
-            ll::Operation::GetAttr(_) => {
-                se.filesystem
-                    .getattr(self, self.request.nodeid().into(), self.reply());
+            ll::Operation::GetAttr(x) => {
+                se.filesystem.getattr(
+                    self,
+                    self.request.nodeid().into(),
+                    x.file_handle().map(|fh| fh.into()),
+                    self.reply::<ReplyAttr>().with_default_ttl(se.attr_ttl),
+                );
             }
             ll::Operation::SetAttr(x) => {
                 se.filesystem.setattr(
@@ -201,7 +391,7 @@ impl<'a> Request<'a> {
                     x.chgtime(),
                     x.bkuptime(),
                     x.flags(),
-                    self.reply(),
+                    self.reply::<ReplyAttr>().with_default_ttl(se.attr_ttl),
                 );
             }
             ll::Operation::ReadLink(_) => {
@@ -216,7 +406,9 @@ impl<'a> Request<'a> {
                     x.mode(),
                     x.umask(),
                     x.rdev(),
-                    self.reply(),
+                    self.reply_with::<ReplyEntry>(self.entry_sender(se))
+                        .with_export_support(se.export_support)
+                        .with_default_ttl(se.entry_ttl, se.attr_ttl),
                 );
             }
             ll::Operation::MkDir(x) => {
@@ -226,7 +418,9 @@ impl<'a> Request<'a> {
                     x.name().as_ref(),
                     x.mode(),
                     x.umask(),
-                    self.reply(),
+                    self.reply_with::<ReplyEntry>(self.entry_sender(se))
+                        .with_export_support(se.export_support)
+                        .with_default_ttl(se.entry_ttl, se.attr_ttl),
                 );
             }
             ll::Operation::Unlink(x) => {
@@ -251,7 +445,9 @@ impl<'a> Request<'a> {
                     self.request.nodeid().into(),
                     x.target().as_ref(),
                     &Path::new(x.link()),
-                    self.reply(),
+                    self.reply_with::<ReplyEntry>(self.entry_sender(se))
+                        .with_export_support(se.export_support)
+                        .with_default_ttl(se.entry_ttl, se.attr_ttl),
                 );
             }
             ll::Operation::Rename(x) => {
@@ -271,12 +467,19 @@ impl<'a> Request<'a> {
                     x.inode_no().into(),
                     self.request.nodeid().into(),
                     x.dest().name.as_ref(),
-                    self.reply(),
+                    self.reply_with::<ReplyEntry>(self.entry_sender(se))
+                        .with_export_support(se.export_support)
+                        .with_default_ttl(se.entry_ttl, se.attr_ttl),
                 );
             }
             ll::Operation::Open(x) => {
-                se.filesystem
-                    .open(self, self.request.nodeid().into(), x.flags(), self.reply());
+                se.filesystem.open(
+                    self,
+                    self.request.nodeid().into(),
+                    x.flags(),
+                    self.reply::<ReplyOpen>()
+                        .with_no_open_support(se.no_open_support),
+                );
             }
             ll::Operation::Read(x) => {
                 se.filesystem.read(
@@ -291,6 +494,13 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::Write(x) => {
+                let combiner = se.write_combining.as_mut();
+                if let Some(combiner) = combiner.filter(|c| c.combines(x.data())) {
+                    if let Some(pending) = combiner.add(&self.request, self.data) {
+                        se.write_pending(pending);
+                    }
+                    return Ok(Some(Response::new_write(x.data().len() as u32)));
+                }
                 se.filesystem.write(
                     self,
                     self.request.nodeid().into(),
@@ -300,16 +510,18 @@ impl<'a> Request<'a> {
                     x.write_flags(),
                     x.flags(),
                     x.lock_owner().map(|l| l.into()),
-                    self.reply(),
+                    self.reply::<ReplyWrite>()
+                        .with_requested(x.data().len() as u32),
                 );
             }
             ll::Operation::Flush(x) => {
+                let reply = self.write_error_reply(se, x.file_handle());
                 se.filesystem.flush(
                     self,
                     self.request.nodeid().into(),
                     x.file_handle().into(),
                     x.lock_owner().into(),
-                    self.reply(),
+                    reply,
                 );
             }
             ll::Operation::Release(x) => {
@@ -324,19 +536,33 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::FSync(x) => {
+                let reply = self.write_error_reply(se, x.file_handle());
                 se.filesystem.fsync(
                     self,
                     self.request.nodeid().into(),
                     x.file_handle().into(),
                     x.fdatasync(),
-                    self.reply(),
+                    reply,
                 );
             }
             ll::Operation::OpenDir(x) => {
-                se.filesystem
-                    .opendir(self, self.request.nodeid().into(), x.flags(), self.reply());
+                // Record the handle replied to the opendir
+                let sender = match &se.dir_handles {
+                    Some(dir_handles) => self.ch.clone().with_dir_handles(dir_handles.clone()),
+                    None => self.ch.clone(),
+                };
+                se.filesystem.opendir(
+                    self,
+                    self.request.nodeid().into(),
+                    x.flags(),
+                    self.reply_with::<ReplyOpen>(sender)
+                        .with_no_open_support(se.no_opendir_support),
+                );
             }
             ll::Operation::ReadDir(x) => {
+                if let Some(dir_handles) = &se.dir_handles {
+                    dir_handles.check("READDIR", x.file_handle().into());
+                }
                 se.filesystem.readdir(
                     self,
                     self.request.nodeid().into(),
@@ -350,6 +576,9 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::ReleaseDir(x) => {
+                if let Some(dir_handles) = &se.dir_handles {
+                    dir_handles.released(x.file_handle().into());
+                }
                 se.filesystem.releasedir(
                     self,
                     self.request.nodeid().into(),
@@ -415,7 +644,8 @@ impl<'a> Request<'a> {
                     x.mode(),
                     x.umask(),
                     x.flags(),
-                    self.reply(),
+                    self.reply_with::<ReplyCreate>(self.entry_sender(se))
+                        .with_export_support(se.export_support),
                 );
             }
             ll::Operation::GetLk(x) => {
@@ -487,9 +717,16 @@ impl<'a> Request<'a> {
                 }
             }
             #[cfg(feature = "abi-7-11")]
-            ll::Operation::Poll(_) => {
-                // TODO: handle FUSE_POLL
-                return Err(Errno::ENOSYS);
+            ll::Operation::Poll(x) => {
+                se.filesystem.poll(
+                    self,
+                    self.request.nodeid().into(),
+                    x.file_handle().into(),
+                    x.kernel_handle(),
+                    x.events(),
+                    x.flags(),
+                    self.reply(),
+                );
             }
             #[cfg(feature = "abi-7-15")]
             ll::Operation::NotifyReply(_) => {
@@ -498,6 +735,11 @@ impl<'a> Request<'a> {
             }
             #[cfg(feature = "abi-7-16")]
             ll::Operation::BatchForget(x) => {
+                if let Some(lookups) = &se.lookups {
+                    for node in x.nodes() {
+                        lookups.forgot(node.nodeid, node.nlookup);
+                    }
+                }
                 se.filesystem.batch_forget(self, x.nodes()); // no reply
             }
             #[cfg(feature = "abi-7-19")]
@@ -514,6 +756,14 @@ impl<'a> Request<'a> {
             }
             #[cfg(feature = "abi-7-21")]
             ll::Operation::ReadDirPlus(x) => {
+                if let Some(dir_handles) = &se.dir_handles {
+                    dir_handles.check("READDIRPLUS", x.file_handle().into());
+                }
+                // Count the lookups of the entries, like for other entry replies
+                let sender = match &se.lookups {
+                    Some(lookups) => self.ch.clone().with_lookups(lookups.clone(), true),
+                    None => self.ch.clone(),
+                };
                 se.filesystem.readdirplus(
                     self,
                     self.request.nodeid().into(),
@@ -521,7 +771,7 @@ impl<'a> Request<'a> {
                     x.offset(),
                     ReplyDirectoryPlus::new(
                         self.request.unique().into(),
-                        self.ch.clone(),
+                        sender,
                         x.size() as usize,
                     ),
                 );
@@ -552,6 +802,8 @@ impl<'a> Request<'a> {
             #[cfg(feature = "abi-7-28")]
             ll::Operation::CopyFileRange(x) => {
                 let (i, o) = (x.src(), x.dest());
+                // The kernel only passes 32 bit flags
+                let flags = x.flags().try_into().map_err(|_| Errno::EINVAL)?;
                 se.filesystem.copy_file_range(
                     self,
                     i.inode.into(),
@@ -561,10 +813,48 @@ impl<'a> Request<'a> {
                     o.file_handle.into(),
                     o.offset,
                     x.len(),
-                    x.flags().try_into().unwrap(),
+                    flags,
                     self.reply(),
                 );
             }
+            #[cfg(feature = "abi-7-31")]
+            ll::Operation::SetupMapping(x) => {
+                se.filesystem.setup_mapping(
+                    self,
+                    self.request.nodeid().into(),
+                    x.file_handle().into(),
+                    x.foffset(),
+                    x.len(),
+                    x.flags(),
+                    x.moffset(),
+                    self.reply(),
+                );
+            }
+            #[cfg(feature = "abi-7-31")]
+            ll::Operation::RemoveMapping(x) => {
+                let mappings: Vec<_> = x.mappings().collect();
+                se.filesystem.remove_mapping(
+                    self,
+                    self.request.nodeid().into(),
+                    &mappings,
+                    self.reply(),
+                );
+            }
+            ll::Operation::SyncFs(_) => {
+                se.filesystem
+                    .syncfs(self, self.request.nodeid().into(), self.reply());
+            }
+            ll::Operation::TmpFile(x) => {
+                se.filesystem.tmpfile(
+                    self,
+                    self.request.nodeid().into(),
+                    x.mode(),
+                    x.umask(),
+                    x.flags(),
+                    self.reply_with::<ReplyCreate>(self.entry_sender(se))
+                        .with_export_support(se.export_support),
+                );
+            }
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName(x) => {
                 se.filesystem.setvolname(self, x.name(), self.reply());
@@ -586,6 +876,11 @@ impl<'a> Request<'a> {
                     self.reply(),
                 );
             }
+            #[cfg(target_os = "android")]
+            ll::Operation::CanonicalPath(_) => {
+                se.filesystem
+                    .canonical_path(self, self.request.nodeid().into(), self.reply());
+            }
 
             #[cfg(feature = "abi-7-12")]
             ll::Operation::CuseInit(_) => {
@@ -599,7 +894,46 @@ impl<'a> Request<'a> {
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique().into(), self.ch.clone())
+        self.reply_with(self.ch.clone())
+    }
+
+    /// Create a reply object for this request which is sent with the given sender
+    fn reply_with<T: Reply>(&self, sender: ChannelSender) -> T {
+        Reply::new(self.request.unique().into(), sender)
+    }
+
+    /// Reply to a flush or fsync of the given file handle, which reports the error of a
+    /// failed combined write of the handle instead of success
+    fn write_error_reply<FS: Filesystem>(
+        &self,
+        se: &mut Session<FS>,
+        fh: FileHandle,
+    ) -> ReplyEmpty {
+        match se.take_write_error(self.request.nodeid(), fh) {
+            Some(errno) => Reply::new(
+                self.request.unique().into(),
+                ReplyCapture(WriteErrorReply {
+                    sender: self.ch.clone(),
+                    errno,
+                }),
+            ),
+            None => self.reply(),
+        }
+    }
+
+    /// Sender for replies with an entry, which counts the entry's lookup if the session
+    /// tracks lookups
+    fn entry_sender<FS: Filesystem>(&self, se: &Session<FS>) -> ChannelSender {
+        match &se.lookups {
+            Some(lookups) => self.ch.clone().with_lookups(lookups.clone(), false),
+            None => self.ch.clone(),
+        }
+    }
+
+    /// Returns a token to check whether this request was interrupted by the kernel. Only
+    /// requests for which a token was retrieved can be interrupted, see `InterruptToken`.
+    pub fn interrupt_token(&self) -> InterruptToken {
+        self.interrupts.token(self.unique())
     }
 
     /// Returns the unique identifier of this request
@@ -608,6 +942,20 @@ impl<'a> Request<'a> {
         self.request.unique().into()
     }
 
+    /// Returns the raw opcode of this request (see `consts` for the values)
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.request.opcode()
+    }
+
+    /// Returns the total length of this request in bytes, including the header. Filesystems
+    /// can use this to log or reject unusually large requests. The session already checks
+    /// that it matches the size of the data received from the kernel.
+    #[inline]
+    pub fn total_len(&self) -> u32 {
+        self.request.total_len()
+    }
+
     /// Returns the uid of this request
     #[inline]
     pub fn uid(&self) -> u32 {
@@ -625,4 +973,55 @@ impl<'a> Request<'a> {
     pub fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns true if this request was issued by the given mount owner or by root.
+    ///
+    /// The kernel only knows about `allow_other`, so `MountOption::AllowRoot` is implemented by
+    /// mounting with `allow_other` and rejecting requests of other users with EACCES in fuser.
+    /// Some operations which the kernel may issue without the caller's uid set (e.g. read,
+    /// write and release) are not checked though. Filesystems that are not mounted with
+    /// `default_permissions` can use this to additionally reject unauthorized callers.
+    #[inline]
+    pub fn is_permitted(&self, mount_owner_uid: u32) -> bool {
+        let uid = self.uid();
+        uid == mount_owner_uid || uid == 0
+    }
+}
+
+/// Returns true for operations that modify the filesystem, which are rejected for read-only
+/// sessions
+fn modifies_filesystem(op: &ll::Operation<'_>) -> bool {
+    match op {
+        ll::Operation::SetAttr(_)
+        | ll::Operation::SymLink(_)
+        | ll::Operation::MkNod(_)
+        | ll::Operation::MkDir(_)
+        | ll::Operation::Unlink(_)
+        | ll::Operation::RmDir(_)
+        | ll::Operation::Rename(_)
+        | ll::Operation::Link(_)
+        | ll::Operation::Write(_)
+        | ll::Operation::SetXAttr(_)
+        | ll::Operation::RemoveXAttr(_)
+        | ll::Operation::Create(_)
+        | ll::Operation::TmpFile(_) => true,
+        // Opening for writing or truncating fails, like open() on a read-only mount
+        ll::Operation::Open(x) => {
+            x.flags() & libc::O_ACCMODE != libc::O_RDONLY || x.flags() & libc::O_TRUNC != 0
+        }
+        #[cfg(feature = "abi-7-19")]
+        ll::Operation::FAllocate(_) => true,
+        #[cfg(feature = "abi-7-23")]
+        ll::Operation::Rename2(_) => true,
+        #[cfg(feature = "abi-7-28")]
+        ll::Operation::CopyFileRange(_) => true,
+        // Writable DAX mappings would let the kernel write to the file directly
+        #[cfg(feature = "abi-7-31")]
+        ll::Operation::SetupMapping(x) => {
+            x.flags() & abi::consts::FUSE_SETUPMAPPING_FLAG_WRITE != 0
+        }
+        #[cfg(target_os = "macos")]
+        ll::Operation::SetVolName(_) | ll::Operation::Exchange(_) => true,
+        _ => false,
+    }
 }
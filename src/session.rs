@@ -5,27 +5,243 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::info;
+use libc::{c_int, EAGAIN, EINTR, ENODEV, ENOENT, EPROTO};
+use log::{info, warn};
+use std::any::Any;
+use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
-use std::{io, ops::DerefMut};
+use std::time::Duration;
+use zerocopy::AsBytes;
 
+use crate::dir_handles::DirHandles;
+use crate::interrupt::Interrupts;
 use crate::ll::fuse_abi as abi;
+use crate::ll::{FileHandle, INodeNo, RequestError};
+use crate::lookups::Lookups;
+use crate::mnt::mount_options::{check_mount_options, check_option_conflicts};
+use crate::mnt::{check_mountpoint, force_unmount, mount_errno};
+#[cfg(target_os = "linux")]
+use crate::mnt::{mount_info, MountInfo};
+use crate::notify::Notifier;
 use crate::request::Request;
-use crate::Filesystem;
+use crate::signal::SignalHandlers;
+use crate::stats::{Counters, SessionStats};
+use crate::write_combining::{PendingWrite, WriteCombiner};
 use crate::MountOption;
 use crate::{channel::Channel, mnt::Mount};
+use crate::{Filesystem, KernelConfig, Ops};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// Extra space in the buffer for reading a request from the kernel. A write request
+/// contains the request header and write arguments in addition to the data.
+const BUFFER_HEADER_SIZE: usize = 4096;
+
+/// Size of the buffer for reading requests before the filesystem is initialized. The
+/// kernel doesn't send any request other than INIT before it has been replied, so this
+/// only needs to cover the historical default max write size of 128k. Once initialized
+/// the buffer is grown to fit the negotiated max write size.
+const INIT_BUFFER_SIZE: usize = 128 * 1024 + BUFFER_HEADER_SIZE;
+
+/// Observer for requests processed by a session, e.g. to collect metrics
+///
+/// `on_request` is called before a request is dispatched to the filesystem, `on_reply` when
+/// the reply for it was successfully sent to the kernel. The duration is measured from receiving the
+/// request until sending the reply, which may happen on another thread. Requests that don't
+/// expect a reply (forget, batch forget and interrupt) are only reported to `on_request`.
+/// Both methods are called for every request on the session's hot path and should return
+/// quickly.
+pub trait SessionObserver: Send + Sync {
+    /// Called when a request with the given opcode is received
+    fn on_request(&self, _opcode: u32, _unique: u64) {}
+
+    /// Called when the reply to a request was sent. `error` is set if the reply was an
+    /// error reply.
+    fn on_reply(&self, _unique: u64, _duration: Duration, _error: Option<c_int>) {}
+}
+
+impl fmt::Debug for dyn SessionObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "SessionObserver")
+    }
+}
+
+/// Policy for retrying a mount that failed with a transient error, see `mount_with_retry()`
+///
+/// A failed mount is retried up to `attempts - 1` times if the error has one of the given
+/// error codes. Mounts through fusermount only report EBUSY, EAGAIN and EINTR, recognized
+/// from its error message. The delay between attempts starts at `delay` and doubles after
+/// every attempt, up to `max_delay`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub delay: Duration,
+    /// Maximum delay between attempts
+    pub max_delay: Duration,
+    /// Error codes that are retried, like EBUSY. Other errors fail immediately.
+    pub errnos: Vec<c_int>,
+}
+
+impl Default for RetryPolicy {
+    /// 5 attempts starting with a delay of 100ms, retrying EBUSY, EAGAIN and EINTR
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            errnos: vec![libc::EBUSY, EAGAIN, EINTR],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that doesn't retry
+    fn none() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            errnos: vec![],
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Call f until it succeeds, fails with an error that is not retried, or the number of
+    /// attempts is exhausted, and return its last result
+    fn retry<T, F: FnMut() -> io::Result<T>>(&self, mut f: F) -> io::Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err)
+                    if attempt < self.attempts
+                        && mount_errno(&err).is_some_and(|e| self.errnos.contains(&e)) =>
+                {
+                    warn!(
+                        "Mount attempt {} of {} failed: {}, retrying in {:?}",
+                        attempt, self.attempts, err, delay
+                    );
+                    thread::sleep(delay);
+                    delay = min(delay * 2, self.max_delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Builder for sessions with many mount options or settings
+///
+/// Collects mount options and settings, which are validated together when the session is
+/// built: conflicting options and invalid sizes fail with `ErrorKind::InvalidInput` before
+/// anything is mounted.
+#[derive(Clone, Debug, Default)]
+pub struct SessionBuilder {
+    options: Vec<MountOption>,
+    max_write: Option<u32>,
+    max_read: Option<u32>,
+    retry_policy: Option<RetryPolicy>,
+}
+
+impl SessionBuilder {
+    /// Create a builder without any mount options
+    pub fn new() -> Self {
+        SessionBuilder::default()
+    }
+
+    /// Add a mount option
+    pub fn mount_option(mut self, option: MountOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    /// Add several mount options
+    pub fn mount_options(mut self, options: &[MountOption]) -> Self {
+        self.options.extend_from_slice(options);
+        self
+    }
+
+    /// Set the name of the source in mtab (`MountOption::FSName`)
+    pub fn fs_name<S: Into<String>>(self, name: S) -> Self {
+        self.mount_option(MountOption::FSName(name.into()))
+    }
+
+    /// Allow all users to access the filesystem (`MountOption::AllowOther`)
+    pub fn allow_other(self) -> Self {
+        self.mount_option(MountOption::AllowOther)
+    }
+
+    /// Set the max size of write requests to start the init handshake with, instead of
+    /// `MAX_WRITE_SIZE`. The filesystem may still change it in `Filesystem::init()`, see
+    /// `KernelConfig::set_max_write()`. Must be between 1 and `MAX_WRITE_SIZE`.
+    pub fn max_write(mut self, max_write: u32) -> Self {
+        self.max_write = Some(max_write);
+        self
+    }
+
+    /// Limit the size of read requests with the max_read mount option, the same as passing
+    /// `MountOption::MaxRead`. Must not be 0.
+    pub fn max_read(mut self, max_read: u32) -> Self {
+        self.max_read = Some(max_read);
+        self
+    }
+
+    /// Retry mounting according to the given policy, see `mount_with_retry()`
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Check the options and sizes without mounting
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if let Some(max_write) = self.max_write {
+            if max_write == 0 || max_write as usize > MAX_WRITE_SIZE {
+                return Err(invalid(format!(
+                    "Invalid max_write {}, must be between 1 and {}",
+                    max_write, MAX_WRITE_SIZE
+                )));
+            }
+        }
+        if self.max_read == Some(0) {
+            return Err(invalid("Invalid max_read 0".to_string()));
+        }
+        check_option_conflicts(&self.options)
+    }
+
+    /// Mount the given filesystem to the given mountpoint and return the session, which can
+    /// then be run or spawned
+    pub fn build<FS: Filesystem, P: AsRef<Path>>(
+        self,
+        filesystem: FS,
+        mountpoint: P,
+    ) -> io::Result<Session<FS>> {
+        self.validate()?;
+        let mut options = self.options;
+        if let Some(max_read) = self.max_read {
+            options.push(MountOption::MaxRead(max_read));
+        }
+        let policy = self.retry_policy.unwrap_or_else(RetryPolicy::none);
+        let mut se = Session::new_with_retry(filesystem, mountpoint.as_ref(), &options, &policy)?;
+        se.default_max_write = self.max_write;
+        Ok(se)
+    }
+}
+
+/// Default time for which the kernel caches entries and attributes, if the filesystem replies
+/// without giving an explicit ttl
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) enum SessionACL {
@@ -34,6 +250,48 @@ pub(crate) enum SessionACL {
     Owner,
 }
 
+impl SessionACL {
+    /// Users allowed to access the filesystem with the given mount options. An AutoUnmount
+    /// mount is restricted to the owner, even though AllowOther is set for fusermount.
+    fn from_options(options: &[MountOption]) -> Self {
+        if options.contains(&MountOption::AllowRoot) {
+            SessionACL::RootAndOwner
+        } else if options.contains(&MountOption::AllowOther) {
+            SessionACL::All
+        } else {
+            SessionACL::Owner
+        }
+    }
+}
+
+/// The kernel config negotiated during init, shared with the background session
+#[derive(Debug, Default)]
+pub(crate) struct NegotiatedConfig {
+    config: Mutex<Option<KernelConfig>>,
+    initialized: Condvar,
+}
+
+impl NegotiatedConfig {
+    /// Store the config replied to init and wake up threads waiting for it
+    pub(crate) fn set(&self, config: &KernelConfig) {
+        *self.config.lock().unwrap() = Some(config.clone());
+        self.initialized.notify_all();
+    }
+
+    fn get(&self) -> Option<KernelConfig> {
+        self.config.lock().unwrap().clone()
+    }
+
+    fn wait(&self, timeout: Duration) -> Option<KernelConfig> {
+        let config = self.config.lock().unwrap();
+        let (config, _) = self
+            .initialized
+            .wait_timeout_while(config, timeout, |config| config.is_none())
+            .unwrap();
+        config.clone()
+    }
+}
+
 /// The session data structure
 #[derive(Debug)]
 pub struct Session<FS: Filesystem> {
@@ -45,6 +303,8 @@ pub struct Session<FS: Filesystem> {
     mount: Option<Mount>,
     /// Mount point
     mountpoint: PathBuf,
+    /// Options the filesystem was mounted with
+    pub(crate) mount_options: Vec<MountOption>,
     /// Whether to restrict access to owner, root + owner, or unrestricted
     /// Used to implement allow_root and auto_unmount
     pub(crate) allowed: SessionACL,
@@ -58,51 +318,166 @@ pub struct Session<FS: Filesystem> {
     pub(crate) initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub(crate) destroyed: bool,
+    /// Error returned by the filesystem's init method. The session ends once the error
+    /// has been sent to the kernel.
+    pub(crate) init_error: Option<c_int>,
+    /// Reject requests that modify the filesystem with EROFS
+    pub(crate) read_only: bool,
+    /// Catch panics of filesystem methods instead of ending the session loop
+    pub(crate) catch_panics: bool,
+    /// Error replied for replies dropped by a caught panic
+    pub(crate) panic_errno: c_int,
+    /// True if the filesystem may be exported over NFS (FUSE_EXPORT_SUPPORT negotiated)
+    pub(crate) export_support: bool,
+    /// True if the kernel treats ENOSYS replied to open as success and stops sending open
+    /// and release requests (FUSE_NO_OPEN_SUPPORT)
+    pub(crate) no_open_support: bool,
+    /// Same as `no_open_support` for opendir and releasedir (FUSE_NO_OPENDIR_SUPPORT)
+    pub(crate) no_opendir_support: bool,
+    /// Operations passed to the filesystem, see `Filesystem::supported_ops()`
+    pub(crate) supported_ops: Ops,
+    /// Max size of write requests, as negotiated during init
+    pub(crate) max_write: u32,
+    /// Max size of write requests to start the init handshake with, see `SessionBuilder`
+    pub(crate) default_max_write: Option<u32>,
+    /// Kernel config negotiated during init
+    pub(crate) negotiated: Arc<NegotiatedConfig>,
+    /// Requests that can be interrupted by the kernel
+    pub(crate) interrupts: Interrupts,
+    /// Observer to report requests and replies to
+    pub(crate) observer: Option<Arc<dyn SessionObserver>>,
+    /// Default ttl for entries replied to lookup and other requests creating entries
+    pub(crate) entry_ttl: Duration,
+    /// Default ttl for attributes replied to getattr, setattr, lookup etc.
+    pub(crate) attr_ttl: Duration,
+    /// Statistics of requests and replies
+    pub(crate) stats: Arc<Counters>,
+    /// Directory handles which are open, if tracking is enabled
+    pub(crate) dir_handles: Option<Arc<DirHandles>>,
+    /// Lookup counts replied to the kernel, if tracking is enabled
+    pub(crate) lookups: Option<Arc<Lookups>>,
+    /// Buffer for combining sequential writes, if enabled
+    pub(crate) write_combining: Option<WriteCombiner>,
+    /// Buffer for receiving requests, allocated on the first request
+    buffer: Vec<u8>,
 }
 
 impl<FS: Filesystem> Session<FS> {
-    /// Create a new session by mounting the given filesystem to the given mountpoint
+    /// Create a new session by mounting the given filesystem to the given mountpoint. The
+    /// mountpoint must be an existing directory or regular file. Mounting over a non-empty
+    /// directory is allowed by the kernel, but libfuse2's fusermount refuses it unless the
+    /// `nonempty` option is given as `MountOption::CUSTOM`.
     pub fn new(
         filesystem: FS,
         mountpoint: &Path,
         options: &[MountOption],
     ) -> io::Result<Session<FS>> {
+        Session::new_with_retry(filesystem, mountpoint, options, &RetryPolicy::none())
+    }
+
+    /// Like `new()`, but retries mounting according to the given policy if the mount fails
+    /// with one of the policy's error codes. Invalid options or mountpoints fail right away.
+    pub(crate) fn new_with_retry(
+        filesystem: FS,
+        mountpoint: &Path,
+        options: &[MountOption],
+        policy: &RetryPolicy,
+    ) -> io::Result<Session<FS>> {
+        check_mount_options(options)?;
+        check_mountpoint(mountpoint)?;
         info!("Mounting {}", mountpoint.display());
         // If AutoUnmount is requested, but not AllowRoot or AllowOther we enforce the ACL
         // ourself and implicitly set AllowOther because fusermount needs allow_root or allow_other
         // to handle the auto_unmount option
-        let (file, mount) = if options.contains(&MountOption::AutoUnmount)
+        let mut mount_options = options.to_vec();
+        if options.contains(&MountOption::AutoUnmount)
             && !(options.contains(&MountOption::AllowRoot)
                 || options.contains(&MountOption::AllowOther))
         {
-            let mut modified_options = options.to_vec();
-            modified_options.push(MountOption::AllowOther);
-            Mount::new(mountpoint, &modified_options)?
-        } else {
-            Mount::new(mountpoint, options)?
-        };
+            mount_options.push(MountOption::AllowOther);
+        }
+        let (file, mount) = policy.retry(|| Mount::new(mountpoint, &mount_options))?;
 
         let ch = Channel::new(file);
-        let allowed = if options.contains(&MountOption::AllowRoot) {
-            SessionACL::RootAndOwner
-        } else if options.contains(&MountOption::AllowOther) {
-            SessionACL::All
-        } else {
-            SessionACL::Owner
-        };
+        let mut se = Session::with_channel(filesystem, ch, Some(mount), mountpoint, options);
+        se.mount_options = mount_options;
+        Ok(se)
+    }
+
+    /// Create a new session for a FUSE device that was already opened and mounted by the
+    /// caller, e.g. a fd passed by systemd socket activation or a container runtime, or
+    /// received over a unix socket from a privileged mount helper (like fusermount does).
+    /// Neither `/dev/fuse` nor the mount helper are used, and the session doesn't unmount the
+    /// filesystem when it ends, the owner of the mount is responsible for that. The init
+    /// handshake is done by the session loop as usual, once `run()` or `spawn()` is called.
+    /// A raw fd can be converted with `File::from_raw_fd()`. The options are not applied to
+    /// the mount, they only determine which users are allowed to access the filesystem
+    /// (`AllowOther` and `AllowRoot`) and should match the options used for mounting.
+    pub fn from_fd(
+        filesystem: FS,
+        fd: File,
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> io::Result<Session<FS>> {
+        check_option_conflicts(options)?;
+        info!(
+            "Using FUSE device fd {} for {}",
+            fd.as_raw_fd(),
+            mountpoint.display()
+        );
+        Ok(Session::with_channel(
+            filesystem,
+            Channel::new(Arc::new(fd)),
+            None,
+            mountpoint,
+            options,
+        ))
+    }
 
-        Ok(Session {
+    fn with_channel(
+        filesystem: FS,
+        ch: Channel,
+        mount: Option<Mount>,
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> Session<FS> {
+        Session {
             filesystem,
             ch,
-            mount: Some(mount),
+            mount,
             mountpoint: mountpoint.to_owned(),
-            allowed,
+            mount_options: options.to_vec(),
+            allowed: SessionACL::from_options(options),
+            read_only: options.contains(&MountOption::RO),
+            catch_panics: true,
+            panic_errno: libc::EIO,
             session_owner: unsafe { libc::geteuid() },
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
             destroyed: false,
-        })
+            init_error: None,
+            export_support: false,
+            no_open_support: false,
+            no_opendir_support: false,
+            supported_ops: Ops::ALL,
+            max_write: 0,
+            default_max_write: None,
+            negotiated: Arc::default(),
+            interrupts: Interrupts::default(),
+            observer: None,
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
+            stats: Arc::default(),
+            dir_handles: if cfg!(debug_assertions) {
+                Some(Arc::default())
+            } else {
+                None
+            },
+            lookups: None,
+            write_combining: None,
+            buffer: vec![],
+        }
     }
 
     /// Return path of the mounted filesystem
@@ -110,49 +485,370 @@ impl<FS: Filesystem> Session<FS> {
         &self.mountpoint
     }
 
+    /// Returns the mount id and device number the kernel assigned to the mount, looked up in
+    /// /proc/self/mountinfo by mountpoint (a stat of the mountpoint would need the session to
+    /// reply to getattr). Fails with NotFound if the filesystem isn't mounted (anymore).
+    #[cfg(target_os = "linux")]
+    pub fn mount_info(&self) -> io::Result<MountInfo> {
+        mount_info(&self.mountpoint)
+    }
+
+    /// Returns the options the filesystem was mounted with. This includes AllowOther if it
+    /// was added implicitly for AutoUnmount, which needs it for fusermount (access is still
+    /// restricted to the owner then). For sessions created with `from_fd()`, these are the
+    /// options given by the caller.
+    pub fn mount_options(&self) -> &[MountOption] {
+        &self.mount_options
+    }
+
+    /// Returns a notifier which can be used to send notifications to the kernel driver.
+    /// The notifier stops working once this session has ended.
+    pub fn notifier(&self) -> Notifier {
+        Notifier::new(self.ch.weak_sender())
+    }
+
+    /// Returns a snapshot of the statistics of this session
+    pub fn stats(&self) -> SessionStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns the kernel config negotiated during init, as replied to the kernel after
+    /// `Filesystem::init()` adjusted it. Returns None before init.
+    pub fn kernel_config(&self) -> Option<KernelConfig> {
+        self.negotiated.get()
+    }
+
+    /// Set an observer that is notified about every request and reply of this session.
+    /// Without an observer, no timing information is collected.
+    pub fn set_observer(&mut self, observer: Arc<dyn SessionObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Set the default time for which the kernel may cache directory entries. It is used by
+    /// replies that don't specify a ttl, like `ReplyEntry::entry_default_ttl()`, and defaults to
+    /// 1 second. Longer ttls avoid lookup requests but delay noticing changes that were not
+    /// made through this mount, `Duration::ZERO` makes the kernel revalidate entries on every
+    /// access.
+    pub fn set_entry_ttl(&mut self, ttl: Duration) {
+        self.entry_ttl = ttl;
+    }
+
+    /// Set the default time for which the kernel may cache file attributes. It is used by
+    /// replies that don't specify a ttl, like `ReplyAttr::attr_default_ttl()`, and defaults to
+    /// 1 second. `Duration::ZERO` makes the kernel request attributes on every access.
+    pub fn set_attr_ttl(&mut self, ttl: Duration) {
+        self.attr_ttl = ttl;
+    }
+
+    /// Reject requests that would modify the filesystem with EROFS before they reach the
+    /// filesystem, like writes, creating, removing or renaming entries, setting attributes
+    /// and extended attributes. Opening files for writing (O_WRONLY or O_RDWR) or with
+    /// O_TRUNC fails as well, read-only opens are passed on, so a read-only filesystem only
+    /// needs to implement the read side. This is enabled by default if the session was created with
+    /// `MountOption::RO`. The kernel already rejects most of these for read-only mounts, but
+    /// this makes the behavior uniform, e.g. for mounts set up by someone else with `from_fd()`.
+    /// Disable it to let the filesystem handle such requests itself.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Catch panics of filesystem methods, so a panic fails only the request it happened in.
+    /// The reply passed to the panicking method is dropped, which replies the error set with
+    /// `set_panic_errno()` (EIO by default) unless the reply was already used. A reply the
+    /// method stored elsewhere before panicking, e.g. to reply from another thread, isn't
+    /// dropped by the panic: its request is only replied once the reply is used or dropped,
+    /// and never if the reply is leaked. The panic is logged and the session goes on with the
+    /// next request, the filesystem must then cope with whatever state the panic left it in.
+    /// Panics in `Filesystem::init()` are never caught, since the session can't continue
+    /// without init. Enabled by default, disable it to let panics end the session loop (and
+    /// a `BackgroundSession` thread) right away. This has no effect if panics abort the
+    /// process (`panic = "abort"`).
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.catch_panics = catch_panics;
+    }
+
+    /// Set the error replied to requests whose filesystem method panicked without using its
+    /// reply, see `set_catch_panics()`. Defaults to EIO.
+    pub fn set_panic_errno(&mut self, errno: c_int) {
+        self.panic_errno = errno;
+    }
+
+    /// Keep track of the file handles replied to opendir, and warn about handles passed to
+    /// readdir, readdirplus or releasedir which aren't open (e.g. used after releasedir), and
+    /// about handles which were never released when the session ends. This helps finding bugs
+    /// in the directory handling of a filesystem. It is enabled by default in debug builds
+    /// and disabled in release builds. Tracking costs a lock per directory request.
+    pub fn set_track_dir_handles(&mut self, track: bool) {
+        if track != self.dir_handles.is_some() {
+            self.dir_handles = if track { Some(Arc::default()) } else { None };
+        }
+    }
+
+    /// Keep track of the lookup counts replied to the kernel (with entries replied to lookup,
+    /// mknod, mkdir, symlink, link, create, tmpfile and readdirplus) and of the forgets
+    /// received, to find drifting lookup count bookkeeping in a filesystem. Forgets of more
+    /// lookups than were replied are logged as warnings, and the inodes which weren't
+    /// forgotten are logged when the session ends. The kernel doesn't send forgets when
+    /// unmounting, so those are the lookup counts the filesystem should have had then.
+    /// Disabled by default, tracking costs a lock per replied entry and forget.
+    pub fn set_track_lookups(&mut self, track: bool) {
+        if track != self.lookups.is_some() {
+            self.lookups = if track { Some(Arc::default()) } else { None };
+        }
+    }
+
+    /// Returns the lookup counts of the inodes which weren't forgotten yet, by inode number,
+    /// or None if tracking is disabled, see `set_track_lookups()`
+    pub fn lookup_counts(&self) -> Option<BTreeMap<u64, u64>> {
+        self.lookups.as_ref().map(|lookups| lookups.counts())
+    }
+
+    /// Combine sequential writes to the same file handle into writes of up to max_size bytes,
+    /// or disable combining with None. Disabled by default.
+    ///
+    /// This is meant for filesystems without the writeback cache, where the kernel sends a
+    /// write request for every write system call, however small. Writes are replied to as
+    /// soon as they are buffered, and the buffered data is passed to the filesystem as a
+    /// single write when the buffer is full, or before the session handles any request
+    /// other than a write continuing the buffered data (same file handle, offset, flags
+    /// and process). This changes when writes become visible: there is no time limit, the
+    /// data reaches the filesystem only with the next request of any kind, which may be
+    /// much later if the filesystem is idle. Closing or syncing the file sends such a
+    /// request (flush or fsync), so the data is written by then at the latest.
+    ///
+    /// As the writes have already succeeded, an error or short write of the combined write
+    /// is reported by failing the next flush or fsync of the file handle instead. That flush
+    /// or fsync is still passed to the filesystem, its reply is only replaced by the error if
+    /// it succeeded. The session doesn't wait for the reply to the
+    /// combined write, so the filesystem may keep the reply and use it after `write()` has
+    /// returned, e.g. from another thread. Errors replied that way are reported by the
+    /// first flush or fsync received after the reply.
+    pub fn set_write_combining(&mut self, max_size: Option<usize>) {
+        if let Some(pending) = self.write_combining.as_mut().and_then(|c| c.take()) {
+            self.write_pending(pending);
+        }
+        self.write_combining = max_size.map(WriteCombiner::new);
+    }
+
+    /// Pass buffered writes to the filesystem without waiting for its reply. Errors and short
+    /// writes are recorded once the filesystem replies, to be reported by the next flush or
+    /// fsync of the file handle.
+    pub(crate) fn write_pending(&mut self, pending: PendingWrite) {
+        let (buf, len) = pending.request();
+        let sender = self.ch.sender();
+        if let Ok(req) = Request::new(sender, self.interrupts.clone(), &buf.as_bytes()[..len]) {
+            req.write_to(&mut self.filesystem, pending.reply_sender());
+        }
+    }
+
+    /// Returns the error of a combined write of the given file handle, if there was one
+    pub(crate) fn take_write_error(&mut self, ino: INodeNo, fh: FileHandle) -> Option<c_int> {
+        let combiner = self.write_combining.as_mut()?;
+        combiner.take_error(ino.into(), fh.into())
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
     /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
+    /// may run concurrent by spawning threads. Requests are received into a single buffer,
+    /// sized to the negotiated max write size after init and reused for every request, so
+    /// receiving a request doesn't allocate. Since only this loop receives requests, there
+    /// is no pool of receive buffers and no buffer count to configure. Once the loop ends,
+    /// because the filesystem was unmounted or an error occurred, the filesystem is
+    /// destroyed.
+    ///
+    /// Requests are dispatched one at a time in the order the kernel sent them, so calls
+    /// for the same inode or file handle are serialized as long as the filesystem replies
+    /// before returning. The session has no multi-threaded or per-inode sharded mode, since
+    /// filesystem methods take `&mut self` and can't be called concurrently. Filesystems replying from worker threads can keep this ordering
+    /// per object by always passing the requests of an inode (or file handle) to the same
+    /// worker, e.g. chosen by the inode number modulo the number of workers, while
+    /// requests for other objects proceed in parallel.
     pub fn run(&mut self) -> io::Result<()> {
+        let result = self.dispatch_loop();
+        self.destroy();
+        result
+    }
+
+    /// Run the session loop like `run()` until one of the given signals (e.g. SIGINT and
+    /// SIGTERM) is received, then unmount the filesystem and return. The session loop also
+    /// ends as usual if the filesystem is unmounted otherwise. Signal handlers are installed
+    /// for the duration of the call and the previous handlers are restored afterwards, only
+    /// one session per process can wait for signals at a time (EBUSY otherwise). Sessions
+    /// created with `from_fd()` can't be unmounted and fail with ENODEV.
+    pub fn run_until_signal(&mut self, signals: &[c_int]) -> io::Result<()> {
+        let mount = self
+            .mount
+            .take()
+            .ok_or_else(|| io::Error::from_raw_os_error(ENODEV))?;
+        let (handlers, receiver) = match SignalHandlers::install(signals) {
+            Ok(handlers) => handlers,
+            Err(err) => {
+                self.mount = Some(mount);
+                return Err(err);
+            }
+        };
+        let mountpoint = self.mountpoint.clone();
+        // Unmounting makes the kernel end the session loop. If the filesystem was unmounted
+        // otherwise, the watcher is woken up without a signal and dropping the mount is a
+        // no-op.
+        let watcher = thread::spawn(move || {
+            if let Some(signal) = receiver.wait() {
+                info!(
+                    "Received signal {}, unmounting {}",
+                    signal,
+                    mountpoint.display()
+                );
+            }
+            drop(mount);
+        });
+        let result = self.run();
+        handlers.wake();
+        let watched = watcher.join();
+        drop(handlers);
+        result.and(watched.map_err(panic_error))
+    }
+
+    fn dispatch_loop(&mut self) -> io::Result<()> {
+        loop {
+            match self.process_one() {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                // Explicitly try again
+                Err(err) if err.raw_os_error() == Some(EAGAIN) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Receive a single request from the kernel and dispatch it to the filesystem. Returns
+    /// false once the session has ended, because the filesystem was unmounted. This can be
+    /// used to drive the session from an event loop instead of `run()`, by waiting for the
+    /// fd of the session (see `AsRawFd`) to become readable. If the fd was set to
+    /// non-blocking mode with `set_nonblocking()` and no request is available, this fails
+    /// with `ErrorKind::WouldBlock` and should be retried once the fd is readable again.
+    /// The crate doesn't depend on an async runtime and has no driver of its own. With tokio,
+    /// wrap the fd in an `AsyncFd`, await readability, and call this until it fails with
+    /// `WouldBlock`, then clear the readiness and await it again.
+    /// A request whose size doesn't match the length in its header is replied with EIO and
+    /// skipped, a read shorter than the request header fails with EPROTO.
+    /// Unlike `run()`, the filesystem is only destroyed when the session is dropped.
+    pub fn process_one(&mut self) -> io::Result<bool> {
         // Buffer for receiving requests from the kernel. Only one is allocated and
         // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer = vec![0; BUFFER_SIZE];
-        let buf = aligned_sub_buf(
-            buffer.deref_mut(),
-            std::mem::align_of::<abi::fuse_in_header>(),
-        );
-        loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(buf) {
-                Ok(size) => match Request::new(self.ch.sender(), &buf[..size]) {
+        // It is only reallocated once, after init, to fit the negotiated max write size.
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let size = if self.initialized {
+            self.buffer_size()
+        } else {
+            INIT_BUFFER_SIZE
+        };
+        if buffer.len() < size {
+            buffer = vec![0; size];
+        }
+        let result = self.receive_and_dispatch(&mut buffer);
+        self.buffer = buffer;
+        result
+    }
+
+    fn receive_and_dispatch(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        let buf = aligned_sub_buf(buffer, std::mem::align_of::<abi::fuse_in_header>());
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        match self.ch.receive(buf) {
+            Ok(size) => {
+                let sender = match &self.observer {
+                    Some(observer) => self.ch.sender().with_observer(observer.clone()),
+                    None => self.ch.sender(),
+                };
+                match Request::new(sender, self.interrupts.clone(), &buf[..size]) {
                     // Dispatch request
-                    Some(req) => req.dispatch(self),
+                    Ok(req) => {
+                        let req = req.with_stats(self.stats.clone());
+                        req.dispatch(self);
+                        // The filesystem refused to initialize, quit the loop. The kernel
+                        // aborts the connection after receiving the error reply.
+                        match self.init_error {
+                            Some(err) => Err(io::Error::from_raw_os_error(err)),
+                            None => Ok(true),
+                        }
+                    }
+                    // The request was replied with EIO, continue with the next one
+                    Err(RequestError::ShortRead(..)) | Err(RequestError::LengthMismatch(..)) => {
+                        Ok(true)
+                    }
+                    // Nothing was read, the fd passed to from_fd() reached its end
+                    Err(RequestError::ShortReadHeader(0)) => Ok(false),
+                    // Without a complete header, there's no unique id to reply to and the
+                    // kernel can't be expected to send well-formed requests anymore
+                    Err(RequestError::ShortReadHeader(_)) => {
+                        Err(io::Error::from_raw_os_error(EPROTO))
+                    }
                     // Quit loop on illegal request
-                    None => break,
-                },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
-                },
+                    Err(_) => Ok(false),
+                }
             }
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => Ok(true),
+                // Interrupted system call, retry
+                Some(EINTR) => Ok(true),
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => Ok(false),
+                // Unhandled errors like EBADF are fatal. EAGAIN means no request is available
+                // on a non-blocking fd, run() retries it.
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Set the fd of the session to non-blocking mode, see `process_one()`
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.ch.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
         }
         Ok(())
     }
 
+    /// Size of the buffer needed to receive requests with the negotiated max write size
+    /// (plus extra space for the alignment of the buffer)
+    fn buffer_size(&self) -> usize {
+        self.max_write as usize + BUFFER_HEADER_SIZE + std::mem::align_of::<abi::fuse_in_header>()
+    }
+
     /// Unmount the filesystem
     pub fn unmount(&mut self) {
         drop(std::mem::take(&mut self.mount));
     }
+
+    /// Call the filesystem's destroy method, unless it was not initialized or already
+    /// destroyed. The session calls this when the kernel sends FUSE_DESTROY, when the
+    /// session loop ends and when it is dropped, whichever happens first.
+    pub(crate) fn destroy(&mut self) {
+        if self.initialized && !self.destroyed {
+            self.destroyed = true;
+            if let Some(pending) = self.write_combining.as_mut().and_then(|c| c.take()) {
+                self.write_pending(pending);
+            }
+            self.filesystem.destroy();
+            if let Some(dir_handles) = &self.dir_handles {
+                dir_handles.warn_unreleased();
+            }
+            if let Some(lookups) = &self.lookups {
+                lookups.log_outstanding();
+            }
+        }
+    }
 }
 
 fn aligned_sub_buf(buf: &mut [u8], alignment: usize) -> &mut [u8] {
@@ -171,37 +867,86 @@ impl<FS: 'static + Filesystem + Send> Session<FS> {
     }
 }
 
+impl<FS: Filesystem> AsRawFd for Session<FS> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ch.as_raw_fd()
+    }
+}
+
 impl<FS: Filesystem> Drop for Session<FS> {
     fn drop(&mut self) {
-        if !self.destroyed {
-            self.filesystem.destroy();
-            self.destroyed = true;
-        }
+        self.destroy();
         info!("Unmounted {}", self.mountpoint().display());
     }
 }
 
+/// How the session loop of a `BackgroundSession` ended, see `BackgroundSession::join()`
+#[derive(Debug)]
+pub enum SessionExit {
+    /// The filesystem was unmounted and the session loop ended normally
+    Unmounted,
+    /// The session loop failed, e.g. with the error returned by the filesystem's init method
+    Error(io::Error),
+    /// The session thread panicked, with the message of the panic
+    Panicked(String),
+}
+
+impl SessionExit {
+    /// Returns Ok for a clean unmount, otherwise the error of the session loop or an error
+    /// with the panic message
+    pub fn into_result(self) -> io::Result<()> {
+        match self {
+            SessionExit::Unmounted => Ok(()),
+            SessionExit::Error(err) => Err(err),
+            SessionExit::Panicked(msg) => Err(panicked_error(&msg)),
+        }
+    }
+
+    /// Returns how the session thread with the given result ended
+    fn from_thread(result: thread::Result<io::Result<()>>) -> Self {
+        match result {
+            Ok(Ok(())) => SessionExit::Unmounted,
+            Ok(Err(err)) => SessionExit::Error(err),
+            Err(panic) => SessionExit::Panicked(panic_message(&*panic).to_string()),
+        }
+    }
+}
+
 /// The background session data structure
 pub struct BackgroundSession {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
     /// Thread guard of the background session
     pub guard: JoinHandle<io::Result<()>>,
-    /// Ensures the filesystem is unmounted when the session ends
-    _mount: Mount,
+    /// Notifier for the background session
+    notifier: Notifier,
+    /// Statistics of the background session
+    stats: Arc<Counters>,
+    /// Kernel config negotiated during init
+    negotiated: Arc<NegotiatedConfig>,
+    /// Options the filesystem was mounted with
+    mount_options: Vec<MountOption>,
+    /// Ensures the filesystem is unmounted when the session ends, unless it was mounted by
+    /// the caller (see `Session::from_fd()`)
+    _mount: Option<Mount>,
 }
 
 impl BackgroundSession {
     /// Create a new background session for the given session by running its
     /// session loop in a background thread. If the returned handle is dropped,
-    /// the filesystem is unmounted and the given session ends.
+    /// the filesystem is unmounted and the given session ends. A filesystem mounted by the
+    /// caller (see `Session::from_fd()`) is not unmounted, the session ends once the caller
+    /// unmounts it.
     pub fn new<FS: Filesystem + Send + 'static>(
         mut se: Session<FS>,
     ) -> io::Result<BackgroundSession> {
         let mountpoint = se.mountpoint().to_path_buf();
+        let notifier = se.notifier();
+        let stats = se.stats.clone();
+        let negotiated = se.negotiated.clone();
+        let mount_options = se.mount_options.clone();
         // Take the fuse_session, so that we can unmount it
         let mount = std::mem::take(&mut se.mount);
-        let mount = mount.ok_or_else(|| io::Error::from_raw_os_error(libc::ENODEV))?;
         let guard = thread::spawn(move || {
             let mut se = se;
             se.run()
@@ -209,21 +954,118 @@ impl BackgroundSession {
         Ok(BackgroundSession {
             mountpoint,
             guard,
+            notifier,
+            stats,
+            negotiated,
+            mount_options,
             _mount: mount,
         })
     }
-    /// Unmount the filesystem and join the background thread.
-    pub fn join(self) {
+
+    /// Returns a notifier for the background session. The notifier stops working
+    /// once the session has ended.
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Returns a snapshot of the statistics of the background session
+    pub fn stats(&self) -> SessionStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns the options the filesystem was mounted with, see `Session::mount_options()`
+    pub fn mount_options(&self) -> &[MountOption] {
+        &self.mount_options
+    }
+
+    /// Returns the mount id and device number of the mount, see `Session::mount_info()`
+    #[cfg(target_os = "linux")]
+    pub fn mount_info(&self) -> io::Result<MountInfo> {
+        mount_info(&self.mountpoint)
+    }
+
+    /// Returns the kernel config negotiated during init, see `Session::kernel_config()`.
+    /// Init happens in the background thread, so this returns None until it completed.
+    pub fn kernel_config(&self) -> Option<KernelConfig> {
+        self.negotiated.get()
+    }
+
+    /// Wait until init completed and return the negotiated kernel config. Returns None if
+    /// init didn't complete within the timeout, e.g. because the filesystem's init failed.
+    pub fn wait_for_init(&self, timeout: Duration) -> Option<KernelConfig> {
+        self.negotiated.wait(timeout)
+    }
+
+    /// Unmount the filesystem and join the background thread. Returns how the session
+    /// loop ended: with a clean unmount, with an error like the one returned by the
+    /// filesystem's init method, or with a panic of the session thread. Use
+    /// `SessionExit::into_result()` to treat the latter two as errors. Errors while
+    /// unmounting are logged. For a filesystem mounted by the caller, this waits until the
+    /// caller unmounted it.
+    pub fn join(self) -> SessionExit {
         let Self {
             mountpoint: _,
             guard,
+            notifier: _,
+            stats: _,
+            negotiated: _,
+            mount_options: _,
+            _mount,
+        } = self;
+        drop(_mount);
+        SessionExit::from_thread(guard.join())
+    }
+
+    /// Unmount the filesystem even if it is busy and join the background thread, for when
+    /// `join()` or dropping the session would fail to unmount because processes still use
+    /// the filesystem. See `join()` for the result. On Linux, the filesystem is detached
+    /// lazily (with `fusermount -u -z` for non-root users) and the connection is aborted if
+    /// the process may do so (usually only root), otherwise the session loop only ends once
+    /// the last file on the filesystem is closed. Other systems unmount with MNT_FORCE.
+    /// Returns an error without joining if the unmount failed. Sessions created with
+    /// `Session::from_fd()` can't be unmounted and fail with ENODEV.
+    pub fn force_unmount(self) -> io::Result<SessionExit> {
+        let Self {
+            mountpoint,
+            guard,
+            notifier: _,
+            stats: _,
+            negotiated: _,
+            mount_options: _,
             _mount,
         } = self;
+        if _mount.is_none() {
+            return Err(io::Error::from_raw_os_error(ENODEV));
+        }
+        force_unmount(&mountpoint)?;
+        let exit = SessionExit::from_thread(guard.join());
+        // Not mounted anymore, so dropping the mount doesn't unmount anything
         drop(_mount);
-        guard.join().unwrap().unwrap();
+        Ok(exit)
+    }
+}
+
+/// Returns the message of a panic, if it has one
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => match panic.downcast_ref::<String>() {
+            Some(msg) => msg.as_str(),
+            None => "unknown panic",
+        },
     }
 }
 
+/// Convert the panic of a session thread to an error, keeping the panic message if possible
+fn panic_error(panic: Box<dyn Any + Send>) -> io::Error {
+    panicked_error(panic_message(&*panic))
+}
+
+/// Returns the error for a session thread that panicked with the given message
+fn panicked_error(msg: &str) -> io::Error {
+    io::Error::other(format!("Session thread panicked: {}", msg))
+}
+
 // replace with #[derive(Debug)] if Debug ever gets implemented for
 // thread_scoped::JoinGuard
 impl<'a> fmt::Debug for BackgroundSession {
@@ -235,3 +1077,1781 @@ impl<'a> fmt::Debug for BackgroundSession {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+    use std::ffi::{OsStr, OsString};
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use libc::{c_int, ENOENT};
+    use zerocopy::AsBytes;
+
+    use super::{RetryPolicy, Session, SessionACL, SessionBuilder, SessionExit};
+    use crate::channel::Channel;
+    use crate::request::Request;
+    use crate::{
+        CallbackFilesystem, FileAttrBuilder, FileType, Filesystem, InodeTable, InterruptToken,
+        KernelConfig, MountOption, Ops, ReplyAttr, ReplyCreate, ReplyData, ReplyEmpty, ReplyEntry,
+        ReplyOpen, ReplyWrite, TimeOrNow,
+    };
+
+    #[derive(Default)]
+    struct DestroyCounter(usize);
+
+    impl Filesystem for DestroyCounter {
+        fn destroy(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    /// Records calls to fsync, fsyncdir and syncfs as (operation, ino, fh, datasync)
+    #[derive(Default)]
+    struct SyncRecorder(Vec<(&'static str, u64, u64, bool)>);
+
+    impl Filesystem for SyncRecorder {
+        fn fsync(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            datasync: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("fsync", ino, fh, datasync));
+            reply.ok();
+        }
+
+        fn fsyncdir(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            datasync: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("fsyncdir", ino, fh, datasync));
+            reply.ok();
+        }
+
+        fn syncfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyEmpty) {
+            self.0.push(("syncfs", ino, 0, false));
+            reply.ok();
+        }
+    }
+
+    /// Records writes and fsyncs, failing writes with the given error or keeping their
+    /// replies to reply later
+    #[derive(Default)]
+    struct Writes {
+        ops: Vec<(&'static str, i64, Vec<u8>)>,
+        error: Option<c_int>,
+        defer: bool,
+        deferred: Vec<ReplyWrite>,
+    }
+
+    impl Filesystem for Writes {
+        fn write(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            self.ops.push(("write", offset, data.to_vec()));
+            match self.error {
+                _ if self.defer => self.deferred.push(reply),
+                Some(err) => reply.error(err),
+                None => reply.written(data.len() as u32),
+            }
+        }
+
+        fn fsync(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _datasync: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.ops.push(("fsync", 0, vec![]));
+            reply.ok();
+        }
+    }
+
+    /// Records the address of the data of the last write
+    #[derive(Default)]
+    struct WriteAddress(usize);
+
+    impl Filesystem for WriteAddress {
+        fn write(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            self.0 = data.as_ptr() as usize;
+            reply.written(data.len() as u32);
+        }
+    }
+
+    /// Stores symlink targets, the symlink with inode n has the target at index n - 2
+    #[derive(Default)]
+    struct Symlinks(Vec<OsString>);
+
+    impl Filesystem for Symlinks {
+        fn symlink(
+            &mut self,
+            _req: &Request<'_>,
+            _parent: u64,
+            _name: &OsStr,
+            link: &Path,
+            reply: ReplyEntry,
+        ) {
+            self.0.push(link.as_os_str().to_owned());
+            let ino = self.0.len() as u64 + 1;
+            let attr = FileAttrBuilder::new(ino, FileType::Symlink);
+            reply.entry(&Duration::ZERO, &attr.into(), 0);
+        }
+
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            reply.data(self.0[ino as usize - 2].as_bytes());
+        }
+    }
+
+    /// Stores the names of created files, the file with inode n has the name at index n - 2
+    #[derive(Default)]
+    struct Files(Vec<OsString>);
+
+    impl Filesystem for Files {
+        fn create(
+            &mut self,
+            _req: &Request<'_>,
+            _parent: u64,
+            name: &OsStr,
+            _mode: u32,
+            _umask: u32,
+            flags: i32,
+            reply: ReplyCreate,
+        ) {
+            assert_ne!(flags & libc::O_CREAT, 0);
+            let index = match self.0.iter().position(|n| n == name) {
+                Some(_) if flags & libc::O_EXCL != 0 => return reply.error(libc::EEXIST),
+                Some(index) => index,
+                None => {
+                    self.0.push(name.to_owned());
+                    self.0.len() - 1
+                }
+            };
+            let ino = index as u64 + 2;
+            let attr = FileAttrBuilder::new(ino, FileType::RegularFile);
+            reply.created(&Duration::ZERO, &attr.into(), 0, ino, 0);
+        }
+
+        fn tmpfile(
+            &mut self,
+            _req: &Request<'_>,
+            _parent: u64,
+            _mode: u32,
+            _umask: u32,
+            _flags: i32,
+            reply: ReplyCreate,
+        ) {
+            // Unnamed files get an empty name
+            self.0.push(OsString::new());
+            let ino = self.0.len() as u64 + 1;
+            let attr = FileAttrBuilder::new(ino, FileType::RegularFile);
+            reply.created(&Duration::ZERO, &attr.into(), 0, ino, 0);
+        }
+    }
+
+    /// Opens files with a new fh each time and records the ino and fh of file operations
+    #[derive(Default)]
+    struct Handles(Vec<(&'static str, u64, u64)>);
+
+    impl Filesystem for Handles {
+        fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+            let fh = 100 + self.0.len() as u64;
+            self.0.push(("open", ino, fh));
+            reply.opened(fh, 0);
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            _offset: i64,
+            _size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            self.0.push(("read", ino, fh));
+            reply.data(&[]);
+        }
+
+        fn flush(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            _lock_owner: u64,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("flush", ino, fh));
+            reply.ok();
+        }
+
+        fn release(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            _flush: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("release", ino, fh));
+            reply.ok();
+        }
+
+        fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+            let fh = 100 + self.0.len() as u64;
+            self.0.push(("opendir", ino, fh));
+            reply.opened(fh, 0);
+        }
+    }
+
+    /// Replies to setattr with the mtime that was set
+    struct Times;
+
+    impl Filesystem for Times {
+        fn setattr(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _mode: Option<u32>,
+            _uid: Option<u32>,
+            _gid: Option<u32>,
+            _size: Option<u64>,
+            _atime: Option<TimeOrNow>,
+            mtime: Option<TimeOrNow>,
+            _ctime: Option<SystemTime>,
+            _fh: Option<u64>,
+            _crtime: Option<SystemTime>,
+            _chgtime: Option<SystemTime>,
+            _bkuptime: Option<SystemTime>,
+            _flags: Option<u32>,
+            reply: ReplyAttr,
+        ) {
+            let mtime = match mtime {
+                Some(TimeOrNow::SpecificTime(mtime)) => mtime,
+                _ => panic!("Unexpected mtime {:?}", mtime),
+            };
+            let attr = FileAttrBuilder::new(ino, FileType::RegularFile).mtime(mtime);
+            reply.attr(&Duration::ZERO, &attr.into());
+        }
+    }
+
+    /// Records the lock owners passed to flush and release
+    #[derive(Default)]
+    struct LockOwners(Vec<(&'static str, Option<u64>)>);
+
+    impl Filesystem for LockOwners {
+        fn flush(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            lock_owner: u64,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("flush", Some(lock_owner)));
+            reply.ok();
+        }
+
+        fn release(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _flags: i32,
+            lock_owner: Option<u64>,
+            _flush: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("release", lock_owner));
+            reply.ok();
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            _size: u32,
+            _flags: i32,
+            lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            self.0.push(("read", lock_owner));
+            reply.data(&[]);
+        }
+
+        fn write(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            self.0.push(("write", lock_owner));
+            reply.written(data.len() as u32);
+        }
+    }
+
+    /// Records the names passed to lookup, mkdir, unlink and rename as raw bytes
+    #[derive(Default)]
+    struct NameRecorder(Vec<(&'static str, Vec<u8>)>);
+
+    impl Filesystem for NameRecorder {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEntry) {
+            self.0.push(("lookup", name.as_bytes().to_vec()));
+            reply.error(libc::ENOENT);
+        }
+
+        fn mkdir(
+            &mut self,
+            _req: &Request<'_>,
+            _parent: u64,
+            name: &OsStr,
+            _mode: u32,
+            _umask: u32,
+            reply: ReplyEntry,
+        ) {
+            self.0.push(("mkdir", name.as_bytes().to_vec()));
+            reply.error(libc::EPERM);
+        }
+
+        fn unlink(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            self.0.push(("unlink", name.as_bytes().to_vec()));
+            reply.ok();
+        }
+
+        fn rename(
+            &mut self,
+            _req: &Request<'_>,
+            _parent: u64,
+            name: &OsStr,
+            _newparent: u64,
+            newname: &OsStr,
+            _flags: u32,
+            reply: ReplyEmpty,
+        ) {
+            self.0.push(("rename", name.as_bytes().to_vec()));
+            self.0.push(("rename", newname.as_bytes().to_vec()));
+            reply.ok();
+        }
+    }
+
+    /// Grants all access requests, recording their masks
+    #[derive(Default)]
+    struct AccessRecorder(Vec<i32>);
+
+    impl Filesystem for AccessRecorder {
+        fn access(&mut self, _req: &Request<'_>, _ino: u64, mask: i32, reply: ReplyEmpty) {
+            self.0.push(mask);
+            reply.ok();
+        }
+    }
+
+    /// Replies EXDEV to all renames to another directory
+    struct CrossDevice;
+
+    impl Filesystem for CrossDevice {
+        fn rename(
+            &mut self,
+            _req: &Request<'_>,
+            parent: u64,
+            _name: &OsStr,
+            newparent: u64,
+            _newname: &OsStr,
+            _flags: u32,
+            reply: ReplyEmpty,
+        ) {
+            if parent == newparent {
+                reply.ok();
+            } else {
+                reply.error(libc::EXDEV);
+            }
+        }
+    }
+
+    /// Allocates inodes for looked up names, replies ENOENT for forgotten inodes
+    struct Inodes(InodeTable<OsString>);
+
+    impl Filesystem for Inodes {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEntry) {
+            let (ino, generation) = self.0.allocate(name.to_owned());
+            let attr = FileAttrBuilder::new(ino, FileType::RegularFile);
+            reply.entry(&Duration::ZERO, &attr.into(), generation);
+        }
+
+        fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+            self.0.forget(ino, nlookup);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+            match self.0.lookup(ino) {
+                Some(_) => reply.attr(
+                    &Duration::ZERO,
+                    &FileAttrBuilder::new(ino, FileType::RegularFile).into(),
+                ),
+                None => reply.error(ENOENT),
+            }
+        }
+    }
+
+    /// Waits for locks forever, replying EINTR once interrupted. Keeps the interrupt tokens
+    /// of the waiting requests.
+    #[derive(Default)]
+    struct LockWaiter(Vec<InterruptToken>);
+
+    impl Filesystem for LockWaiter {
+        fn setlk(
+            &mut self,
+            req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _typ: i32,
+            _pid: u32,
+            sleep: bool,
+            reply: ReplyEmpty,
+        ) {
+            assert!(sleep);
+            let token = req.interrupt_token();
+            token.on_interrupt(move || reply.error(libc::EINTR));
+            self.0.push(token);
+        }
+    }
+
+    /// Replies POLLIN to polls and keeps the kernel handles of polls which wait for events
+    #[cfg(feature = "abi-7-11")]
+    #[derive(Default)]
+    struct Poller(Vec<u64>);
+
+    #[cfg(feature = "abi-7-11")]
+    impl Filesystem for Poller {
+        fn poll(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            kh: u64,
+            _events: u32,
+            flags: u32,
+            reply: crate::ReplyPoll,
+        ) {
+            if flags & crate::consts::FUSE_POLL_SCHEDULE_NOTIFY != 0 {
+                self.0.push(kh);
+            }
+            reply.poll(libc::POLLIN as u32);
+        }
+    }
+
+    /// Panics in readlink, after replying for inode 3
+    struct Panicker;
+
+    impl Filesystem for Panicker {
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            if ino == 3 {
+                reply.data(b"target");
+            }
+            panic!("readlink of inode {}", ino);
+        }
+    }
+
+    /// Panics in init
+    struct InitPanicker;
+
+    impl Filesystem for InitPanicker {
+        fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+            panic!("init");
+        }
+    }
+
+    /// Records the file handle and flags of ioctls
+    #[cfg(feature = "abi-7-18")]
+    #[derive(Default)]
+    struct Ioctls(Vec<(u64, u32)>);
+
+    #[cfg(feature = "abi-7-18")]
+    impl Filesystem for Ioctls {
+        fn ioctl(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            fh: u64,
+            flags: u32,
+            _cmd: u32,
+            _in_data: &[u8],
+            _out_size: u32,
+            reply: crate::ReplyIoctl,
+        ) {
+            self.0.push((fh, flags));
+            reply.ioctl(0, &[]);
+        }
+    }
+
+    /// Keeps the replies of readlink, to reply them later
+    #[derive(Default)]
+    struct Deferred(Vec<(u64, ReplyData)>);
+
+    impl Filesystem for Deferred {
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            self.0.push((ino, reply));
+        }
+    }
+
+    /// Replies to canonical path requests with a path on the storage backing the mount
+    #[cfg(target_os = "android")]
+    struct CanonicalPaths;
+
+    #[cfg(target_os = "android")]
+    impl Filesystem for CanonicalPaths {
+        fn canonical_path(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            reply.canonical_path(format!("/data/media/0/{}", ino));
+        }
+    }
+
+    /// Declares the given operations and requests the given capabilities in init
+    struct DeclaredOps(Ops, u32);
+
+    impl Filesystem for DeclaredOps {
+        fn supported_ops(&self) -> Ops {
+            self.0
+        }
+
+        fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+            config.add_capabilities(self.1).unwrap();
+            Ok(())
+        }
+
+        fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
+            reply.data(b"target");
+        }
+    }
+
+    /// Records lookups by name and by inode
+    #[derive(Default)]
+    struct Exported(Vec<(u64, OsString)>);
+
+    impl Filesystem for Exported {
+        fn supported_ops(&self) -> Ops {
+            Ops::LOOKUP | Ops::LOOKUP_INODE
+        }
+
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            self.0.push((parent, name.to_owned()));
+            reply.error(ENOENT);
+        }
+
+        fn lookup_inode(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyEntry) {
+            self.0.push((ino, OsString::new()));
+            reply.error(libc::ESTALE);
+        }
+    }
+
+    /// Create a session which writes replies to a temporary file instead of a mount
+    fn session<FS: Filesystem>(filesystem: FS) -> Session<FS> {
+        session_with_output(filesystem).0
+    }
+
+    /// Create a session and return the file its replies are written to
+    fn session_with_output<FS: Filesystem>(filesystem: FS) -> (Session<FS>, File) {
+        let file = tempfile::tempfile().unwrap();
+        let output = file.try_clone().unwrap();
+        let ch = Channel::new(Arc::new(file));
+        let se = Session::with_channel(filesystem, ch, None, Path::new("/"), &[]);
+        (se, output)
+    }
+
+    /// Read all replies written to the given file
+    fn replies(output: &mut File) -> Vec<Vec<u8>> {
+        let mut data = vec![];
+        output.seek(SeekFrom::Start(0)).unwrap();
+        output.read_to_end(&mut data).unwrap();
+        let mut replies = vec![];
+        while !data.is_empty() {
+            let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+            replies.push(data.drain(..len).collect());
+        }
+        replies
+    }
+
+    /// Encode a request as the kernel would send it
+    fn request_bytes(opcode: u32, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+        let len = 40 + arg.len();
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(len as u32).to_ne_bytes());
+        bytes.extend_from_slice(&opcode.to_ne_bytes());
+        bytes.extend_from_slice(&1u64.to_ne_bytes()); // unique
+        bytes.extend_from_slice(&nodeid.to_ne_bytes());
+        bytes.extend_from_slice(&[0; 16]); // uid, gid, pid, padding
+        bytes.extend_from_slice(arg);
+        bytes
+    }
+
+    /// Dispatch a request with the given opcode and arguments
+    fn dispatch<FS: Filesystem>(se: &mut Session<FS>, opcode: u32, nodeid: u64, arg: &[u8]) {
+        let bytes = request_bytes(opcode, nodeid, arg);
+        let len = bytes.len();
+        // Copy to an aligned buffer
+        let mut buf = vec![0u64; len / 8 + 1];
+        buf.as_bytes_mut()[..len].copy_from_slice(&bytes);
+        let req = Request::new(
+            se.ch.sender(),
+            se.interrupts.clone(),
+            &buf.as_bytes()[..len],
+        );
+        let req = req.unwrap().with_stats(se.stats.clone());
+        req.dispatch(se);
+    }
+
+    /// Arguments of a FUSE_INIT for ABI 7.8
+    fn init_in() -> Vec<u8> {
+        let mut arg = vec![];
+        for x in &[7u32, 8, 4096, 0] {
+            arg.extend_from_slice(&x.to_ne_bytes()); // major, minor, max_readahead, flags
+        }
+        arg
+    }
+
+    fn init<FS: Filesystem>(se: &mut Session<FS>) {
+        dispatch(se, 26, 0, &init_in());
+        assert!(se.initialized);
+    }
+
+    /// Arguments of FUSE_FSYNC and FUSE_FSYNCDIR
+    fn fsync_in(fh: u64, fsync_flags: u32) -> Vec<u8> {
+        let mut arg = fh.to_ne_bytes().to_vec();
+        arg.extend_from_slice(&fsync_flags.to_ne_bytes());
+        arg.extend_from_slice(&[0; 4]); // padding
+        arg
+    }
+
+    /// Arguments of a write of the given data to fh 3
+    fn write_in(offset: i64, data: &[u8]) -> Vec<u8> {
+        let mut arg = 3u64.to_ne_bytes().to_vec(); // fh
+        arg.extend_from_slice(&offset.to_ne_bytes());
+        arg.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        arg.extend_from_slice(&[0; 4]); // write_flags
+        if cfg!(feature = "abi-7-9") {
+            arg.extend_from_slice(&[0; 16]); // lock_owner, flags, padding
+        }
+        arg.extend_from_slice(data);
+        arg
+    }
+
+    #[test]
+    fn write_data_not_copied() {
+        let mut se = session(WriteAddress::default());
+        init(&mut se);
+        let bytes = request_bytes(16, 2, &write_in(0, b"data"));
+        let len = bytes.len();
+        let mut buf = vec![0u64; len / 8 + 1];
+        buf.as_bytes_mut()[..len].copy_from_slice(&bytes);
+        let req = Request::new(
+            se.ch.sender(),
+            se.interrupts.clone(),
+            &buf.as_bytes()[..len],
+        );
+        req.unwrap().dispatch(&mut se);
+        // The data is passed to the filesystem in place, at the end of the request
+        assert_eq!(se.filesystem.0, buf.as_ptr() as usize + len - 4);
+    }
+
+    #[test]
+    fn write_combining() {
+        let (mut se, mut output) = session_with_output(Writes::default());
+        se.set_write_combining(Some(8));
+        init(&mut se);
+        dispatch(&mut se, 16, 2, &write_in(0, b"ab"));
+        dispatch(&mut se, 16, 2, &write_in(2, b"cd"));
+        assert!(se.filesystem.ops.is_empty());
+        dispatch(&mut se, 20, 2, &fsync_in(3, 0));
+        // Not contiguous
+        dispatch(&mut se, 16, 2, &write_in(0, b"ef"));
+        dispatch(&mut se, 16, 2, &write_in(4, b"gh"));
+        // Too large to be combined
+        dispatch(&mut se, 16, 2, &write_in(6, b"ijklmnop"));
+        let expected = [
+            ("write", 0, b"abcd".to_vec()),
+            ("fsync", 0, vec![]),
+            ("write", 0, b"ef".to_vec()),
+            ("write", 4, b"gh".to_vec()),
+            ("write", 6, b"ijklmnop".to_vec()),
+        ];
+        assert_eq!(se.filesystem.ops, expected);
+        // Every write is replied with its size
+        let all = replies(&mut output);
+        let written: Vec<_> = [&all[1..3], &all[4..]]
+            .concat()
+            .iter()
+            .map(|reply| u32::from_ne_bytes(reply[16..20].try_into().unwrap()))
+            .collect();
+        assert_eq!(written, [2, 2, 2, 2, 8]);
+
+        // An error of the combined write is reported by the next fsync, which still reaches
+        // the filesystem
+        se.filesystem.ops.clear();
+        se.filesystem.error = Some(libc::ENOSPC);
+        dispatch(&mut se, 16, 2, &write_in(0, b"ab"));
+        dispatch(&mut se, 20, 2, &fsync_in(3, 0));
+        dispatch(&mut se, 20, 2, &fsync_in(3, 0));
+        let expected = [
+            ("write", 0, b"ab".to_vec()),
+            ("fsync", 0, vec![]),
+            ("fsync", 0, vec![]),
+        ];
+        assert_eq!(se.filesystem.ops, expected);
+        let all = replies(&mut output);
+        let error = |reply: &[u8]| i32::from_ne_bytes(reply[4..8].try_into().unwrap());
+        let n = all.len();
+        assert_eq!(error(&all[n - 2]), -libc::ENOSPC);
+        assert_eq!(error(&all[n - 1]), 0);
+
+        // The combined write may be replied after the filesystem method returned
+        se.filesystem.error = None;
+        se.filesystem.defer = true;
+        dispatch(&mut se, 16, 2, &write_in(0, b"ab"));
+        dispatch(&mut se, 20, 2, &fsync_in(3, 0));
+        se.filesystem.deferred.pop().unwrap().error(libc::ENOSPC);
+        dispatch(&mut se, 20, 2, &fsync_in(3, 0));
+        let all = replies(&mut output);
+        let n = all.len();
+        assert_eq!(error(&all[n - 2]), 0);
+        assert_eq!(error(&all[n - 1]), -libc::ENOSPC);
+        se.filesystem.defer = false;
+
+        // Buffered data is written when combining is disabled
+        dispatch(&mut se, 16, 2, &write_in(0, b"ab"));
+        se.set_write_combining(None);
+        assert_eq!(se.filesystem.ops.last().unwrap().2, b"ab");
+    }
+
+    #[test]
+    fn destroy_called_once() {
+        let mut se = session(DestroyCounter::default());
+        init(&mut se);
+        dispatch(&mut se, 38, 0, &[]);
+        dispatch(&mut se, 38, 0, &[]);
+        assert_eq!(se.filesystem.0, 1);
+        se.destroy();
+        assert_eq!(se.filesystem.0, 1);
+    }
+
+    #[test]
+    fn no_destroy_without_init() {
+        let mut se = session(DestroyCounter::default());
+        se.destroy();
+        assert_eq!(se.filesystem.0, 0);
+    }
+
+    #[test]
+    fn fsyncdir_dispatch() {
+        let mut se = session(SyncRecorder::default());
+        init(&mut se);
+        dispatch(&mut se, 30, 2, &fsync_in(0x11, 0));
+        dispatch(&mut se, 30, 2, &fsync_in(0x11, 1));
+        assert_eq!(
+            se.filesystem.0,
+            [("fsyncdir", 2, 0x11, false), ("fsyncdir", 2, 0x11, true)]
+        );
+    }
+
+    #[test]
+    fn fsync_and_fsyncdir_are_distinct() {
+        let mut se = session(SyncRecorder::default());
+        init(&mut se);
+        dispatch(&mut se, 20, 3, &fsync_in(0x22, 1));
+        dispatch(&mut se, 30, 2, &fsync_in(0x11, 0));
+        assert_eq!(
+            se.filesystem.0,
+            [("fsync", 3, 0x22, true), ("fsyncdir", 2, 0x11, false)]
+        );
+    }
+
+    #[test]
+    fn syncfs_dispatch() {
+        let (mut se, mut output) = session_with_output(SyncRecorder::default());
+        init(&mut se);
+        // fuse_syncfs_in: padding
+        dispatch(&mut se, 50, 1, &[0; 8]);
+        assert_eq!(se.filesystem.0, [("syncfs", 1, 0, false)]);
+        let all = replies(&mut output);
+        assert_eq!(all[1].len(), 16);
+        assert_eq!(&all[1][4..8], &0i32.to_ne_bytes());
+
+        // The default implementation isn't supported
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        init(&mut se);
+        dispatch(&mut se, 50, 1, &[0; 8]);
+        assert_eq!(
+            &replies(&mut output)[1][4..8],
+            &(-libc::ENOSYS).to_ne_bytes()
+        );
+    }
+
+    #[test]
+    fn session_stats() {
+        let mut se = session(DestroyCounter::default());
+        init(&mut se);
+        dispatch(&mut se, 20, 3, &fsync_in(0x22, 0));
+        dispatch(&mut se, 2, 3, &1u64.to_ne_bytes()); // forget
+        let stats = se.stats();
+        assert_eq!(
+            stats.requests.into_iter().collect::<Vec<_>>(),
+            [(2, 1), (20, 1), (26, 1)]
+        );
+        assert_eq!(
+            stats.errors.into_iter().collect::<Vec<_>>(),
+            [(libc::ENOSYS, 1)]
+        );
+        assert_eq!(stats.in_flight, 0);
+    }
+
+    #[test]
+    fn readlink_non_utf8_target() {
+        let (mut se, mut output) = session_with_output(Symlinks::default());
+        init(&mut se);
+        let mut target = b"/mnt/caf\xe9/\xff\xfe".to_vec();
+        target.extend(vec![b'x'; 4000]);
+        let mut arg = b"link\0".to_vec();
+        arg.extend_from_slice(&target);
+        arg.push(0);
+        dispatch(&mut se, 6, 1, &arg); // symlink
+        assert_eq!(se.filesystem.0, [OsString::from_vec(target.clone())]);
+        dispatch(&mut se, 5, 2, &[]); // readlink
+        let replies = replies(&mut output);
+        assert_eq!(replies.len(), 3);
+        // Error of the reply, followed by the target without a NUL terminator
+        assert_eq!(replies[2][4..8], [0; 4]);
+        assert_eq!(replies[2][16..], target[..]);
+    }
+
+    #[cfg(target_os = "android")]
+    #[test]
+    fn canonical_path() {
+        let (mut se, mut output) = session_with_output(CanonicalPaths);
+        init(&mut se);
+        dispatch(&mut se, 2016, 5, &[]); // canonical path
+        let replies = replies(&mut output);
+        assert_eq!(replies.len(), 2);
+        // Error of the reply, followed by the NUL terminated path
+        assert_eq!(replies[1][4..8], [0; 4]);
+        assert_eq!(replies[1][16..], b"/data/media/0/5\0"[..]);
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        // "café" in Latin-1 and bytes which are never valid in UTF-8
+        let latin1: &[u8] = b"caf\xe9";
+        let invalid: &[u8] = b"\xff\xfe\xc0";
+        let name = |name: &[u8]| [name, b"\0"].concat();
+        let mut se = session(NameRecorder::default());
+        init(&mut se);
+        dispatch(&mut se, 1, 1, &name(latin1)); // lookup
+        dispatch(&mut se, 9, 1, &[&[0; 8], &name(invalid)[..]].concat()); // mkdir
+        dispatch(&mut se, 10, 1, &name(invalid)); // unlink
+        let rename = [&1u64.to_ne_bytes(), &name(latin1)[..], &name(invalid)].concat();
+        dispatch(&mut se, 12, 1, &rename);
+        assert_eq!(
+            se.filesystem.0,
+            [
+                ("lookup", latin1.to_vec()),
+                ("mkdir", invalid.to_vec()),
+                ("unlink", invalid.to_vec()),
+                ("rename", latin1.to_vec()),
+                ("rename", invalid.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_fd() {
+        let fd = tempfile::tempfile().unwrap();
+        let options = [MountOption::AllowRoot];
+        let se = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &options);
+        let se = se.unwrap();
+        assert_eq!(se.allowed, SessionACL::RootAndOwner);
+        assert!(se.mount.is_none());
+        assert_eq!(se.mountpoint(), Path::new("/mnt"));
+
+        let mut se = se;
+        init(&mut se);
+        assert_eq!(se.proto_major, 7);
+
+        let fd = tempfile::tempfile().unwrap();
+        let options = [MountOption::RO, MountOption::RW];
+        let err = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &options);
+        assert_eq!(err.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn process_one() {
+        use std::io::Write;
+
+        let mut fd = tempfile::tempfile().unwrap();
+        fd.write_all(&request_bytes(26, 0, &init_in())).unwrap();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        let mut se = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &[]);
+        let se = se.as_mut().unwrap();
+        se.set_nonblocking(true).unwrap();
+        assert!(se.process_one().unwrap());
+        assert!(se.initialized);
+        assert_eq!(se.buffer.len(), super::INIT_BUFFER_SIZE);
+        // The reply was appended to the file, there are no further requests
+        assert!(!se.process_one().unwrap());
+        assert_eq!(se.buffer.len(), se.buffer_size());
+        // The buffer is reused
+        let buffer = se.buffer.as_ptr();
+        assert!(!se.process_one().unwrap());
+        assert_eq!(se.buffer.as_ptr(), buffer);
+    }
+
+    #[test]
+    fn short_read() {
+        use std::io::Write;
+
+        let request = request_bytes(26, 0, &init_in());
+        let mut fd = tempfile::tempfile().unwrap();
+        fd.write_all(&request[..20]).unwrap();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        let se = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &[]);
+        let mut se = se.unwrap();
+        let err = se.process_one().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPROTO));
+        // The end of the input ends the session as usual
+        assert!(!se.process_one().unwrap());
+    }
+
+    #[test]
+    fn background_session_exit() {
+        use std::io::Write;
+
+        let background = |fs, data: &[u8], catch_panics| {
+            let mut fd = tempfile::tempfile().unwrap();
+            fd.write_all(data).unwrap();
+            fd.seek(SeekFrom::Start(0)).unwrap();
+            let mut se = Session::from_fd(fs, fd, Path::new("/mnt"), &[]).unwrap();
+            se.set_catch_panics(catch_panics);
+            se.spawn().unwrap().join()
+        };
+        let init = request_bytes(26, 0, &init_in());
+        // The end of the input ends the session like an unmount
+        match background(InitPanicker, &[], false) {
+            SessionExit::Unmounted => (),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+        match background(InitPanicker, &init[..20], false) {
+            SessionExit::Error(err) => assert_eq!(err.raw_os_error(), Some(libc::EPROTO)),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+        let exit = background(InitPanicker, &init, false);
+        match &exit {
+            SessionExit::Panicked(msg) => assert_eq!(msg, "init"),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+        let err = exit.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "Session thread panicked: init");
+    }
+
+    #[test]
+    fn length_mismatch() {
+        use std::io::Write;
+
+        let mut request = request_bytes(26, 0, &init_in());
+        request[..4].copy_from_slice(&64u32.to_ne_bytes());
+        let mut fd = tempfile::tempfile().unwrap();
+        fd.write_all(&request).unwrap();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        let mut output = fd.try_clone().unwrap();
+        let se = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &[]);
+        let mut se = se.unwrap();
+        assert!(se.process_one().unwrap());
+        assert!(!se.initialized);
+
+        // The reply is appended to the request
+        let mut reply = vec![];
+        output.seek(SeekFrom::Start(request.len() as u64)).unwrap();
+        output.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply.len(), 16);
+        assert_eq!(reply[4..8], (-libc::EIO).to_ne_bytes());
+        assert_eq!(reply[8..16], 1u64.to_ne_bytes()); // unique
+    }
+
+    #[test]
+    fn interrupted_receive() {
+        use std::io::Write;
+        use std::os::unix::io::FromRawFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(_signal: libc::c_int) {
+            SIGNALS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        // Without SA_RESTART, so that the blocking read of the session fails with EINTR
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = count as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::sigaction(libc::SIGUSR2, &action, &mut previous) },
+            0
+        );
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (read, mut write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let thread = unsafe { libc::pthread_self() };
+        let kernel = std::thread::spawn(move || {
+            for _ in 0..5 {
+                std::thread::sleep(Duration::from_millis(10));
+                unsafe { libc::pthread_kill(thread, libc::SIGUSR2) };
+            }
+            write.write_all(&request_bytes(26, 0, &init_in())).unwrap();
+            // Closing the pipe ends the session like an unmount
+        });
+
+        let se = Session::from_fd(DestroyCounter::default(), read, Path::new("/mnt"), &[]);
+        let mut se = se.unwrap();
+        se.run().unwrap();
+        kernel.join().unwrap();
+        unsafe { libc::sigaction(libc::SIGUSR2, &previous, std::ptr::null_mut()) };
+        assert_eq!(SIGNALS.load(Ordering::SeqCst), 5);
+        assert!(se.initialized);
+        assert_eq!(se.filesystem.0, 1);
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    #[test]
+    fn zero_message_open() {
+        use crate::consts::FUSE_NO_OPEN_SUPPORT;
+
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        let mut arg = vec![];
+        for x in &[7u32, 23, 4096, FUSE_NO_OPEN_SUPPORT] {
+            arg.extend_from_slice(&x.to_ne_bytes()); // major, minor, max_readahead, flags
+        }
+        dispatch(&mut se, 26, 0, &arg);
+        assert!(se.no_open_support);
+        assert!(!se.no_opendir_support);
+        dispatch(&mut se, 14, 2, &[0; 8]); // open
+        dispatch(&mut se, 27, 1, &[0; 8]); // opendir
+        let replies = replies(&mut output);
+        assert_eq!(replies[1][4..8], (-libc::ENOSYS).to_ne_bytes());
+        assert_eq!(replies[2][4..8], [0; 4]);
+    }
+
+    #[test]
+    fn rename_exdev() {
+        let (mut se, mut output) = session_with_output(CrossDevice);
+        init(&mut se);
+        let arg = |newdir: u64| [&newdir.to_ne_bytes()[..], b"a\0b\0"].concat();
+        dispatch(&mut se, 12, 1, &arg(1));
+        dispatch(&mut se, 12, 1, &arg(2));
+        let replies = replies(&mut output);
+        assert_eq!(replies[1][4..8], [0; 4]);
+        assert_eq!(replies[2][4..8], (-libc::EXDEV).to_ne_bytes());
+    }
+
+    #[test]
+    fn create_excl() {
+        let (mut se, mut output) = session_with_output(Files::default());
+        init(&mut se);
+        let arg = |flags: i32, name: &[u8]| {
+            let mut arg = flags.to_ne_bytes().to_vec();
+            arg.extend_from_slice(&0o644u32.to_ne_bytes()); // mode
+            if cfg!(feature = "abi-7-12") {
+                arg.extend_from_slice(&[0; 8]); // umask, padding
+            }
+            [&arg[..], name, b"\0"].concat()
+        };
+        let (creat, excl) = (libc::O_CREAT | libc::O_RDWR, libc::O_CREAT | libc::O_EXCL);
+        dispatch(&mut se, 35, 1, &arg(excl, b"a"));
+        dispatch(&mut se, 35, 1, &arg(excl, b"a"));
+        dispatch(&mut se, 35, 1, &arg(creat, b"a"));
+        dispatch(&mut se, 35, 1, &arg(creat, b"b"));
+        assert_eq!(se.filesystem.0, ["a", "b"]);
+
+        let replies = replies(&mut output);
+        assert_eq!(replies[2][4..8], (-libc::EEXIST).to_ne_bytes());
+        for (reply, ino) in [(&replies[1], 2u64), (&replies[3], 2), (&replies[4], 3)] {
+            assert_eq!(reply[4..8], [0; 4]);
+            // fuse_entry_out starts with the inode, it is followed by fuse_open_out
+            assert_eq!(reply[16..24], ino.to_ne_bytes());
+            let fh = reply.len() - 16;
+            assert_eq!(reply[fh..fh + 8], ino.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn tmpfile() {
+        let (mut se, mut output) = session_with_output(Files::default());
+        init(&mut se);
+        let mut arg = (libc::O_TMPFILE | libc::O_RDWR).to_ne_bytes().to_vec();
+        arg.extend_from_slice(&0o600u32.to_ne_bytes()); // mode
+        if cfg!(feature = "abi-7-12") {
+            arg.extend_from_slice(&[0; 8]); // umask, padding
+        }
+        arg.extend_from_slice(b"/\0");
+        dispatch(&mut se, 51, 1, &arg);
+        dispatch(&mut se, 51, 1, &arg);
+        assert_eq!(se.filesystem.0, ["", ""]);
+
+        let all = replies(&mut output);
+        for (reply, ino) in [(&all[1], 2u64), (&all[2], 3)] {
+            assert_eq!(reply[4..8], [0; 4]);
+            assert_eq!(reply[16..24], ino.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        init(&mut se);
+        se.set_read_only(true);
+        let mut write = vec![0; 16]; // fh, offset
+        write.extend_from_slice(&1u32.to_ne_bytes()); // size
+        write.extend_from_slice(&[0; 4]); // write_flags
+        if cfg!(feature = "abi-7-9") {
+            write.extend_from_slice(&[0; 16]); // lock_owner, flags, padding
+        }
+        write.push(b'x');
+        let setxattr = [&[1, 0, 0, 0, 0, 0, 0, 0][..], b"a\0b"].concat(); // size, flags
+        let open = |flags: i32| [flags.to_ne_bytes(), [0; 4]].concat();
+        dispatch(&mut se, 16, 2, &write);
+        dispatch(&mut se, 10, 1, b"a\0"); // unlink
+        dispatch(&mut se, 21, 2, &setxattr);
+        dispatch(&mut se, 14, 2, &open(libc::O_WRONLY));
+        dispatch(&mut se, 14, 2, &open(libc::O_RDWR));
+        dispatch(&mut se, 14, 2, &open(libc::O_RDONLY | libc::O_TRUNC));
+        let mkdir = [&0o755u32.to_ne_bytes()[..], &[0; 4], b"d\0"].concat(); // mode, umask
+        let rename = [&1u64.to_ne_bytes()[..], b"a\0b\0"].concat(); // newdir
+        dispatch(&mut se, 9, 1, &mkdir);
+        dispatch(&mut se, 12, 1, &rename);
+        dispatch(&mut se, 14, 2, &open(libc::O_RDONLY));
+        se.set_read_only(false);
+        dispatch(&mut se, 10, 1, b"a\0");
+        let replies = replies(&mut output);
+        for reply in &replies[1..9] {
+            assert_eq!(reply[4..8], (-libc::EROFS).to_ne_bytes());
+        }
+        assert_eq!(replies[9][4..8], [0; 4]);
+        assert_eq!(replies[10][4..8], (-libc::ENOSYS).to_ne_bytes());
+
+        let fd = tempfile::tempfile().unwrap();
+        let options = [MountOption::RO];
+        let se = Session::from_fd(DestroyCounter::default(), fd, Path::new("/mnt"), &options);
+        assert!(se.unwrap().read_only);
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            delay: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        let attempts = |errors: &[c_int]| {
+            let mut errors = errors.iter();
+            let mut attempts = 0;
+            let result = policy.retry(|| {
+                attempts += 1;
+                match errors.next() {
+                    Some(&errno) => Err(io::Error::from_raw_os_error(errno)),
+                    None => Ok(()),
+                }
+            });
+            (result.map_err(|err| err.raw_os_error().unwrap()), attempts)
+        };
+        assert_eq!(attempts(&[]), (Ok(()), 1));
+        assert_eq!(attempts(&[libc::EBUSY, libc::EAGAIN]), (Ok(()), 3));
+        assert_eq!(attempts(&[libc::EBUSY; 3]), (Err(libc::EBUSY), 3));
+        assert_eq!(attempts(&[libc::EBUSY, libc::EPERM]), (Err(libc::EPERM), 2));
+        let mut calls = 0;
+        let result = RetryPolicy::none().retry(|| {
+            calls += 1;
+            Err::<(), _>(io::Error::from_raw_os_error(libc::EBUSY))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn file_handle_round_trip() {
+        let (mut se, mut output) = session_with_output(Handles::default());
+        init(&mut se);
+        dispatch(&mut se, 14, 5, &[0; 8]); // open
+        dispatch(&mut se, 14, 6, &[0; 8]);
+        let replies = replies(&mut output);
+        // fuse_open_out starts with the fh
+        let fh = |reply: &[u8]| u64::from_ne_bytes(reply[16..24].try_into().unwrap());
+        let fhs = [fh(&replies[1]), fh(&replies[2])];
+        assert_eq!(fhs, [100, 101]);
+
+        for (&ino, &fh) in [5u64, 6].iter().zip(fhs.iter()) {
+            let mut read = fh.to_ne_bytes().to_vec();
+            read.extend_from_slice(&[0; 8]); // offset
+            read.extend_from_slice(&16u32.to_ne_bytes()); // size
+            if cfg!(feature = "abi-7-9") {
+                read.extend_from_slice(&[0; 20]); // read_flags, lock_owner, flags, padding
+            } else {
+                read.extend_from_slice(&[0; 4]); // padding
+            }
+            let flush = [&fh.to_ne_bytes()[..], &[0; 16]].concat(); // unused, lock_owner
+            let release = [&fh.to_ne_bytes()[..], &[0; 16]].concat(); // flags, lock_owner
+            dispatch(&mut se, 15, ino, &read);
+            dispatch(&mut se, 25, ino, &flush);
+            dispatch(&mut se, 18, ino, &release);
+        }
+        let ops = ["read", "flush", "release"];
+        let expected: Vec<_> = [("open", 5, 100), ("open", 6, 101)]
+            .iter()
+            .cloned()
+            .chain(ops.iter().map(|&op| (op, 5, 100)))
+            .chain(ops.iter().map(|&op| (op, 6, 101)))
+            .collect();
+        assert_eq!(se.filesystem.0, expected);
+    }
+
+    #[test]
+    fn dir_handle_tracking() {
+        let mut se = session(Handles::default());
+        se.set_track_dir_handles(true);
+        init(&mut se);
+        dispatch(&mut se, 27, 5, &[0; 8]); // opendir
+        dispatch(&mut se, 27, 6, &[0; 8]);
+        let dir_handles = se.dir_handles.clone().unwrap();
+        assert_eq!(dir_handles.open(), [100, 101]);
+
+        let releasedir = |fh: u64| [&fh.to_ne_bytes()[..], &[0; 16]].concat();
+        dispatch(&mut se, 29, 5, &releasedir(100));
+        assert_eq!(dir_handles.open(), [101]);
+        // Releasing again only warns
+        dispatch(&mut se, 29, 5, &releasedir(100));
+        assert_eq!(dir_handles.open(), [101]);
+
+        se.set_track_dir_handles(false);
+        assert!(se.dir_handles.is_none());
+        dispatch(&mut se, 27, 7, &[0; 8]);
+        assert_eq!(dir_handles.open(), [101]);
+    }
+
+    #[test]
+    fn access_with_default_permissions() {
+        let ch = Channel::new(Arc::new(tempfile::tempfile().unwrap()));
+        let options = [MountOption::DefaultPermissions];
+        let mut se = Session::with_channel(
+            AccessRecorder::default(),
+            ch,
+            None,
+            Path::new("/"),
+            &options,
+        );
+        init(&mut se);
+        // fuse_access_in: mask, padding
+        let arg = [&libc::W_OK.to_ne_bytes()[..], &[0; 4]].concat();
+        dispatch(&mut se, 34, 2, &arg);
+        assert_eq!(se.filesystem.0, [libc::W_OK]);
+    }
+
+    #[test]
+    fn session_builder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let invalid = [
+            SessionBuilder::new().max_write(0),
+            SessionBuilder::new().max_write(u32::MAX),
+            SessionBuilder::new().max_read(0),
+            SessionBuilder::new()
+                .mount_option(MountOption::RO)
+                .mount_option(MountOption::RW),
+        ];
+        for builder in invalid.iter() {
+            let err = builder
+                .clone()
+                .build(DestroyCounter::default(), tmp.path())
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // The max write size is used as default for init
+        let mut se = session(DestroyCounter::default());
+        se.default_max_write = Some(64 * 1024);
+        init(&mut se);
+        assert_eq!(se.max_write, 64 * 1024);
+    }
+
+    #[test]
+    fn truncated_requests() {
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        init(&mut se);
+        // Every opcode with truncated, empty or garbage arguments must not panic
+        for opcode in (1..=52).filter(|&opcode| opcode != 26 && opcode != 38) {
+            for len in 0..=96 {
+                for &fill in [0u8, 0xff, b'a'].iter() {
+                    dispatch(&mut se, opcode, 2, &vec![fill; len]);
+                }
+            }
+        }
+        // Write data that doesn't match the size in the arguments is rejected
+        let mut arg = write_in(0, b"data");
+        arg.truncate(arg.len() - 1);
+        let start = replies(&mut output).len();
+        dispatch(&mut se, 16, 2, &arg);
+        let all = replies(&mut output);
+        assert_eq!(all.len(), start + 1);
+        let error = i32::from_ne_bytes(all[start][4..8].try_into().unwrap());
+        assert_eq!(error, -libc::EINVAL);
+    }
+
+    #[test]
+    fn interrupted_setlkw() {
+        let (mut se, mut output) = session_with_output(LockWaiter::default());
+        init(&mut se);
+        // fuse_lk_in: fh, owner, start, end, type, pid (and lk_flags, padding)
+        let mut arg = vec![0; 32];
+        arg.extend_from_slice(&libc::F_WRLCK.to_ne_bytes());
+        arg.extend_from_slice(&[0; 4]);
+        if cfg!(feature = "abi-7-9") {
+            arg.extend_from_slice(&[0; 8]);
+        }
+        dispatch(&mut se, 33, 2, &arg); // setlkw
+        assert_eq!(replies(&mut output).len(), 1);
+        // Interrupt the setlkw with unique id 1
+        dispatch(&mut se, 36, 0, &1u64.to_ne_bytes());
+        let all = replies(&mut output);
+        assert_eq!(all.len(), 2);
+        assert_eq!(&all[1][4..8], &(-libc::EINTR).to_ne_bytes());
+    }
+
+    #[test]
+    fn supported_ops() {
+        use crate::consts::{FUSE_ASYNC_READ, FUSE_POSIX_LOCKS};
+
+        let mut arg = init_in();
+        arg[12..16].copy_from_slice(&(FUSE_ASYNC_READ | FUSE_POSIX_LOCKS).to_ne_bytes());
+        // Declared locks are requested without asking for them in init
+        let (mut se, mut output) =
+            session_with_output(DeclaredOps(Ops::GETLK | Ops::SETLK | Ops::READLINK, 0));
+        dispatch(&mut se, 26, 0, &arg);
+        let config = se.kernel_config().unwrap();
+        assert_ne!(config.negotiated_capabilities() & FUSE_POSIX_LOCKS, 0);
+        dispatch(&mut se, 5, 2, &[]);
+        assert_eq!(replies(&mut output)[1][16..], *b"target");
+
+        // Undeclared operations are replied with ENOSYS, and their capabilities dropped
+        let (mut se, mut output) = session_with_output(DeclaredOps(Ops::GETATTR, FUSE_POSIX_LOCKS));
+        dispatch(&mut se, 26, 0, &arg);
+        let config = se.kernel_config().unwrap();
+        assert_eq!(config.negotiated_capabilities() & FUSE_POSIX_LOCKS, 0);
+        dispatch(&mut se, 5, 2, &[]);
+        assert_eq!(replies(&mut output)[1][4..8], (-libc::ENOSYS).to_ne_bytes());
+
+        // By default, everything is dispatched and only requested capabilities are enabled
+        let (mut se, mut output) = session_with_output(DeclaredOps(Ops::ALL, 0));
+        dispatch(&mut se, 26, 0, &arg);
+        let config = se.kernel_config().unwrap();
+        assert_eq!(config.negotiated_capabilities() & FUSE_POSIX_LOCKS, 0);
+        dispatch(&mut se, 5, 2, &[]);
+        assert_eq!(replies(&mut output)[1][16..], *b"target");
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-10")]
+    fn lookup_inode() {
+        use crate::consts::FUSE_EXPORT_SUPPORT;
+
+        let (mut se, mut output) = session_with_output(Exported::default());
+        let mut arg = init_in();
+        arg[4..8].copy_from_slice(&10u32.to_ne_bytes()); // minor
+        arg[12..16].copy_from_slice(&FUSE_EXPORT_SUPPORT.to_ne_bytes());
+        dispatch(&mut se, 26, 0, &arg);
+        assert!(se.export_support);
+        dispatch(&mut se, 1, 5, b".\0");
+        dispatch(&mut se, 1, 5, b"..\0");
+        assert_eq!(
+            se.filesystem.0,
+            [(5, OsString::new()), (5, OsString::from(".."))]
+        );
+        let all = replies(&mut output);
+        assert_eq!(all[1][4..8], (-libc::ESTALE).to_ne_bytes());
+    }
+
+    #[test]
+    fn mount_options() {
+        let options = [MountOption::RO, MountOption::FSName("test".to_string())];
+        let file = tempfile::tempfile().unwrap();
+        let se = Session::from_fd(DestroyCounter::default(), file, Path::new("/"), &options);
+        assert_eq!(se.unwrap().mount_options(), options);
+    }
+
+    #[test]
+    fn kernel_config() {
+        use crate::consts::{FUSE_ASYNC_READ, FUSE_POSIX_LOCKS};
+
+        let mut se = session(DestroyCounter::default());
+        assert!(se.kernel_config().is_none());
+        assert!(se.negotiated.wait(Duration::from_millis(1)).is_none());
+        // Like a background session, wait for init on another thread
+        let negotiated = se.negotiated.clone();
+        let waiter = std::thread::spawn(move || negotiated.wait(Duration::from_secs(10)));
+
+        let mut arg = init_in();
+        arg[12..16].copy_from_slice(&(FUSE_ASYNC_READ | FUSE_POSIX_LOCKS).to_ne_bytes());
+        dispatch(&mut se, 26, 0, &arg);
+        let config = se.kernel_config().unwrap();
+        assert_eq!(config.capabilities(), FUSE_ASYNC_READ | FUSE_POSIX_LOCKS);
+        // Locks are only enabled if requested by the filesystem
+        assert_eq!(config.negotiated_capabilities(), FUSE_ASYNC_READ);
+        assert_eq!(config.max_readahead(), 4096);
+        assert_eq!(config.max_write(), se.max_write);
+        let config = waiter.join().unwrap().unwrap();
+        assert_eq!(config.max_write(), se.max_write);
+    }
+
+    #[test]
+    fn max_read_in_kernel_config() {
+        let file = tempfile::tempfile().unwrap();
+        let ch = Channel::new(Arc::new(file));
+        let options = [MountOption::MaxRead(65536)];
+        let mut se = Session::with_channel(
+            DestroyCounter::default(),
+            ch,
+            None,
+            Path::new("/"),
+            &options,
+        );
+        init(&mut se);
+        assert_eq!(se.kernel_config().unwrap().max_read(), Some(65536));
+
+        let mut se = session(DestroyCounter::default());
+        init(&mut se);
+        assert_eq!(se.kernel_config().unwrap().max_read(), None);
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    #[test]
+    fn poll_notify() {
+        let (mut se, mut output) = session_with_output(Poller::default());
+        init(&mut se);
+        let poll_in = |kh: u64, flags: u32| {
+            let mut arg = 7u64.to_ne_bytes().to_vec(); // fh
+            arg.extend_from_slice(&kh.to_ne_bytes());
+            arg.extend_from_slice(&flags.to_ne_bytes());
+            arg.extend_from_slice(&(libc::POLLIN as u32).to_ne_bytes()); // events
+            arg
+        };
+        dispatch(&mut se, 40, 2, &poll_in(0x11, 0));
+        dispatch(&mut se, 40, 2, &poll_in(0x22, 1)); // FUSE_POLL_SCHEDULE_NOTIFY
+        assert_eq!(se.filesystem.0, [0x22]);
+        se.notifier().poll(0x22).unwrap();
+
+        let all = replies(&mut output);
+        // fuse_poll_out: revents, padding
+        assert_eq!(all[1][16..20], (libc::POLLIN as u32).to_ne_bytes());
+        assert_eq!(all[2].len(), 24);
+        // The wakeup notification with unique 0, FUSE_POLL as error and the kernel handle
+        assert_eq!(all[3][4..8], 1i32.to_ne_bytes());
+        assert_eq!(all[3][8..16], 0u64.to_ne_bytes());
+        assert_eq!(all[3][16..24], 0x22u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn catch_panics() {
+        let (mut se, mut output) = session_with_output(Panicker);
+        init(&mut se);
+        dispatch(&mut se, 5, 2, &[]); // readlink
+        dispatch(&mut se, 5, 3, &[]);
+        let all = replies(&mut output);
+        // The unused reply was replied with EIO, the used one not again
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1][4..8], (-libc::EIO).to_ne_bytes());
+        assert_eq!(all[2][16..], b"target"[..]);
+
+        se.set_panic_errno(libc::EAGAIN);
+        dispatch(&mut se, 5, 2, &[]);
+        let all = replies(&mut output);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3][4..8], (-libc::EAGAIN).to_ne_bytes());
+
+        se.set_catch_panics(false);
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            dispatch(&mut se, 5, 2, &[]);
+        }));
+        assert_eq!(
+            super::panic_message(&*panic.unwrap_err()),
+            "readlink of inode 2"
+        );
+    }
+
+    #[cfg(feature = "abi-7-18")]
+    #[test]
+    fn directory_ioctl() {
+        use crate::consts::FUSE_IOCTL_DIR;
+
+        let mut se = session(Ioctls::default());
+        init(&mut se);
+        let ioctl_in = |fh: u64, flags: u32| {
+            let mut arg = fh.to_ne_bytes().to_vec();
+            arg.extend_from_slice(&flags.to_ne_bytes());
+            arg.extend_from_slice(&0x5401u32.to_ne_bytes()); // cmd
+            arg.extend_from_slice(&[0; 16]); // arg, in_size, out_size
+            arg
+        };
+        dispatch(&mut se, 39, 2, &ioctl_in(5, 0)); // ioctl
+        dispatch(&mut se, 39, 1, &ioctl_in(6, FUSE_IOCTL_DIR));
+        assert_eq!(se.filesystem.0, [(5, 0), (6, FUSE_IOCTL_DIR)]);
+    }
+
+    #[test]
+    fn deferred_replies() {
+        let (mut se, mut output) = session_with_output(Deferred::default());
+        init(&mut se);
+        dispatch(&mut se, 5, 2, &[]); // readlink
+        dispatch(&mut se, 5, 3, &[]);
+        assert_eq!(replies(&mut output).len(), 1);
+        // Reply from another thread, in reverse order
+        let deferred = std::mem::take(&mut se.filesystem.0);
+        std::thread::spawn(move || {
+            for (ino, reply) in deferred.into_iter().rev() {
+                reply.data(&ino.to_ne_bytes());
+            }
+        })
+        .join()
+        .unwrap();
+        let all = replies(&mut output);
+        assert_eq!(all[1][16..], 3u64.to_ne_bytes());
+        assert_eq!(all[2][16..], 2u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn default_access() {
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        init(&mut se);
+        let mut access = libc::X_OK.to_ne_bytes().to_vec(); // mask, padding
+        access.extend_from_slice(&[0; 4]);
+        dispatch(&mut se, 34, 1, &access);
+        // The kernel treats ENOSYS as granting access
+        let all = replies(&mut output);
+        assert_eq!(all[1][4..8], (-libc::ENOSYS).to_ne_bytes());
+    }
+
+    #[test]
+    fn default_statfs() {
+        let (mut se, mut output) = session_with_output(DestroyCounter::default());
+        init(&mut se);
+        dispatch(&mut se, 17, 1, &[]); // statfs
+        let all = replies(&mut output);
+        // fuse_out_header and fuse_kstatfs
+        assert_eq!(all[1].len(), 16 + 80);
+        let field =
+            |offset: usize| u32::from_ne_bytes(all[1][offset..offset + 4].try_into().unwrap());
+        assert_eq!(field(16 + 40), 512); // bsize
+        assert_eq!(field(16 + 44), 255); // namelen
+    }
+
+    #[test]
+    fn callback_filesystem() {
+        let fs = CallbackFilesystem::new()
+            .on_lookup(|_req, parent, name, reply| {
+                if parent == 1 && name == "link" {
+                    let attr = FileAttrBuilder::new(2, FileType::Symlink);
+                    reply.entry(&Duration::ZERO, &attr.into(), 0);
+                } else {
+                    reply.error(ENOENT);
+                }
+            })
+            .on_readlink(|_req, _ino, reply| reply.data(b"target"));
+        let (mut se, mut output) = session_with_output(fs);
+        init(&mut se);
+        dispatch(&mut se, 1, 1, b"link\0"); // lookup
+        dispatch(&mut se, 1, 1, b"other\0");
+        dispatch(&mut se, 5, 2, &[]); // readlink
+        let getattr = vec![0; if cfg!(feature = "abi-7-9") { 16 } else { 0 }];
+        dispatch(&mut se, 3, 2, &getattr);
+        let all = replies(&mut output);
+        let error = |reply: &Vec<u8>| i32::from_ne_bytes(reply[4..8].try_into().unwrap());
+        assert_eq!(error(&all[1]), 0);
+        assert_eq!(all[1][16..24], 2u64.to_ne_bytes());
+        assert_eq!(error(&all[2]), -ENOENT);
+        assert_eq!(all[3][16..], b"target"[..]);
+        // Operations without a closure aren't implemented
+        assert_eq!(error(&all[4]), -libc::ENOSYS);
+    }
+
+    #[test]
+    fn forgotten_inode() {
+        let (mut se, mut output) = session_with_output(Inodes(InodeTable::new(OsString::new())));
+        init(&mut se);
+        let getattr = vec![0; if cfg!(feature = "abi-7-9") { 16 } else { 0 }];
+        dispatch(&mut se, 1, 1, b"a\0"); // lookup
+        dispatch(&mut se, 3, 2, &getattr);
+        // A forget with more lookups than counted is clamped
+        dispatch(&mut se, 2, 2, &5u64.to_ne_bytes());
+        dispatch(&mut se, 2, 2, &1u64.to_ne_bytes());
+        // A request racing with the forget gets ENOENT
+        dispatch(&mut se, 3, 2, &getattr);
+        let all = replies(&mut output);
+        // init, lookup and getattr replies, forget has none
+        assert_eq!(all.len(), 4);
+        let error = |reply: &Vec<u8>| i32::from_ne_bytes(reply[4..8].try_into().unwrap());
+        assert_eq!(error(&all[2]), 0);
+        assert_eq!(all[3].len(), 16);
+        assert_eq!(error(&all[3]), -ENOENT);
+        assert_eq!(se.filesystem.0.lookup(2), None);
+    }
+
+    #[test]
+    fn track_lookups() {
+        let mut se = session(Inodes(InodeTable::new(OsString::new())));
+        assert_eq!(se.lookup_counts(), None);
+        init(&mut se);
+        se.set_track_lookups(true);
+        dispatch(&mut se, 1, 1, b"a\0");
+        dispatch(&mut se, 1, 1, b"a\0");
+        dispatch(&mut se, 1, 1, b"b\0");
+        dispatch(&mut se, 2, 2, &1u64.to_ne_bytes()); // forget
+        let counts = se.lookup_counts().unwrap();
+        assert_eq!(counts.into_iter().collect::<Vec<_>>(), [(2, 1), (3, 1)]);
+        assert_eq!(se.filesystem.0.lookups(2), Some(1));
+        dispatch(&mut se, 2, 3, &1u64.to_ne_bytes());
+        assert_eq!(se.lookup_counts().unwrap().len(), 1);
+        se.set_track_lookups(false);
+        assert_eq!(se.lookup_counts(), None);
+    }
+
+    #[test]
+    fn lock_owners() {
+        let mut se = session(LockOwners::default());
+        init(&mut se);
+        let arg = |flags: u32, lock_owner: u64| {
+            let mut arg = vec![0; 12]; // fh, flush: unused, release: flags
+            arg.extend_from_slice(&flags.to_ne_bytes()); // flush: padding, release: release_flags
+            arg.extend_from_slice(&lock_owner.to_ne_bytes());
+            arg
+        };
+        dispatch(&mut se, 25, 2, &arg(0, 7)); // flush
+        dispatch(&mut se, 18, 2, &arg(0, 8)); // release
+        dispatch(&mut se, 18, 2, &arg(2, 9)); // release with FUSE_RELEASE_FLOCK_UNLOCK
+        let owner = if cfg!(feature = "abi-7-17") {
+            None
+        } else {
+            Some(8)
+        };
+        let expected = [("flush", Some(7)), ("release", owner), ("release", Some(9))];
+        assert_eq!(se.filesystem.0, expected);
+    }
+
+    #[test]
+    fn read_write_lock_owners() {
+        let mut se = session(LockOwners::default());
+        init(&mut se);
+        // fuse_read_in and fuse_write_in start with fh, offset, size and read/write flags,
+        // followed by lock_owner, flags and padding with ABI >= 7.9
+        let arg = |flags: u32, lock_owner: u64, data: &[u8]| {
+            let mut arg = vec![0; 16];
+            arg.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+            arg.extend_from_slice(&flags.to_ne_bytes());
+            if cfg!(feature = "abi-7-9") {
+                arg.extend_from_slice(&lock_owner.to_ne_bytes());
+                arg.extend_from_slice(&[0; 8]);
+            }
+            arg.extend_from_slice(data);
+            arg
+        };
+        dispatch(&mut se, 15, 2, &arg(0, 7, &[])); // read
+        dispatch(&mut se, 15, 2, &arg(2, 8, &[])); // read with FUSE_READ_LOCKOWNER
+        dispatch(&mut se, 16, 2, &arg(0, 9, b"data")); // write
+        dispatch(&mut se, 16, 2, &arg(2, 10, b"data")); // write with FUSE_WRITE_LOCKOWNER
+        let owner = |owner| {
+            if cfg!(feature = "abi-7-9") {
+                Some(owner)
+            } else {
+                None
+            }
+        };
+        let expected = [
+            ("read", None),
+            ("read", owner(8)),
+            ("write", None),
+            ("write", owner(10)),
+        ];
+        assert_eq!(se.filesystem.0, expected);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn mtime_nanoseconds() {
+        let (mut se, mut output) = session_with_output(Times);
+        init(&mut se);
+        let times = [
+            (1_600_000_000i64, 123_456_789u32),
+            (-2, 500_000_000),
+            (0, 1),
+        ];
+        for (secs, nsecs) in times.iter() {
+            let mut arg = vec![0; 88];
+            arg[0..4].copy_from_slice(&(1u32 << 5).to_ne_bytes()); // valid: FATTR_MTIME
+            arg[40..48].copy_from_slice(&secs.to_ne_bytes()); // mtime
+            arg[60..64].copy_from_slice(&nsecs.to_ne_bytes()); // mtimensec
+            dispatch(&mut se, 4, 2, &arg);
+        }
+        let replies = replies(&mut output);
+        for (reply, (secs, nsecs)) in replies[1..].iter().zip(times.iter()) {
+            // fuse_attr_out: attr_valid, attr_valid_nsec, dummy, fuse_attr
+            assert_eq!(reply[16..32], [0; 16]);
+            assert_eq!(reply[64..72], secs.to_ne_bytes());
+            assert_eq!(reply[84..88], nsecs.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn panic_error() {
+        let panic = std::thread::spawn(|| panic!("boom")).join().unwrap_err();
+        let err = super::panic_error(panic);
+        assert_eq!(err.to_string(), "Session thread panicked: boom");
+        let panic = std::thread::spawn(|| panic!("{}", 42)).join().unwrap_err();
+        let err = super::panic_error(panic);
+        assert_eq!(err.to_string(), "Session thread panicked: 42");
+    }
+}
@@ -0,0 +1,154 @@
+//! Signal handling
+//!
+//! Signal handlers may only call async-signal-safe functions, so the handler installed here
+//! just writes the signal number to a pipe (the self-pipe trick). The session waits for it on
+//! another thread and unmounts the filesystem, which ends the session loop.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use libc::{c_int, c_void};
+
+/// True while signal handlers are installed (only one set of handlers per process)
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Write end of the pipe the signal handler writes to, or -1
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handler(signal: c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // Nothing can be done about errors in a signal handler. If the pipe is full, a
+        // signal is already pending anyway.
+        unsafe { libc::write(fd, &byte as *const u8 as *const c_void, 1) };
+    }
+}
+
+/// Create a pipe with the close-on-exec flag set. The write end is non-blocking, so the
+/// signal handler never blocks.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    for fd in &fds {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if unsafe { libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((read, write))
+}
+
+/// Handlers for a set of signals, the previous handlers are restored on drop
+pub(crate) struct SignalHandlers {
+    write: File,
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl SignalHandlers {
+    /// Install handlers for the given signals. Returns the receiving end for the signals.
+    /// Fails with EBUSY if handlers are already installed.
+    pub(crate) fn install(signals: &[c_int]) -> io::Result<(SignalHandlers, SignalReceiver)> {
+        if ACTIVE.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        let (read, write) = match pipe() {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                ACTIVE.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        PIPE.store(write.as_raw_fd(), Ordering::SeqCst);
+        let mut handlers = SignalHandlers {
+            write,
+            previous: vec![],
+        };
+        for &signal in signals {
+            let mut action: libc::sigaction = unsafe { mem::zeroed() };
+            action.sa_sigaction = handler as extern "C" fn(c_int) as libc::sighandler_t;
+            // Without SA_RESTART, so that blocking calls of the signaled thread return EINTR
+            action.sa_flags = 0;
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            let mut previous: libc::sigaction = unsafe { mem::zeroed() };
+            if unsafe { libc::sigaction(signal, &action, &mut previous) } != 0 {
+                // Dropping the handlers restores the ones installed so far
+                return Err(io::Error::last_os_error());
+            }
+            handlers.previous.push((signal, previous));
+        }
+        Ok((handlers, SignalReceiver(read)))
+    }
+
+    /// Wake up the receiver without a signal
+    pub(crate) fn wake(&self) {
+        let _ = (&self.write).write(&[0]);
+    }
+}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous.drain(..).rev() {
+            unsafe { libc::sigaction(signal, &previous, ptr::null_mut()) };
+        }
+        PIPE.store(-1, Ordering::SeqCst);
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Receiving end for signals caught by `SignalHandlers`
+pub(crate) struct SignalReceiver(File);
+
+impl SignalReceiver {
+    /// Block until a signal arrives and return it, or None if woken up without a signal
+    pub(crate) fn wait(mut self) -> Option<c_int> {
+        let mut byte = [0];
+        loop {
+            match self.0.read(&mut byte) {
+                Ok(1) if byte[0] != 0 => return Some(c_int::from(byte[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{mem, ptr};
+
+    use super::SignalHandlers;
+
+    /// Returns the handler currently installed for the given signal
+    fn current_handler(signal: libc::c_int) -> libc::sighandler_t {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe { libc::sigaction(signal, ptr::null(), &mut action) };
+        action.sa_sigaction
+    }
+
+    #[test]
+    fn signal_handlers() {
+        let signal = libc::SIGUSR1;
+        let previous = current_handler(signal);
+        let (handlers, receiver) = SignalHandlers::install(&[signal]).unwrap();
+        let err = SignalHandlers::install(&[signal]).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        unsafe { libc::raise(signal) };
+        assert_eq!(receiver.wait(), Some(signal));
+        drop(handlers);
+        assert_eq!(current_handler(signal), previous);
+
+        let (handlers, receiver) = SignalHandlers::install(&[signal]).unwrap();
+        handlers.wake();
+        assert_eq!(receiver.wait(), None);
+    }
+}
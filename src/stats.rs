@@ -0,0 +1,201 @@
+//! Session statistics
+//!
+//! A session counts the requests it processes with atomic counters, so the statistics are
+//! always available at negligible cost. `Session::stats()` and `BackgroundSession::stats()`
+//! return a snapshot of the current counters, e.g. to export them to a monitoring system.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::c_int;
+
+use crate::ll::fuse_abi::fuse_opcode;
+
+/// Number of opcodes that are counted individually. All known opcodes are smaller.
+const OPCODES: usize = 64;
+
+/// Number of error codes that are counted individually. All errno values are smaller.
+const ERRNOS: usize = 256;
+
+/// Snapshot of the statistics of a session
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SessionStats {
+    /// Number of requests received per opcode (see `fuse_opcode`)
+    pub requests: BTreeMap<u32, u64>,
+    /// Number of error replies sent per error code
+    pub errors: BTreeMap<c_int, u64>,
+    /// Number of bytes replied to read requests
+    pub bytes_read: u64,
+    /// Number of bytes the filesystem reported as written in replies to write requests
+    pub bytes_written: u64,
+    /// Number of requests which have been received, but not replied to yet
+    pub in_flight: u64,
+}
+
+/// Counters shared by a session and the replies to its requests
+#[derive(Debug)]
+pub(crate) struct Counters {
+    requests: Vec<AtomicU64>,
+    errors: Vec<AtomicU64>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Requests received which expect a reply
+    received: AtomicU64,
+    /// Replies sent
+    replied: AtomicU64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            requests: (0..OPCODES).map(|_| AtomicU64::new(0)).collect(),
+            errors: (0..ERRNOS).map(|_| AtomicU64::new(0)).collect(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            replied: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Counters {
+    /// Count a received request
+    pub(crate) fn request(&self, opcode: u32) {
+        if let Some(counter) = self.requests.get(opcode as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if expects_reply(opcode) {
+            self.received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a reply sent to a request with the given opcode. The first buffer contains the
+    /// fuse_out_header, followed by the data of the reply.
+    pub(crate) fn reply(&self, opcode: u32, bufs: &[IoSlice<'_>]) {
+        self.replied.fetch_add(1, Ordering::Relaxed);
+        let header = match bufs.first() {
+            Some(header) if header.len() >= 16 => header,
+            _ => return,
+        };
+        let len = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+        let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+        if error != 0 {
+            if let Some(counter) = self.errors.get(error.unsigned_abs() as usize) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        } else if opcode == fuse_opcode::FUSE_READ as u32 {
+            let size = u64::from(len).saturating_sub(16);
+            self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        } else if opcode == fuse_opcode::FUSE_WRITE as u32 {
+            // The reply is a fuse_write_out, which starts with the written size
+            let size = bufs.get(1).and_then(|data| data.get(0..4));
+            if let Some(size) = size {
+                let size = u32::from_ne_bytes(size.try_into().unwrap());
+                self.bytes_written
+                    .fetch_add(u64::from(size), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns a snapshot of the counters
+    pub(crate) fn snapshot(&self) -> SessionStats {
+        let nonzero = |counters: &[AtomicU64]| {
+            counters
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (i, c.load(Ordering::Relaxed)))
+                .filter(|(_, c)| *c != 0)
+                .collect::<Vec<_>>()
+        };
+        let replied = self.replied.load(Ordering::Relaxed);
+        SessionStats {
+            requests: nonzero(&self.requests)
+                .into_iter()
+                .map(|(opcode, c)| (opcode as u32, c))
+                .collect(),
+            errors: nonzero(&self.errors)
+                .into_iter()
+                .map(|(errno, c)| (errno as c_int, c))
+                .collect(),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            in_flight: self
+                .received
+                .load(Ordering::Relaxed)
+                .saturating_sub(replied),
+        }
+    }
+}
+
+/// Returns false for requests the kernel doesn't expect a reply for
+fn expects_reply(opcode: u32) -> bool {
+    #[cfg(feature = "abi-7-16")]
+    {
+        if opcode == fuse_opcode::FUSE_BATCH_FORGET as u32 {
+            return false;
+        }
+    }
+    opcode != fuse_opcode::FUSE_FORGET as u32 && opcode != fuse_opcode::FUSE_INTERRUPT as u32
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::IoSlice;
+
+    use zerocopy::AsBytes;
+
+    use super::Counters;
+    use crate::ll::fuse_abi::{self as abi, fuse_opcode};
+
+    fn header(len: u32, error: i32) -> abi::fuse_out_header {
+        abi::fuse_out_header {
+            len,
+            error,
+            unique: 1,
+        }
+    }
+
+    #[test]
+    fn count_requests() {
+        let counters = Counters::default();
+        let read = fuse_opcode::FUSE_READ as u32;
+        let write = fuse_opcode::FUSE_WRITE as u32;
+        counters.request(read);
+        counters.request(write);
+        counters.request(write);
+        counters.request(fuse_opcode::FUSE_FORGET as u32);
+
+        let data = [0u8; 10];
+        let out = header(26, 0);
+        counters.reply(read, &[IoSlice::new(out.as_bytes()), IoSlice::new(&data)]);
+        let stats = counters.snapshot();
+        assert_eq!(stats.in_flight, 2);
+        assert_eq!(stats.bytes_read, 10);
+
+        let written = [7u32, 0];
+        let out = header(24, 0);
+        counters.reply(
+            write,
+            &[
+                IoSlice::new(out.as_bytes()),
+                IoSlice::new(written.as_bytes()),
+            ],
+        );
+        let out = header(16, -libc::EIO);
+        counters.reply(write, &[IoSlice::new(out.as_bytes())]);
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.requests.get(&read), Some(&1));
+        assert_eq!(stats.requests.get(&write), Some(&2));
+        assert_eq!(
+            stats.requests.get(&(fuse_opcode::FUSE_FORGET as u32)),
+            Some(&1)
+        );
+        assert_eq!(stats.errors.get(&libc::EIO), Some(&1));
+        assert_eq!(stats.errors.len(), 1);
+        assert_eq!(stats.bytes_written, 7);
+        assert_eq!(stats.in_flight, 0);
+    }
+}
@@ -0,0 +1,349 @@
+//! Write combining
+//!
+//! Without the writeback cache, every write system call of an application results in a
+//! write request of the same size. If enabled with `Session::set_write_combining()`, the
+//! session replies to sequential writes to the same file handle right away and buffers
+//! their data, passing it to the filesystem as a single larger write once the next request
+//! isn't a write continuing the buffered data, or the buffer is full. Errors of the
+//! combined write can't be reported to the writes anymore, they are reported to the next
+//! flush or fsync of the file handle instead. The session doesn't wait for the reply to
+//! the combined write, the filesystem may reply later or from another thread.
+
+use std::collections::HashMap;
+use std::io::IoSlice;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use libc::{c_int, EIO};
+use log::{error, warn};
+use zerocopy::{AsBytes, LayoutVerified};
+
+use crate::ll::fuse_abi as abi;
+use crate::ll::{self, AnyRequest, Request as _};
+use crate::reply::{parse_reply, ReplyCapture, ReplySender, ReplySink};
+
+/// Errors of combined writes, by inode and file handle
+type WriteErrors = Arc<Mutex<HashMap<(u64, u64), c_int>>>;
+
+/// Buffered data of sequential writes
+#[derive(Debug)]
+pub(crate) struct PendingWrite {
+    /// Header and fuse_write_in of the first write
+    arg: Vec<u8>,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    write_flags: u32,
+    flags: i32,
+    lock_owner: Option<u64>,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    data: Vec<u8>,
+    /// Errors of the combiner, to record the error of this write in
+    errors: WriteErrors,
+}
+
+impl PendingWrite {
+    /// Returns a write request for all the buffered data, with the header and arguments of
+    /// the first write. The request is returned in an aligned buffer with its length.
+    pub(crate) fn request(&self) -> (Vec<u64>, usize) {
+        let len = self.arg.len() + self.data.len();
+        let mut buf = vec![0u64; len / 8 + 1];
+        let bytes = buf.as_bytes_mut();
+        bytes[..self.arg.len()].copy_from_slice(&self.arg);
+        bytes[self.arg.len()..len].copy_from_slice(&self.data);
+        // The header starts with the length, fuse_write_in has the size after fh and offset
+        bytes[0..4].copy_from_slice(&(len as u32).to_ne_bytes());
+        let size = size_of::<abi::fuse_in_header>() + 16;
+        bytes[size..size + 4].copy_from_slice(&(self.data.len() as u32).to_ne_bytes());
+        (buf, len)
+    }
+
+    /// Returns a sender for the reply to the combined write, which records an error or
+    /// short write, whenever the filesystem replies
+    pub(crate) fn reply_sender(&self) -> ReplyCapture<CombinedWriteReply> {
+        ReplyCapture(CombinedWriteReply {
+            ino: self.ino,
+            fh: self.fh,
+            len: self.data.len(),
+            errors: self.errors.clone(),
+        })
+    }
+
+    /// Returns true if the given request is a write continuing this one, without exceeding
+    /// max_size
+    fn continued_by(&self, req: &AnyRequest<'_>, max: usize) -> bool {
+        let x = match req.operation() {
+            Ok(ll::Operation::Write(x)) => x,
+            _ => return false,
+        };
+        self.ino == req.nodeid().into()
+            && self.fh == x.file_handle().into()
+            && self.offset + self.data.len() as i64 == x.offset()
+            && self.write_flags == x.write_flags()
+            && self.flags == x.flags()
+            && self.lock_owner == x.lock_owner().map(|l| l.into())
+            && (self.uid, self.gid, self.pid) == (req.uid(), req.gid(), req.pid())
+            && self.data.len() + x.data().len() <= max
+    }
+}
+
+/// Receives the reply of a combined write, recording errors and short writes for the next
+/// flush or fsync of the file handle
+#[derive(Debug)]
+pub(crate) struct CombinedWriteReply {
+    ino: u64,
+    fh: u64,
+    len: usize,
+    errors: WriteErrors,
+}
+
+impl ReplySink for CombinedWriteReply {
+    fn captured(&self, reply: Vec<u64>) {
+        let err = match parse_reply::<abi::fuse_write_out>(&reply).map(|out| out.size) {
+            Ok(size) if size as usize == self.len => return,
+            Ok(size) => {
+                warn!(
+                    "Combined write of {} bytes to inode {} was short ({} bytes)",
+                    self.len, self.ino, size
+                );
+                EIO
+            }
+            Err(err) => {
+                warn!(
+                    "Combined write of {} bytes to inode {} failed with error {}",
+                    self.len, self.ino, err
+                );
+                err
+            }
+        };
+        self.errors.lock().unwrap().insert((self.ino, self.fh), err);
+    }
+}
+
+/// Receives the reply of the filesystem to a flush or fsync of a file handle whose combined
+/// write failed, and sends it to the kernel. A successful reply is sent as the error of the
+/// write instead, an error of the filesystem is sent as it is and the write error is logged.
+pub(crate) struct WriteErrorReply<S> {
+    pub(crate) sender: S,
+    pub(crate) errno: c_int,
+}
+
+impl<S: ReplySender> ReplySink for WriteErrorReply<S> {
+    fn captured(&self, mut reply: Vec<u64>) {
+        let mut header = match LayoutVerified::<_, abi::fuse_out_header>::new_from_prefix(
+            reply.as_bytes_mut(),
+        ) {
+            Some((header, _)) => header,
+            None => return,
+        };
+        if header.error == 0 {
+            header.error = -self.errno;
+            header.len = size_of::<abi::fuse_out_header>() as u32;
+        } else {
+            warn!(
+                "Request {}: error {} of a combined write is not reported, the reply is error {}",
+                header.unique, self.errno, -header.error
+            );
+        }
+        let (len, unique) = (header.len as usize, header.unique);
+        if let Err(err) = self.sender.send(&[IoSlice::new(&reply.as_bytes()[..len])]) {
+            error!("Request {}: Failed to send reply: {}", unique, err);
+        }
+    }
+}
+
+/// Buffered writes and their errors
+#[derive(Debug)]
+pub(crate) struct WriteCombiner {
+    max_size: usize,
+    pending: Option<PendingWrite>,
+    errors: WriteErrors,
+}
+
+impl WriteCombiner {
+    pub(crate) fn new(max_size: usize) -> Self {
+        WriteCombiner {
+            max_size,
+            pending: None,
+            errors: WriteErrors::default(),
+        }
+    }
+
+    /// Returns true if the given write data should be combined with others. Writes which
+    /// fill the buffer by themselves are passed to the filesystem as they are.
+    pub(crate) fn combines(&self, data: &[u8]) -> bool {
+        data.len() < self.max_size
+    }
+
+    /// Returns the pending write, unless the given request is a write continuing it
+    pub(crate) fn take_unless_continued(&mut self, req: &AnyRequest<'_>) -> Option<PendingWrite> {
+        match &self.pending {
+            Some(pending) if pending.continued_by(req, self.max_size) => None,
+            _ => self.pending.take(),
+        }
+    }
+
+    /// Returns all buffered data, e.g. when the session ends
+    pub(crate) fn take(&mut self) -> Option<PendingWrite> {
+        self.pending.take()
+    }
+
+    /// Buffer the data of the given write request, which must continue the pending write if
+    /// there is one. The raw request data is needed to pass the header and arguments on.
+    /// Returns the pending write if the buffer is full.
+    pub(crate) fn add(&mut self, req: &AnyRequest<'_>, data: &[u8]) -> Option<PendingWrite> {
+        let x = match req.operation() {
+            Ok(ll::Operation::Write(x)) => x,
+            _ => return None,
+        };
+        let errors = &self.errors;
+        let pending = self.pending.get_or_insert_with(|| PendingWrite {
+            arg: data[..data.len() - x.data().len()].to_vec(),
+            ino: req.nodeid().into(),
+            fh: x.file_handle().into(),
+            offset: x.offset(),
+            write_flags: x.write_flags(),
+            flags: x.flags(),
+            lock_owner: x.lock_owner().map(|l| l.into()),
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+            data: vec![],
+            errors: errors.clone(),
+        });
+        pending.data.extend_from_slice(x.data());
+        if pending.data.len() >= self.max_size {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the error of a combined write of the given file handle, if there was one
+    pub(crate) fn take_error(&mut self, ino: u64, fh: u64) -> Option<c_int> {
+        self.errors.lock().unwrap().remove(&(ino, fh))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use std::io::IoSlice;
+    use std::sync::mpsc::channel;
+
+    use zerocopy::AsBytes;
+
+    use super::{WriteCombiner, WriteErrorReply};
+    use crate::ll::fuse_abi as abi;
+    use crate::ll::{self, AnyRequest, Request as _};
+    use crate::reply::{parse_reply, ReplyCapture, ReplySender};
+    use crate::{Reply, ReplyEmpty};
+
+    /// Returns an aligned write request for ino 2, fh 3 at the given offset
+    fn write(offset: i64, data: &[u8]) -> Vec<u64> {
+        let mut arg = 3u64.to_ne_bytes().to_vec(); // fh
+        arg.extend_from_slice(&offset.to_ne_bytes());
+        arg.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        arg.extend_from_slice(&0u32.to_ne_bytes()); // write_flags
+        if cfg!(feature = "abi-7-9") {
+            arg.extend_from_slice(&[0; 16]); // lock_owner, flags, padding
+        }
+        arg.extend_from_slice(data);
+        let mut bytes = ((40 + arg.len()) as u32).to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&(abi::fuse_opcode::FUSE_WRITE as u32).to_ne_bytes());
+        bytes.extend_from_slice(&1u64.to_ne_bytes()); // unique
+        bytes.extend_from_slice(&2u64.to_ne_bytes()); // nodeid
+        bytes.extend_from_slice(&[0; 16]); // uid, gid, pid, padding
+        bytes.extend_from_slice(&arg);
+        let mut buf = vec![0u64; bytes.len() / 8 + 1];
+        buf.as_bytes_mut()[..bytes.len()].copy_from_slice(&bytes);
+        buf
+    }
+
+    /// Add a write to the combiner, returns the pending write if it was taken
+    fn add(combiner: &mut WriteCombiner, offset: i64, data: &[u8]) -> Option<Vec<u8>> {
+        let buf = write(offset, data);
+        let len = buf.as_bytes()[0] as usize;
+        let bytes = &buf.as_bytes()[..len];
+        let req = AnyRequest::try_from(bytes).unwrap();
+        let taken = combiner.take_unless_continued(&req);
+        let full = combiner.add(&req, bytes);
+        taken.or(full).map(|pending| {
+            let (buf, len) = pending.request();
+            let req = AnyRequest::try_from(&buf.as_bytes()[..len]).unwrap();
+            assert_eq!(req.nodeid().0, 2);
+            match req.operation().unwrap() {
+                ll::Operation::Write(x) => x.data().to_vec(),
+                _ => unreachable!(),
+            }
+        })
+    }
+
+    #[test]
+    fn combine_writes() {
+        let mut combiner = WriteCombiner::new(8);
+        assert_eq!(add(&mut combiner, 0, b"ab"), None);
+        assert_eq!(add(&mut combiner, 2, b"cd"), None);
+        // Not contiguous, the pending write is taken
+        assert_eq!(add(&mut combiner, 10, b"ef"), Some(b"abcd".to_vec()));
+        assert_eq!(add(&mut combiner, 12, b"gh"), None);
+        // Exceeds the max size
+        assert_eq!(add(&mut combiner, 14, b"ijklmno"), Some(b"efgh".to_vec()));
+        // Fills the buffer
+        assert_eq!(add(&mut combiner, 21, b"p"), Some(b"ijklmnop".to_vec()));
+        assert!(combiner.take().is_none());
+    }
+
+    #[test]
+    fn combined_write_errors() {
+        let mut combiner = WriteCombiner::new(8);
+        let buf = write(0, b"ab");
+        let bytes = &buf.as_bytes()[..buf.as_bytes()[0] as usize];
+        combiner.add(&AnyRequest::try_from(bytes).unwrap(), bytes);
+        let sender = combiner.take().unwrap().reply_sender();
+        // fuse_out_header and fuse_write_out
+        let reply = |error: i32, size: u32| {
+            let mut reply = 24u32.to_ne_bytes().to_vec();
+            reply.extend_from_slice(&error.to_ne_bytes());
+            reply.extend_from_slice(&1u64.to_ne_bytes()); // unique
+            reply.extend_from_slice(&size.to_ne_bytes());
+            reply.extend_from_slice(&[0; 4]); // padding
+            sender.send(&[IoSlice::new(&reply)]).unwrap();
+        };
+
+        reply(0, 2);
+        assert_eq!(combiner.take_error(2, 3), None);
+        reply(0, 1);
+        assert_eq!(combiner.take_error(2, 3), Some(libc::EIO));
+        reply(-libc::ENOSPC, 0);
+        assert_eq!(combiner.take_error(2, 4), None);
+        assert_eq!(combiner.take_error(2, 3), Some(libc::ENOSPC));
+        assert_eq!(combiner.take_error(2, 3), None);
+    }
+
+    #[test]
+    fn write_error_reply() {
+        let (tx, rx) = channel();
+        let sender = ReplyCapture(WriteErrorReply {
+            sender: ReplyCapture(tx.clone()),
+            errno: libc::ENOSPC,
+        });
+        // A successful flush is replied with the error of the write
+        let reply: ReplyEmpty = Reply::new(1, sender);
+        reply.ok();
+        let buf = rx.recv().unwrap();
+        assert_eq!(buf.as_bytes()[0..4], 16u32.to_ne_bytes());
+        assert_eq!(parse_reply::<()>(&buf), Err(libc::ENOSPC));
+
+        // An error of the flush itself is kept
+        let sender = ReplyCapture(WriteErrorReply {
+            sender: ReplyCapture(tx),
+            errno: libc::ENOSPC,
+        });
+        let reply: ReplyEmpty = Reply::new(2, sender);
+        reply.error(libc::EIO);
+        assert_eq!(parse_reply::<()>(&rx.recv().unwrap()), Err(libc::EIO));
+    }
+}